@@ -5,7 +5,7 @@
 compile_error!("You must enable either the `native-tls` or `rustls` feature");
 #[cfg(all(feature = "native-tls", feature = "rustls"))]
 compile_error!("You cannot enable both the `native-tls` and `rustls` features");
-#[cfg(not(any(feature = "postgres", feature = "mysql", feature = "sqlite")))]
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
 compile_error!("You must enable at least one database backend feature!");
 
 /// The build script
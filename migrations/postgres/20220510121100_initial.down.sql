@@ -1,3 +1,4 @@
+-- destructive: statestore_receipts, statestore_state, statestore_members, statestore_presence, statestore_accountdata, statestore_rooms, statestore_media, statestore_kv
 DROP INDEX statestore_receipts_room_event;
 DROP TABLE statestore_receipts;
 DROP TABLE statestore_state;
@@ -5,6 +6,6 @@ DROP TABLE statestore_members;
 DROP TABLE statestore_presence;
 DROP TABLE statestore_accountdata;
 DROP TABLE statestore_rooms;
-DELETE INDEX statestore_media_last_access;
+DROP INDEX statestore_media_last_access;
 DROP TABLE statestore_media;
 DROP TABLE statestore_kv;
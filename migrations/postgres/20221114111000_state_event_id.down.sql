@@ -1 +1,2 @@
+-- destructive: statestore_state.event_id
 ALTER TABLE statestore_state DROP COLUMN event_id;
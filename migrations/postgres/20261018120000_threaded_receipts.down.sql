@@ -0,0 +1,5 @@
+-- destructive: statestore_receipts.thread_id
+DELETE FROM statestore_receipts WHERE thread_id <> '';
+ALTER TABLE statestore_receipts DROP CONSTRAINT statestore_receipts_pkey;
+ALTER TABLE statestore_receipts ADD PRIMARY KEY (room_id, receipt_type, user_id);
+ALTER TABLE statestore_receipts DROP COLUMN thread_id;
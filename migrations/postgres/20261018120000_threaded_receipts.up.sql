@@ -0,0 +1,5 @@
+-- An empty thread ID marks an unthreaded receipt
+ALTER TABLE statestore_receipts
+ADD COLUMN thread_id TEXT NOT NULL DEFAULT '';
+ALTER TABLE statestore_receipts DROP CONSTRAINT statestore_receipts_pkey;
+ALTER TABLE statestore_receipts ADD PRIMARY KEY (room_id, receipt_type, thread_id, user_id);
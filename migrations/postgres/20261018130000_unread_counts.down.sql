@@ -0,0 +1,5 @@
+DROP INDEX statestore_receipts_user;
+ALTER TABLE statestore_receipts DROP COLUMN receipt_ts;
+ALTER TABLE statestore_rooms DROP COLUMN counts_updated_at;
+ALTER TABLE statestore_rooms DROP COLUMN highlight_count;
+ALTER TABLE statestore_rooms DROP COLUMN notification_count;
@@ -0,0 +1,16 @@
+-- Copies of the notification counts in room_info, and when they were stored in ms since the epoch
+ALTER TABLE statestore_rooms
+ADD COLUMN notification_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN highlight_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN counts_updated_at BIGINT NOT NULL DEFAULT 0;
+UPDATE statestore_rooms SET
+  notification_count = COALESCE((room_info->'notification_counts'->>'notification_count')::BIGINT, 0),
+  highlight_count = COALESCE((room_info->'notification_counts'->>'highlight_count')::BIGINT, 0),
+  counts_updated_at = (EXTRACT(EPOCH FROM NOW()) * 1000)::BIGINT;
+-- Copy of the receipt timestamp
+ALTER TABLE statestore_receipts
+ADD COLUMN receipt_ts BIGINT NOT NULL DEFAULT 0;
+UPDATE statestore_receipts SET receipt_ts = COALESCE((receipt->>'ts')::BIGINT, 0);
+CREATE INDEX statestore_receipts_user ON statestore_receipts (user_id, room_id);
@@ -0,0 +1,8 @@
+DROP TABLE statestore_room_tags;
+DROP INDEX statestore_rooms_updated;
+DROP INDEX statestore_rooms_name_sort;
+ALTER TABLE statestore_rooms DROP COLUMN name_sort;
+ALTER TABLE statestore_rooms DROP COLUMN room_name;
+ALTER TABLE statestore_rooms DROP COLUMN is_direct;
+ALTER TABLE statestore_rooms DROP COLUMN is_encrypted;
+ALTER TABLE statestore_rooms DROP COLUMN room_state;
@@ -0,0 +1,32 @@
+-- Copies of room_info fields used to filter and sort rooms
+ALTER TABLE statestore_rooms
+ADD COLUMN room_state TEXT NOT NULL DEFAULT 'Joined';
+ALTER TABLE statestore_rooms
+ADD COLUMN is_encrypted BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE statestore_rooms
+ADD COLUMN is_direct BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE statestore_rooms
+ADD COLUMN room_name TEXT;
+ALTER TABLE statestore_rooms
+ADD COLUMN name_sort TEXT NOT NULL DEFAULT '';
+UPDATE statestore_rooms SET
+  room_state = COALESCE(room_info->>'room_type', 'Joined'),
+  is_encrypted = COALESCE(jsonb_typeof(room_info->'base_info'->'encryption') = 'object', FALSE),
+  is_direct = COALESCE(jsonb_array_length(room_info->'base_info'->'dm_targets') > 0, FALSE),
+  room_name = room_info->'base_info'->'name'->'Original'->'content'->>'name';
+UPDATE statestore_rooms SET name_sort = LOWER(room_name) WHERE room_name IS NOT NULL;
+CREATE INDEX statestore_rooms_name_sort ON statestore_rooms (name_sort, room_id);
+CREATE INDEX statestore_rooms_updated ON statestore_rooms (counts_updated_at, room_id);
+-- Tags of each room, from the m.tag room account data
+CREATE TABLE statestore_room_tags (
+  room_id TEXT NOT NULL,
+  tag TEXT NOT NULL,
+  PRIMARY KEY (room_id, tag)
+);
+CREATE INDEX statestore_room_tags_tag ON statestore_room_tags (tag, room_id);
+INSERT INTO statestore_room_tags (room_id, tag)
+SELECT room_id, jsonb_object_keys(account_data->'content'->'tags')
+FROM statestore_accountdata
+WHERE room_id <> '' AND event_type = 'm.tag'
+  AND jsonb_typeof(account_data->'content'->'tags') = 'object'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,27 @@
+-- Lowercased display name and user ID, without the leading @, for member search
+ALTER TABLE statestore_members
+ADD COLUMN search_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE statestore_members
+ADD COLUMN search_user_id TEXT NOT NULL DEFAULT '';
+UPDATE statestore_members SET
+  search_name = LOWER(COALESCE(displayname, '')),
+  search_user_id = LOWER(SUBSTR(user_id, 2));
+CREATE INDEX statestore_members_search_name ON statestore_members (room_id, search_name text_pattern_ops);
+CREATE INDEX statestore_members_search_user_id ON statestore_members (room_id, search_user_id text_pattern_ops);
+-- Power levels of users from m.room.power_levels, the empty user ID holds users_default
+CREATE TABLE statestore_power_levels (
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  power_level BIGINT NOT NULL,
+  PRIMARY KEY (room_id, user_id)
+);
+INSERT INTO statestore_power_levels (room_id, user_id, power_level)
+SELECT room_id, '', COALESCE((state_event->'content'->>'users_default')::BIGINT, 0)
+FROM statestore_state
+WHERE event_type = 'm.room.power_levels' AND state_key = '' AND NOT is_partial;
+INSERT INTO statestore_power_levels (room_id, user_id, power_level)
+SELECT s.room_id, u.key, u.value::BIGINT
+FROM statestore_state s, jsonb_each_text(s.state_event->'content'->'users') u
+WHERE s.event_type = 'm.room.power_levels' AND s.state_key = '' AND NOT s.is_partial
+  AND jsonb_typeof(s.state_event->'content'->'users') = 'object'
+ON CONFLICT DO NOTHING;
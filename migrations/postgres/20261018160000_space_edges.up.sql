@@ -0,0 +1,21 @@
+-- Space hierarchy from m.space.child and m.space.parent state events with a non-empty via
+CREATE TABLE statestore_space_edges (
+  room_id TEXT NOT NULL,
+  event_type TEXT NOT NULL,
+  state_key TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  parent_id TEXT NOT NULL,
+  child_id TEXT NOT NULL,
+  PRIMARY KEY (room_id, event_type, state_key)
+);
+CREATE INDEX statestore_space_edges_parent ON statestore_space_edges (parent_id, event_type);
+CREATE INDEX statestore_space_edges_child ON statestore_space_edges (child_id, event_type);
+INSERT INTO statestore_space_edges (room_id, event_type, state_key, event_id, parent_id, child_id)
+SELECT room_id, event_type, state_key, event_id,
+  CASE event_type WHEN 'm.space.child' THEN room_id ELSE state_key END,
+  CASE event_type WHEN 'm.space.child' THEN state_key ELSE room_id END
+FROM statestore_state
+WHERE event_type IN ('m.space.child', 'm.space.parent') AND NOT is_partial
+  AND event_id IS NOT NULL
+  AND jsonb_typeof(state_event->'content'->'via') = 'array'
+  AND jsonb_array_length(state_event->'content'->'via') > 0;
@@ -0,0 +1,15 @@
+-- destructive: statestore_journal, statestore_checkpoints
+DROP TRIGGER statestore_space_edges_journal ON statestore_space_edges;
+DROP TRIGGER statestore_power_levels_journal ON statestore_power_levels;
+DROP TRIGGER statestore_room_tags_journal ON statestore_room_tags;
+DROP TRIGGER statestore_membership_history_journal ON statestore_membership_history;
+DROP TRIGGER statestore_state_history_journal ON statestore_state_history;
+DROP TRIGGER statestore_receipts_journal ON statestore_receipts;
+DROP TRIGGER statestore_state_journal ON statestore_state;
+DROP TRIGGER statestore_members_journal ON statestore_members;
+DROP TRIGGER statestore_presence_journal ON statestore_presence;
+DROP TRIGGER statestore_accountdata_journal ON statestore_accountdata;
+DROP TRIGGER statestore_rooms_journal ON statestore_rooms;
+DROP FUNCTION statestore_journal_row;
+DROP TABLE statestore_journal;
+DROP TABLE statestore_checkpoints;
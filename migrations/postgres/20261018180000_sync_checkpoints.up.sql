@@ -0,0 +1,65 @@
+-- One row per call of save_state_changes, the newest rows are kept
+CREATE TABLE statestore_checkpoints (
+  checkpoint_id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  -- The sync token stored after the changes were saved
+  sync_token TEXT,
+  created_at BIGINT NOT NULL,
+  -- TRUE while the changes of the checkpoint are being saved
+  is_open BOOLEAN NOT NULL DEFAULT FALSE
+);
+-- The previous contents of every row changed by a checkpoint
+CREATE TABLE statestore_journal (
+  checkpoint_id BIGINT NOT NULL,
+  table_name TEXT NOT NULL,
+  -- The primary key columns of the row as a JSON object
+  row_key TEXT NOT NULL,
+  -- The row before the first change of the checkpoint, NULL if it did not exist
+  old_row JSONB,
+  PRIMARY KEY (checkpoint_id, table_name, row_key)
+);
+CREATE INDEX statestore_journal_key ON statestore_journal (table_name, row_key, checkpoint_id);
+
+-- Records the row before its first change while a checkpoint is open
+--
+-- The arguments of the trigger are the primary key columns of the table.
+CREATE FUNCTION statestore_journal_row() RETURNS TRIGGER AS $$
+DECLARE
+  changed JSONB;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    changed := to_jsonb(NEW);
+  ELSE
+    changed := to_jsonb(OLD);
+  END IF;
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT checkpoint_id, TG_TABLE_NAME,
+    (SELECT jsonb_object_agg(k, changed -> k) FROM unnest(TG_ARGV) AS k)::TEXT,
+    CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE changed END
+  FROM statestore_checkpoints WHERE is_open
+  ON CONFLICT DO NOTHING;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER statestore_rooms_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_rooms
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id');
+CREATE TRIGGER statestore_accountdata_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_accountdata
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'event_type');
+CREATE TRIGGER statestore_presence_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_presence
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('user_id');
+CREATE TRIGGER statestore_members_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_members
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'user_id');
+CREATE TRIGGER statestore_state_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_state
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'event_type', 'state_key');
+CREATE TRIGGER statestore_receipts_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_receipts
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'receipt_type', 'thread_id', 'user_id');
+CREATE TRIGGER statestore_state_history_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_state_history
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('history_id');
+CREATE TRIGGER statestore_membership_history_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_membership_history
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('history_id');
+CREATE TRIGGER statestore_room_tags_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_room_tags
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'tag');
+CREATE TRIGGER statestore_power_levels_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_power_levels
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'user_id');
+CREATE TRIGGER statestore_space_edges_journal AFTER INSERT OR UPDATE OR DELETE ON statestore_space_edges
+FOR EACH ROW EXECUTE FUNCTION statestore_journal_row('room_id', 'event_type', 'state_key');
@@ -0,0 +1,2 @@
+-- destructive: cryptostore_room_key_withheld
+DROP TABLE cryptostore_room_key_withheld;
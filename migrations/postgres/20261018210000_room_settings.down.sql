@@ -0,0 +1,2 @@
+-- destructive: cryptostore_room_settings
+DROP TABLE cryptostore_room_settings;
@@ -0,0 +1,6 @@
+-- The encryption policy of a room
+CREATE TABLE cryptostore_room_settings (
+  room_id BYTEA PRIMARY KEY NOT NULL,
+  -- The encrypted settings
+  settings_data BYTEA NOT NULL
+);
@@ -0,0 +1,10 @@
+-- The devices that received the current outbound group session of a room
+CREATE TABLE cryptostore_outbound_session_share (
+  room_id BYTEA NOT NULL,
+  session_id BYTEA NOT NULL,
+  user_id BYTEA NOT NULL,
+  device_id BYTEA NOT NULL,
+  -- The encrypted recipient, message index and time of the share
+  share_data BYTEA NOT NULL,
+  PRIMARY KEY (room_id, session_id, user_id, device_id)
+);
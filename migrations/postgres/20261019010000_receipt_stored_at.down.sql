@@ -0,0 +1,4 @@
+-- destructive: statestore_receipts.stored_at
+UPDATE statestore_journal SET old_row = old_row - 'stored_at'
+WHERE table_name = 'statestore_receipts' AND old_row IS NOT NULL;
+ALTER TABLE statestore_receipts DROP COLUMN stored_at;
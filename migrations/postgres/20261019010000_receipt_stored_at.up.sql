@@ -0,0 +1,5 @@
+-- When the receipt was stored in ms since the epoch, to compare it with the unread counts
+ALTER TABLE statestore_receipts
+ADD COLUMN stored_at BIGINT NOT NULL DEFAULT 0;
+UPDATE statestore_journal SET old_row = old_row || '{"stored_at": 0}'
+WHERE table_name = 'statestore_receipts' AND old_row IS NOT NULL;
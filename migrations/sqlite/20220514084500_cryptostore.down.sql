@@ -1,3 +1,4 @@
+-- destructive: cryptostore_tracked_user, cryptostore_device, cryptostore_identity, cryptostore_gossip_request, cryptostore_outbound_group_session, cryptostore_inbound_group_session, cryptostore_message_hash, cryptostore_session
 DROP TABLE cryptostore_tracked_user;
 DROP TABLE cryptostore_device;
 DROP TABLE cryptostore_identity;
@@ -0,0 +1,4 @@
+-- destructive: statestore_state_history
+DROP INDEX statestore_state_history_key;
+DROP INDEX statestore_state_history_event;
+DROP TABLE statestore_state_history;
@@ -0,0 +1,21 @@
+-- Every state event ever stored, in the order it was received
+CREATE TABLE statestore_state_history (
+  history_id INTEGER PRIMARY KEY NOT NULL,
+  room_id TEXT NOT NULL,
+  event_type TEXT NOT NULL,
+  state_key TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  sender TEXT NOT NULL,
+  origin_server_ts INTEGER NOT NULL,
+  redacted BOOLEAN NOT NULL DEFAULT FALSE,
+  state_event JSON NOT NULL
+);
+CREATE UNIQUE INDEX statestore_state_history_event ON statestore_state_history (room_id, event_id);
+CREATE INDEX statestore_state_history_key ON statestore_state_history (room_id, event_type, state_key, history_id);
+-- Start the history with the current state
+INSERT INTO statestore_state_history
+  (room_id, event_type, state_key, event_id, sender, origin_server_ts, state_event)
+SELECT room_id, event_type, state_key, event_id, json_extract(state_event, '$.sender'),
+  json_extract(state_event, '$.origin_server_ts'), state_event
+FROM statestore_state
+WHERE NOT is_partial AND event_id IS NOT NULL;
@@ -0,0 +1,5 @@
+-- destructive: statestore_membership_history
+DROP INDEX statestore_members_user_id;
+DROP INDEX statestore_membership_history_user;
+DROP INDEX statestore_membership_history_event;
+DROP TABLE statestore_membership_history;
@@ -0,0 +1,20 @@
+-- Every change of membership, in the order it was received
+CREATE TABLE statestore_membership_history (
+  history_id INTEGER PRIMARY KEY NOT NULL,
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  sender TEXT NOT NULL,
+  membership TEXT NOT NULL,
+  origin_server_ts INTEGER NOT NULL
+);
+CREATE UNIQUE INDEX statestore_membership_history_event ON statestore_membership_history (room_id, event_id);
+CREATE INDEX statestore_membership_history_user ON statestore_membership_history (room_id, user_id, history_id);
+CREATE INDEX statestore_members_user_id ON statestore_members (user_id);
+-- Start the history with the current memberships
+INSERT INTO statestore_membership_history
+  (room_id, user_id, event_id, sender, membership, origin_server_ts)
+SELECT room_id, user_id, json_extract(member_event, '$.event_id'), json_extract(member_event, '$.sender'),
+  json_extract(member_event, '$.content.membership'), json_extract(member_event, '$.origin_server_ts')
+FROM statestore_members
+WHERE NOT is_partial AND json_extract(member_event, '$.event_id') IS NOT NULL;
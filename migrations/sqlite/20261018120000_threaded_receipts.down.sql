@@ -0,0 +1,16 @@
+-- destructive: statestore_receipts.thread_id
+CREATE TABLE statestore_receipts_unthreaded (
+  room_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  receipt_type TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  receipt JSON NOT NULL,
+  PRIMARY KEY (room_id, receipt_type, user_id)
+);
+INSERT INTO statestore_receipts_unthreaded (room_id, event_id, receipt_type, user_id, receipt)
+SELECT room_id, event_id, receipt_type, user_id, receipt FROM statestore_receipts
+WHERE thread_id = '';
+DROP INDEX statestore_receipts_room_event;
+DROP TABLE statestore_receipts;
+ALTER TABLE statestore_receipts_unthreaded RENAME TO statestore_receipts;
+CREATE INDEX statestore_receipts_room_event ON statestore_receipts (room_id, receipt_type, event_id);
@@ -0,0 +1,17 @@
+-- sqlite cannot change the primary key of a table, so the table is recreated
+-- An empty thread ID marks an unthreaded receipt
+CREATE TABLE statestore_receipts_threaded (
+  room_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  receipt_type TEXT NOT NULL,
+  thread_id TEXT NOT NULL DEFAULT '',
+  user_id TEXT NOT NULL,
+  receipt JSON NOT NULL,
+  PRIMARY KEY (room_id, receipt_type, thread_id, user_id)
+);
+INSERT INTO statestore_receipts_threaded (room_id, event_id, receipt_type, user_id, receipt)
+SELECT room_id, event_id, receipt_type, user_id, receipt FROM statestore_receipts;
+DROP INDEX statestore_receipts_room_event;
+DROP TABLE statestore_receipts;
+ALTER TABLE statestore_receipts_threaded RENAME TO statestore_receipts;
+CREATE INDEX statestore_receipts_room_event ON statestore_receipts (room_id, receipt_type, event_id);
@@ -0,0 +1,16 @@
+-- Copies of the notification counts in room_info, and when they were stored in ms since the epoch
+ALTER TABLE statestore_rooms
+ADD COLUMN notification_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN highlight_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN counts_updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE statestore_rooms SET
+  notification_count = COALESCE(json_extract(room_info, '$.notification_counts.notification_count'), 0),
+  highlight_count = COALESCE(json_extract(room_info, '$.notification_counts.highlight_count'), 0),
+  counts_updated_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER);
+-- Copy of the receipt timestamp
+ALTER TABLE statestore_receipts
+ADD COLUMN receipt_ts INTEGER NOT NULL DEFAULT 0;
+UPDATE statestore_receipts SET receipt_ts = COALESCE(json_extract(receipt, '$.ts'), 0);
+CREATE INDEX statestore_receipts_user ON statestore_receipts (user_id, room_id);
@@ -0,0 +1,31 @@
+-- Copies of room_info fields used to filter and sort rooms
+ALTER TABLE statestore_rooms
+ADD COLUMN room_state TEXT NOT NULL DEFAULT 'Joined';
+ALTER TABLE statestore_rooms
+ADD COLUMN is_encrypted BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN is_direct BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE statestore_rooms
+ADD COLUMN room_name TEXT;
+ALTER TABLE statestore_rooms
+ADD COLUMN name_sort TEXT NOT NULL DEFAULT '';
+UPDATE statestore_rooms SET
+  room_state = COALESCE(json_extract(room_info, '$.room_type'), 'Joined'),
+  is_encrypted = COALESCE(json_type(room_info, '$.base_info.encryption') = 'object', 0),
+  is_direct = COALESCE(json_array_length(room_info, '$.base_info.dm_targets') > 0, 0),
+  room_name = json_extract(room_info, '$.base_info.name.Original.content.name');
+UPDATE statestore_rooms SET name_sort = LOWER(room_name) WHERE room_name IS NOT NULL;
+CREATE INDEX statestore_rooms_name_sort ON statestore_rooms (name_sort, room_id);
+CREATE INDEX statestore_rooms_updated ON statestore_rooms (counts_updated_at, room_id);
+-- Tags of each room, from the m.tag room account data
+CREATE TABLE statestore_room_tags (
+  room_id TEXT NOT NULL,
+  tag TEXT NOT NULL,
+  PRIMARY KEY (room_id, tag)
+);
+CREATE INDEX statestore_room_tags_tag ON statestore_room_tags (tag, room_id);
+INSERT OR IGNORE INTO statestore_room_tags (room_id, tag)
+SELECT a.room_id, t.key
+FROM statestore_accountdata a, json_each(a.account_data, '$.content.tags') t
+WHERE a.room_id <> '' AND a.event_type = 'm.tag'
+  AND json_type(a.account_data, '$.content.tags') = 'object';
@@ -0,0 +1,5 @@
+DROP TABLE statestore_power_levels;
+DROP INDEX statestore_members_search_user_id;
+DROP INDEX statestore_members_search_name;
+ALTER TABLE statestore_members DROP COLUMN search_user_id;
+ALTER TABLE statestore_members DROP COLUMN search_name;
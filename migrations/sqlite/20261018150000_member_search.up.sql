@@ -0,0 +1,26 @@
+-- Lowercased display name and user ID, without the leading @, for member search
+ALTER TABLE statestore_members
+ADD COLUMN search_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE statestore_members
+ADD COLUMN search_user_id TEXT NOT NULL DEFAULT '';
+UPDATE statestore_members SET
+  search_name = LOWER(COALESCE(displayname, '')),
+  search_user_id = LOWER(SUBSTR(user_id, 2));
+CREATE INDEX statestore_members_search_name ON statestore_members (room_id, search_name);
+CREATE INDEX statestore_members_search_user_id ON statestore_members (room_id, search_user_id);
+-- Power levels of users from m.room.power_levels, the empty user ID holds users_default
+CREATE TABLE statestore_power_levels (
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  power_level INTEGER NOT NULL,
+  PRIMARY KEY (room_id, user_id)
+);
+INSERT INTO statestore_power_levels (room_id, user_id, power_level)
+SELECT room_id, '', COALESCE(json_extract(state_event, '$.content.users_default'), 0)
+FROM statestore_state
+WHERE event_type = 'm.room.power_levels' AND state_key = '' AND NOT is_partial;
+INSERT OR IGNORE INTO statestore_power_levels (room_id, user_id, power_level)
+SELECT s.room_id, u.key, CAST(u.value AS INTEGER)
+FROM statestore_state s, json_each(s.state_event, '$.content.users') u
+WHERE s.event_type = 'm.room.power_levels' AND s.state_key = '' AND NOT s.is_partial
+  AND json_type(s.state_event, '$.content.users') = 'object';
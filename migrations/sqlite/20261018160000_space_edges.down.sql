@@ -0,0 +1 @@
+DROP TABLE statestore_space_edges;
@@ -0,0 +1,3 @@
+-- destructive: statestore_kv.expires_at
+DROP INDEX statestore_kv_expires_at;
+ALTER TABLE statestore_kv DROP COLUMN expires_at;
@@ -0,0 +1,4 @@
+-- Expiration time of custom values in ms since the epoch, NULL for values that do not expire
+ALTER TABLE statestore_kv
+ADD COLUMN expires_at INTEGER;
+CREATE INDEX statestore_kv_expires_at ON statestore_kv (expires_at);
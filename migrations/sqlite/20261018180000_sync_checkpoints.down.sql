@@ -0,0 +1,36 @@
+-- destructive: statestore_journal, statestore_checkpoints
+DROP TRIGGER statestore_space_edges_journal_delete;
+DROP TRIGGER statestore_space_edges_journal_update;
+DROP TRIGGER statestore_space_edges_journal_insert;
+DROP TRIGGER statestore_power_levels_journal_delete;
+DROP TRIGGER statestore_power_levels_journal_update;
+DROP TRIGGER statestore_power_levels_journal_insert;
+DROP TRIGGER statestore_room_tags_journal_delete;
+DROP TRIGGER statestore_room_tags_journal_update;
+DROP TRIGGER statestore_room_tags_journal_insert;
+DROP TRIGGER statestore_membership_history_journal_delete;
+DROP TRIGGER statestore_membership_history_journal_update;
+DROP TRIGGER statestore_membership_history_journal_insert;
+DROP TRIGGER statestore_state_history_journal_delete;
+DROP TRIGGER statestore_state_history_journal_update;
+DROP TRIGGER statestore_state_history_journal_insert;
+DROP TRIGGER statestore_receipts_journal_delete;
+DROP TRIGGER statestore_receipts_journal_update;
+DROP TRIGGER statestore_receipts_journal_insert;
+DROP TRIGGER statestore_state_journal_delete;
+DROP TRIGGER statestore_state_journal_update;
+DROP TRIGGER statestore_state_journal_insert;
+DROP TRIGGER statestore_members_journal_delete;
+DROP TRIGGER statestore_members_journal_update;
+DROP TRIGGER statestore_members_journal_insert;
+DROP TRIGGER statestore_presence_journal_delete;
+DROP TRIGGER statestore_presence_journal_update;
+DROP TRIGGER statestore_presence_journal_insert;
+DROP TRIGGER statestore_accountdata_journal_delete;
+DROP TRIGGER statestore_accountdata_journal_update;
+DROP TRIGGER statestore_accountdata_journal_insert;
+DROP TRIGGER statestore_rooms_journal_delete;
+DROP TRIGGER statestore_rooms_journal_update;
+DROP TRIGGER statestore_rooms_journal_insert;
+DROP TABLE statestore_journal;
+DROP TABLE statestore_checkpoints;
@@ -0,0 +1,387 @@
+-- One row per call of save_state_changes, the newest rows are kept
+CREATE TABLE statestore_checkpoints (
+  checkpoint_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  -- The sync token stored after the changes were saved
+  sync_token TEXT,
+  created_at INTEGER NOT NULL,
+  -- TRUE while the changes of the checkpoint are being saved
+  is_open BOOLEAN NOT NULL DEFAULT FALSE
+);
+-- The previous contents of every row changed by a checkpoint
+CREATE TABLE statestore_journal (
+  checkpoint_id INTEGER NOT NULL,
+  table_name TEXT NOT NULL,
+  -- The primary key columns of the row as a JSON object
+  row_key TEXT NOT NULL,
+  -- The row before the first change of the checkpoint, NULL if it did not exist
+  old_row JSON,
+  PRIMARY KEY (checkpoint_id, table_name, row_key)
+);
+CREATE INDEX statestore_journal_key ON statestore_journal (table_name, row_key, checkpoint_id);
+
+-- Record the row before its first change while a checkpoint is open
+--
+-- The triggers list the columns of their table and have to be recreated when columns are added.
+CREATE TRIGGER statestore_rooms_journal_insert AFTER INSERT ON statestore_rooms
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_rooms', json_object('room_id', NEW.room_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_rooms'
+      AND j.row_key = json_object('room_id', NEW.room_id)
+  );
+END;
+CREATE TRIGGER statestore_rooms_journal_update AFTER UPDATE ON statestore_rooms
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_rooms', json_object('room_id', OLD.room_id),
+    json_object('room_id', OLD.room_id, 'is_partial', OLD.is_partial, 'room_info', OLD.room_info, 'notification_count', OLD.notification_count, 'highlight_count', OLD.highlight_count, 'counts_updated_at', OLD.counts_updated_at, 'room_state', OLD.room_state, 'is_encrypted', OLD.is_encrypted, 'is_direct', OLD.is_direct, 'room_name', OLD.room_name, 'name_sort', OLD.name_sort)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_rooms'
+      AND j.row_key = json_object('room_id', OLD.room_id)
+  );
+END;
+CREATE TRIGGER statestore_rooms_journal_delete AFTER DELETE ON statestore_rooms
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_rooms', json_object('room_id', OLD.room_id),
+    json_object('room_id', OLD.room_id, 'is_partial', OLD.is_partial, 'room_info', OLD.room_info, 'notification_count', OLD.notification_count, 'highlight_count', OLD.highlight_count, 'counts_updated_at', OLD.counts_updated_at, 'room_state', OLD.room_state, 'is_encrypted', OLD.is_encrypted, 'is_direct', OLD.is_direct, 'room_name', OLD.room_name, 'name_sort', OLD.name_sort)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_rooms'
+      AND j.row_key = json_object('room_id', OLD.room_id)
+  );
+END;
+CREATE TRIGGER statestore_accountdata_journal_insert AFTER INSERT ON statestore_accountdata
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_accountdata', json_object('room_id', NEW.room_id, 'event_type', NEW.event_type),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_accountdata'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'event_type', NEW.event_type)
+  );
+END;
+CREATE TRIGGER statestore_accountdata_journal_update AFTER UPDATE ON statestore_accountdata
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_accountdata', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'account_data', OLD.account_data)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_accountdata'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type)
+  );
+END;
+CREATE TRIGGER statestore_accountdata_journal_delete AFTER DELETE ON statestore_accountdata
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_accountdata', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'account_data', OLD.account_data)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_accountdata'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type)
+  );
+END;
+CREATE TRIGGER statestore_presence_journal_insert AFTER INSERT ON statestore_presence
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_presence', json_object('user_id', NEW.user_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_presence'
+      AND j.row_key = json_object('user_id', NEW.user_id)
+  );
+END;
+CREATE TRIGGER statestore_presence_journal_update AFTER UPDATE ON statestore_presence
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_presence', json_object('user_id', OLD.user_id),
+    json_object('user_id', OLD.user_id, 'presence', OLD.presence)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_presence'
+      AND j.row_key = json_object('user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_presence_journal_delete AFTER DELETE ON statestore_presence
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_presence', json_object('user_id', OLD.user_id),
+    json_object('user_id', OLD.user_id, 'presence', OLD.presence)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_presence'
+      AND j.row_key = json_object('user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_members_journal_insert AFTER INSERT ON statestore_members
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_members', json_object('room_id', NEW.room_id, 'user_id', NEW.user_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_members'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'user_id', NEW.user_id)
+  );
+END;
+CREATE TRIGGER statestore_members_journal_update AFTER UPDATE ON statestore_members
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_members', json_object('room_id', OLD.room_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'user_id', OLD.user_id, 'is_partial', OLD.is_partial, 'member_event', OLD.member_event, 'user_profile', OLD.user_profile, 'displayname', OLD.displayname, 'joined', OLD.joined, 'search_name', OLD.search_name, 'search_user_id', OLD.search_user_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_members'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_members_journal_delete AFTER DELETE ON statestore_members
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_members', json_object('room_id', OLD.room_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'user_id', OLD.user_id, 'is_partial', OLD.is_partial, 'member_event', OLD.member_event, 'user_profile', OLD.user_profile, 'displayname', OLD.displayname, 'joined', OLD.joined, 'search_name', OLD.search_name, 'search_user_id', OLD.search_user_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_members'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_state_journal_insert AFTER INSERT ON statestore_state
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state', json_object('room_id', NEW.room_id, 'event_type', NEW.event_type, 'state_key', NEW.state_key),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'event_type', NEW.event_type, 'state_key', NEW.state_key)
+  );
+END;
+CREATE TRIGGER statestore_state_journal_update AFTER UPDATE ON statestore_state
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'is_partial', OLD.is_partial, 'state_event', OLD.state_event, 'event_id', OLD.event_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key)
+  );
+END;
+CREATE TRIGGER statestore_state_journal_delete AFTER DELETE ON statestore_state
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'is_partial', OLD.is_partial, 'state_event', OLD.state_event, 'event_id', OLD.event_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key)
+  );
+END;
+CREATE TRIGGER statestore_receipts_journal_insert AFTER INSERT ON statestore_receipts
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_receipts', json_object('room_id', NEW.room_id, 'receipt_type', NEW.receipt_type, 'thread_id', NEW.thread_id, 'user_id', NEW.user_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_receipts'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'receipt_type', NEW.receipt_type, 'thread_id', NEW.thread_id, 'user_id', NEW.user_id)
+  );
+END;
+CREATE TRIGGER statestore_receipts_journal_update AFTER UPDATE ON statestore_receipts
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_receipts', json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'event_id', OLD.event_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id, 'receipt', OLD.receipt, 'receipt_ts', OLD.receipt_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_receipts'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_receipts_journal_delete AFTER DELETE ON statestore_receipts
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_receipts', json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'event_id', OLD.event_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id, 'receipt', OLD.receipt, 'receipt_ts', OLD.receipt_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_receipts'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_state_history_journal_insert AFTER INSERT ON statestore_state_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state_history', json_object('history_id', NEW.history_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state_history'
+      AND j.row_key = json_object('history_id', NEW.history_id)
+  );
+END;
+CREATE TRIGGER statestore_state_history_journal_update AFTER UPDATE ON statestore_state_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state_history', json_object('history_id', OLD.history_id),
+    json_object('history_id', OLD.history_id, 'room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'event_id', OLD.event_id, 'sender', OLD.sender, 'origin_server_ts', OLD.origin_server_ts, 'redacted', OLD.redacted, 'state_event', OLD.state_event)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state_history'
+      AND j.row_key = json_object('history_id', OLD.history_id)
+  );
+END;
+CREATE TRIGGER statestore_state_history_journal_delete AFTER DELETE ON statestore_state_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_state_history', json_object('history_id', OLD.history_id),
+    json_object('history_id', OLD.history_id, 'room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'event_id', OLD.event_id, 'sender', OLD.sender, 'origin_server_ts', OLD.origin_server_ts, 'redacted', OLD.redacted, 'state_event', OLD.state_event)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_state_history'
+      AND j.row_key = json_object('history_id', OLD.history_id)
+  );
+END;
+CREATE TRIGGER statestore_membership_history_journal_insert AFTER INSERT ON statestore_membership_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_membership_history', json_object('history_id', NEW.history_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_membership_history'
+      AND j.row_key = json_object('history_id', NEW.history_id)
+  );
+END;
+CREATE TRIGGER statestore_membership_history_journal_update AFTER UPDATE ON statestore_membership_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_membership_history', json_object('history_id', OLD.history_id),
+    json_object('history_id', OLD.history_id, 'room_id', OLD.room_id, 'user_id', OLD.user_id, 'event_id', OLD.event_id, 'sender', OLD.sender, 'membership', OLD.membership, 'origin_server_ts', OLD.origin_server_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_membership_history'
+      AND j.row_key = json_object('history_id', OLD.history_id)
+  );
+END;
+CREATE TRIGGER statestore_membership_history_journal_delete AFTER DELETE ON statestore_membership_history
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_membership_history', json_object('history_id', OLD.history_id),
+    json_object('history_id', OLD.history_id, 'room_id', OLD.room_id, 'user_id', OLD.user_id, 'event_id', OLD.event_id, 'sender', OLD.sender, 'membership', OLD.membership, 'origin_server_ts', OLD.origin_server_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_membership_history'
+      AND j.row_key = json_object('history_id', OLD.history_id)
+  );
+END;
+CREATE TRIGGER statestore_room_tags_journal_insert AFTER INSERT ON statestore_room_tags
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_room_tags', json_object('room_id', NEW.room_id, 'tag', NEW.tag),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_room_tags'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'tag', NEW.tag)
+  );
+END;
+CREATE TRIGGER statestore_room_tags_journal_update AFTER UPDATE ON statestore_room_tags
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_room_tags', json_object('room_id', OLD.room_id, 'tag', OLD.tag),
+    json_object('room_id', OLD.room_id, 'tag', OLD.tag)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_room_tags'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'tag', OLD.tag)
+  );
+END;
+CREATE TRIGGER statestore_room_tags_journal_delete AFTER DELETE ON statestore_room_tags
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_room_tags', json_object('room_id', OLD.room_id, 'tag', OLD.tag),
+    json_object('room_id', OLD.room_id, 'tag', OLD.tag)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_room_tags'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'tag', OLD.tag)
+  );
+END;
+CREATE TRIGGER statestore_power_levels_journal_insert AFTER INSERT ON statestore_power_levels
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_power_levels', json_object('room_id', NEW.room_id, 'user_id', NEW.user_id),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_power_levels'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'user_id', NEW.user_id)
+  );
+END;
+CREATE TRIGGER statestore_power_levels_journal_update AFTER UPDATE ON statestore_power_levels
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_power_levels', json_object('room_id', OLD.room_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'user_id', OLD.user_id, 'power_level', OLD.power_level)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_power_levels'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_power_levels_journal_delete AFTER DELETE ON statestore_power_levels
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_power_levels', json_object('room_id', OLD.room_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'user_id', OLD.user_id, 'power_level', OLD.power_level)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_power_levels'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_space_edges_journal_insert AFTER INSERT ON statestore_space_edges
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_space_edges', json_object('room_id', NEW.room_id, 'event_type', NEW.event_type, 'state_key', NEW.state_key),
+    NULL
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_space_edges'
+      AND j.row_key = json_object('room_id', NEW.room_id, 'event_type', NEW.event_type, 'state_key', NEW.state_key)
+  );
+END;
+CREATE TRIGGER statestore_space_edges_journal_update AFTER UPDATE ON statestore_space_edges
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_space_edges', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'event_id', OLD.event_id, 'parent_id', OLD.parent_id, 'child_id', OLD.child_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_space_edges'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key)
+  );
+END;
+CREATE TRIGGER statestore_space_edges_journal_delete AFTER DELETE ON statestore_space_edges
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_space_edges', json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key),
+    json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key, 'event_id', OLD.event_id, 'parent_id', OLD.parent_id, 'child_id', OLD.child_id)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_space_edges'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'event_type', OLD.event_type, 'state_key', OLD.state_key)
+  );
+END;
@@ -0,0 +1,3 @@
+-- destructive: cryptostore_secret_inbox
+DROP INDEX cryptostore_secret_inbox_secret_name_idx;
+DROP TABLE cryptostore_secret_inbox;
@@ -0,0 +1,9 @@
+-- Secrets received from other devices that have not been processed yet
+CREATE TABLE cryptostore_secret_inbox (
+  secret_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  -- The hashed name of the secret
+  secret_name BLOB NOT NULL,
+  -- The encrypted secret
+  secret_data BLOB NOT NULL
+);
+CREATE INDEX cryptostore_secret_inbox_secret_name_idx ON cryptostore_secret_inbox (secret_name);
@@ -0,0 +1,9 @@
+-- Room keys that the sender refused to share with us (m.room_key.withheld)
+CREATE TABLE cryptostore_room_key_withheld (
+  room_id BLOB NOT NULL,
+  sender_key BLOB NOT NULL,
+  session_id BLOB NOT NULL,
+  -- The encrypted withheld code and reason
+  withheld_data BLOB NOT NULL,
+  PRIMARY KEY (room_id, sender_key, session_id)
+);
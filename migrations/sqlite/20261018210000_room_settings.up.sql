@@ -0,0 +1,6 @@
+-- The encryption policy of a room
+CREATE TABLE cryptostore_room_settings (
+  room_id BLOB PRIMARY KEY NOT NULL,
+  -- The encrypted settings
+  settings_data BLOB NOT NULL
+);
@@ -0,0 +1,2 @@
+-- destructive: cryptostore_outbound_session_share
+DROP TABLE cryptostore_outbound_session_share;
@@ -0,0 +1,10 @@
+-- The devices that received the current outbound group session of a room
+CREATE TABLE cryptostore_outbound_session_share (
+  room_id BLOB NOT NULL,
+  session_id BLOB NOT NULL,
+  user_id BLOB NOT NULL,
+  device_id BLOB NOT NULL,
+  -- The encrypted recipient, message index and time of the share
+  share_data BLOB NOT NULL,
+  PRIMARY KEY (room_id, session_id, user_id, device_id)
+);
@@ -0,0 +1,4 @@
+-- destructive: cryptostore_identity_change
+DROP INDEX cryptostore_identity_change_acknowledged_idx;
+DROP INDEX cryptostore_identity_change_user_id_idx;
+DROP TABLE cryptostore_identity_change;
@@ -0,0 +1,12 @@
+-- Append-only log of master key changes of cross-signing identities
+CREATE TABLE cryptostore_identity_change (
+  change_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  -- The hashed user ID
+  user_id BLOB NOT NULL,
+  -- The encrypted old and new master keys, time and previous verification state
+  change_data BLOB NOT NULL,
+  -- FALSE until the change was acknowledged, the user is flagged meanwhile
+  acknowledged BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX cryptostore_identity_change_user_id_idx ON cryptostore_identity_change (user_id);
+CREATE INDEX cryptostore_identity_change_acknowledged_idx ON cryptostore_identity_change (acknowledged);
@@ -0,0 +1,4 @@
+DROP INDEX statestore_members_search_user_id;
+DROP INDEX statestore_members_search_name;
+CREATE INDEX statestore_members_search_name ON statestore_members (room_id, search_name);
+CREATE INDEX statestore_members_search_user_id ON statestore_members (room_id, search_user_id);
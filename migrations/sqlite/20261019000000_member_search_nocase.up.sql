@@ -0,0 +1,5 @@
+-- NOCASE indexes, so that SQLite uses them for the LIKE prefix search
+DROP INDEX statestore_members_search_name;
+DROP INDEX statestore_members_search_user_id;
+CREATE INDEX statestore_members_search_name ON statestore_members (room_id, search_name COLLATE NOCASE);
+CREATE INDEX statestore_members_search_user_id ON statestore_members (room_id, search_user_id COLLATE NOCASE);
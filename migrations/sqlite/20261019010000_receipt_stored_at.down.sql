@@ -0,0 +1,28 @@
+-- destructive: statestore_receipts.stored_at
+DROP TRIGGER statestore_receipts_journal_update;
+DROP TRIGGER statestore_receipts_journal_delete;
+UPDATE statestore_journal SET old_row = json_remove(old_row, '$.stored_at')
+WHERE table_name = 'statestore_receipts' AND old_row IS NOT NULL;
+ALTER TABLE statestore_receipts DROP COLUMN stored_at;
+CREATE TRIGGER statestore_receipts_journal_update AFTER UPDATE ON statestore_receipts
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_receipts', json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'event_id', OLD.event_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id, 'receipt', OLD.receipt, 'receipt_ts', OLD.receipt_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_receipts'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id)
+  );
+END;
+CREATE TRIGGER statestore_receipts_journal_delete AFTER DELETE ON statestore_receipts
+BEGIN
+  INSERT INTO statestore_journal (checkpoint_id, table_name, row_key, old_row)
+  SELECT c.checkpoint_id, 'statestore_receipts', json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id),
+    json_object('room_id', OLD.room_id, 'event_id', OLD.event_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id, 'receipt', OLD.receipt, 'receipt_ts', OLD.receipt_ts)
+  FROM statestore_checkpoints c WHERE c.is_open AND NOT EXISTS (
+    SELECT 1 FROM statestore_journal j
+    WHERE j.checkpoint_id = c.checkpoint_id AND j.table_name = 'statestore_receipts'
+      AND j.row_key = json_object('room_id', OLD.room_id, 'receipt_type', OLD.receipt_type, 'thread_id', OLD.thread_id, 'user_id', OLD.user_id)
+  );
+END;
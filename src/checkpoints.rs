@@ -0,0 +1,674 @@
+//! Sync checkpoints with a change journal that allow rolling the state store back
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    history::decode_timestamp,
+    Result, SQLStoreError, StateStore, SupportedDatabase,
+};
+
+/// Maximum number of sync checkpoints that are kept
+pub(crate) const CHECKPOINT_LIMIT: i64 = 100;
+
+/// A table whose changes are recorded in the journal
+pub(crate) struct JournaledTable {
+    /// The name of the table
+    pub(crate) name: &'static str,
+    /// The primary key columns of the table
+    pub(crate) key: &'static [&'static str],
+}
+
+/// The tables that are journaled by triggers while a checkpoint is open
+///
+/// The columns of the tables are read from the database when rolling back. The sqlite triggers
+/// list them as well, and have to be recreated by migrations that add columns.
+pub(crate) const JOURNALED_TABLES: &[JournaledTable] = &[
+    JournaledTable {
+        name: "statestore_rooms",
+        key: &["room_id"],
+    },
+    JournaledTable {
+        name: "statestore_accountdata",
+        key: &["room_id", "event_type"],
+    },
+    JournaledTable {
+        name: "statestore_presence",
+        key: &["user_id"],
+    },
+    JournaledTable {
+        name: "statestore_members",
+        key: &["room_id", "user_id"],
+    },
+    JournaledTable {
+        name: "statestore_state",
+        key: &["room_id", "event_type", "state_key"],
+    },
+    JournaledTable {
+        name: "statestore_receipts",
+        key: &["room_id", "receipt_type", "thread_id", "user_id"],
+    },
+    JournaledTable {
+        name: "statestore_state_history",
+        key: &["history_id"],
+    },
+    JournaledTable {
+        name: "statestore_membership_history",
+        key: &["history_id"],
+    },
+    JournaledTable {
+        name: "statestore_room_tags",
+        key: &["room_id", "tag"],
+    },
+    JournaledTable {
+        name: "statestore_power_levels",
+        key: &["room_id", "user_id"],
+    },
+    JournaledTable {
+        name: "statestore_space_edges",
+        key: &["room_id", "event_type", "state_key"],
+    },
+];
+
+/// A saved batch of state changes that the store can be rolled back to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncCheckpoint {
+    /// The ID of the checkpoint, increasing with every save
+    pub id: i64,
+    /// The sync token stored after the changes were saved
+    pub sync_token: Option<String>,
+    /// When the changes were saved
+    pub created_at: MilliSecondsSinceUnixEpoch,
+    /// The number of rows changed by the checkpoint
+    pub changes: u64,
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Opens a sync checkpoint in a transaction
+    ///
+    /// Changes to the journaled tables are recorded until the checkpoint is closed.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(crate) async fn open_checkpoint<'c>(txn: &mut Transaction<'c, DB>) -> Result<()> {
+        DB::checkpoint_open_query()
+            .bind(i64::from(MilliSecondsSinceUnixEpoch::now().0))
+            .execute(txn)
+            .await?;
+        Ok(())
+    }
+
+    /// Closes the open sync checkpoint in a transaction and drops the oldest checkpoints
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(crate) async fn close_checkpoint<'c>(
+        txn: &mut Transaction<'c, DB>,
+        sync_token: Option<&str>,
+    ) -> Result<()> {
+        let sync_token = match sync_token {
+            Some(sync_token) => Some(sync_token.to_owned()),
+            None => {
+                let row = DB::kv_load_query()
+                    .bind(b"sync_token".as_slice())
+                    .fetch_optional(&mut *txn)
+                    .await?;
+                match row {
+                    Some(row) => Some(String::from_utf8(row.try_get("kv_value")?)?),
+                    None => None,
+                }
+            }
+        };
+        DB::checkpoint_close_query()
+            .bind(sync_token)
+            .execute(&mut *txn)
+            .await?;
+        for query in DB::checkpoint_prune_queries() {
+            query.bind(CHECKPOINT_LIMIT).execute(&mut *txn).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the columns of a table, in the order they were created
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(crate) async fn table_columns<'c>(
+        txn: &mut Transaction<'c, DB>,
+        table: &str,
+    ) -> Result<Vec<String>> {
+        let mut rows = DB::table_columns_query().bind(table).fetch(txn);
+        let mut columns = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            columns.push(row.try_get("name")?);
+        }
+        Ok(columns)
+    }
+
+    /// Returns the stored sync checkpoints, newest first
+    ///
+    /// Every call to `save_changes` and every removal of a room creates a checkpoint, the newest
+    /// 100 are kept.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn sync_checkpoints(&self) -> Result<Vec<SyncCheckpoint>> {
+        let mut rows = DB::checkpoints_load_query().fetch(&*self.db);
+        let mut checkpoints = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let changes: i64 = row.try_get("changes")?;
+            checkpoints.push(SyncCheckpoint {
+                id: row.try_get("checkpoint_id")?,
+                sync_token: row.try_get("sync_token")?,
+                created_at: decode_timestamp(row.try_get("created_at")?),
+                changes: u64::try_from(changes).unwrap_or_default(),
+            });
+        }
+        Ok(checkpoints)
+    }
+
+    /// Rolls the state store back to a sync checkpoint
+    ///
+    /// All changes saved after the checkpoint are undone and the sync token of the checkpoint is
+    /// restored, so the next sync continues from there. The later checkpoints are removed. Clients
+    /// holding state in memory need to be restarted afterwards.
+    ///
+    /// # Errors
+    /// This function will return an error if the checkpoint does not exist or a query fails
+    pub async fn rollback_to_checkpoint(&self, checkpoint_id: i64) -> Result<()> {
+        let mut txn = self.db.begin().await?;
+        let row = DB::checkpoint_load_query()
+            .bind(checkpoint_id)
+            .fetch_optional(&mut txn)
+            .await?
+            .ok_or(SQLStoreError::UnknownCheckpoint(checkpoint_id))?;
+        let sync_token: Option<String> = row.try_get("sync_token")?;
+
+        for table in JOURNALED_TABLES {
+            let columns = Self::table_columns(&mut txn, table.name).await?;
+            let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+            for statement in DB::journal_undo_statements(table.name, table.key, &columns) {
+                sqlx::query(&statement)
+                    .bind(checkpoint_id)
+                    .execute(&mut txn)
+                    .await?;
+            }
+        }
+        for query in DB::checkpoint_discard_queries() {
+            query.bind(checkpoint_id).execute(&mut txn).await?;
+        }
+
+        match sync_token {
+            Some(sync_token) => Self::save_sync_token(&mut txn, &sync_token).await?,
+            None => {
+                DB::kv_remove_query()
+                    .bind(b"sync_token".as_slice())
+                    .execute(&mut txn)
+                    .await?;
+            }
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_base::{StateChanges, StateStore as _};
+    use ruma::{
+        events::{room::redaction::OriginalSyncRoomRedactionEvent, StateEventType},
+        room_id,
+        serde::Raw,
+        user_id,
+    };
+    use serde_json::{json, Value};
+
+    use crate::{SQLStoreError, StateStore};
+
+    /// Adds a state event to changes
+    fn add_event(
+        changes: &mut StateChanges,
+        event_type: StateEventType,
+        state_key: &str,
+        event_id: &str,
+        content: Value,
+    ) {
+        changes
+            .state
+            .entry(room_id!("!checkpoints:localhost").to_owned())
+            .or_default()
+            .entry(event_type.clone())
+            .or_default()
+            .insert(
+                state_key.to_owned(),
+                Raw::new(&json!({
+                    "content": content,
+                    "event_id": event_id,
+                    "origin_server_ts": 1,
+                    "sender": "@alice:localhost",
+                    "state_key": state_key,
+                    "type": event_type.to_string(),
+                }))
+                .unwrap()
+                .cast(),
+            );
+    }
+
+    /// Adds a member event to changes
+    fn add_member(changes: &mut StateChanges, user_id: &str) {
+        changes
+            .members
+            .entry(room_id!("!checkpoints:localhost").to_owned())
+            .or_default()
+            .insert(
+                user_id.try_into().unwrap(),
+                serde_json::from_value(json!({
+                    "content": { "membership": "join" },
+                    "event_id": format!("${}", user_id),
+                    "origin_server_ts": 1,
+                    "sender": user_id,
+                    "state_key": user_id,
+                    "type": "m.room.member",
+                }))
+                .unwrap(),
+            );
+    }
+
+    /// Returns the first batch of changes: a topic, a space child and a member
+    fn first_changes() -> StateChanges {
+        let mut changes = StateChanges::new("s1".to_owned());
+        add_event(
+            &mut changes,
+            StateEventType::RoomTopic,
+            "",
+            "$topic1",
+            json!({ "topic": "one" }),
+        );
+        add_event(
+            &mut changes,
+            StateEventType::SpaceChild,
+            "!child:localhost",
+            "$child",
+            json!({ "via": ["localhost"] }),
+        );
+        add_member(&mut changes, "@alice:localhost");
+        changes
+    }
+
+    /// Returns the second batch of changes: a new topic, a new member and a redacted space child
+    fn second_changes() -> StateChanges {
+        let mut changes = StateChanges::new("s2".to_owned());
+        add_event(
+            &mut changes,
+            StateEventType::RoomTopic,
+            "",
+            "$topic2",
+            json!({ "topic": "two" }),
+        );
+        add_member(&mut changes, "@bob:localhost");
+        let redaction: OriginalSyncRoomRedactionEvent = serde_json::from_value(json!({
+            "content": {},
+            "event_id": "$redaction",
+            "origin_server_ts": 2,
+            "redacts": "$child",
+            "sender": "@alice:localhost",
+            "type": "m.room.redaction",
+        }))
+        .unwrap();
+        changes.add_redaction(room_id!("!checkpoints:localhost"), redaction);
+        changes
+    }
+
+    /// Returns the topic of the test room
+    async fn topic<DB: crate::SupportedDatabase>(store: &StateStore<DB>) -> Value
+    where
+        StateStore<DB>: matrix_sdk_base::StateStore,
+    {
+        let event = store
+            .get_state_event(
+                room_id!("!checkpoints:localhost"),
+                StateEventType::RoomTopic,
+                "",
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        event.deserialize_as::<Value>().unwrap()["content"]["topic"].clone()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_rollback_to_checkpoint() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room = room_id!("!checkpoints:localhost");
+        store.save_state_changes(&first_changes()).await.unwrap();
+        store.save_state_changes(&second_changes()).await.unwrap();
+        store
+            .save_state_changes(&StateChanges::default())
+            .await
+            .unwrap();
+
+        let checkpoints = store.sync_checkpoints().await.unwrap();
+        let tokens: Vec<_> = checkpoints
+            .iter()
+            .map(|c| c.sync_token.as_deref())
+            .collect();
+        assert_eq!(tokens, vec![Some("s2"), Some("s2"), Some("s1")]);
+        assert_eq!(checkpoints[0].changes, 0);
+        assert!(checkpoints[1].changes > 0);
+        assert_eq!(topic(&store).await, "two");
+        assert!(store.space_children(room).await.unwrap().is_empty());
+
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(store.get_sync_token().await.unwrap().as_deref(), Some("s1"));
+        assert_eq!(topic(&store).await, "one");
+        assert_eq!(store.space_children(room).await.unwrap().len(), 1);
+        assert!(store
+            .get_member_event(room, user_id!("@bob:localhost"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_member_event(room, user_id!("@alice:localhost"))
+            .await
+            .unwrap()
+            .is_some());
+        let history = store
+            .state_history(room, StateEventType::RoomTopic, "")
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(
+            !store
+                .state_history(room, StateEventType::SpaceChild, "!child:localhost")
+                .await
+                .unwrap()[0]
+                .redacted
+        );
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 1);
+
+        // Syncing again after the rollback journals as before
+        store.save_state_changes(&second_changes()).await.unwrap();
+        assert_eq!(topic(&store).await, "two");
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(topic(&store).await, "one");
+
+        // Removing the room is journaled as well
+        store.remove_room(room).await.unwrap();
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 2);
+        assert!(store
+            .get_state_event(room, StateEventType::RoomTopic, "")
+            .await
+            .unwrap()
+            .is_none());
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(topic(&store).await, "one");
+        assert_eq!(
+            store
+                .state_history(room, StateEventType::RoomTopic, "")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(matches!(
+            store.rollback_to_checkpoint(checkpoints[0].id).await,
+            Err(SQLStoreError::UnknownCheckpoint(_))
+        ));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_rollback_to_checkpoint() {
+        let store = crate::schema::postgres_test_store("matrix_checkpoints_test").await;
+        let room = room_id!("!checkpoints:localhost");
+        store.save_state_changes(&first_changes()).await.unwrap();
+        store.save_state_changes(&second_changes()).await.unwrap();
+        store
+            .save_state_changes(&StateChanges::default())
+            .await
+            .unwrap();
+
+        let checkpoints = store.sync_checkpoints().await.unwrap();
+        let tokens: Vec<_> = checkpoints
+            .iter()
+            .map(|c| c.sync_token.as_deref())
+            .collect();
+        assert_eq!(tokens, vec![Some("s2"), Some("s2"), Some("s1")]);
+        assert_eq!(checkpoints[0].changes, 0);
+        assert!(checkpoints[1].changes > 0);
+        assert_eq!(topic(&store).await, "two");
+        assert!(store.space_children(room).await.unwrap().is_empty());
+
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(store.get_sync_token().await.unwrap().as_deref(), Some("s1"));
+        assert_eq!(topic(&store).await, "one");
+        assert_eq!(store.space_children(room).await.unwrap().len(), 1);
+        assert!(store
+            .get_member_event(room, user_id!("@bob:localhost"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_member_event(room, user_id!("@alice:localhost"))
+            .await
+            .unwrap()
+            .is_some());
+        let history = store
+            .state_history(room, StateEventType::RoomTopic, "")
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(
+            !store
+                .state_history(room, StateEventType::SpaceChild, "!child:localhost")
+                .await
+                .unwrap()[0]
+                .redacted
+        );
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 1);
+
+        // Syncing again after the rollback journals as before
+        store.save_state_changes(&second_changes()).await.unwrap();
+        assert_eq!(topic(&store).await, "two");
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(topic(&store).await, "one");
+
+        // Removing the room is journaled as well
+        store.remove_room(room).await.unwrap();
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 2);
+        assert!(store
+            .get_state_event(room, StateEventType::RoomTopic, "")
+            .await
+            .unwrap()
+            .is_none());
+        store
+            .rollback_to_checkpoint(checkpoints[2].id)
+            .await
+            .unwrap();
+        assert_eq!(topic(&store).await, "one");
+        assert_eq!(
+            store
+                .state_history(room, StateEventType::RoomTopic, "")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(matches!(
+            store.rollback_to_checkpoint(checkpoints[0].id).await,
+            Err(SQLStoreError::UnknownCheckpoint(_))
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_journal_columns() {
+        use sqlx::Row;
+
+        use super::JOURNALED_TABLES;
+
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        StateStore::new(&db).await.unwrap();
+        let triggers: Vec<(String, String)> = sqlx::query(
+            "SELECT tbl_name, sql FROM sqlite_master WHERE type = 'trigger' AND name LIKE '%journal%'",
+        )
+        .fetch_all(&*db)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("tbl_name"), row.get("sql")))
+        .collect();
+        assert_eq!(triggers.len(), JOURNALED_TABLES.len() * 3);
+
+        let mut txn = db.begin().await.unwrap();
+        for table in JOURNALED_TABLES {
+            let columns = StateStore::table_columns(&mut txn, table.name)
+                .await
+                .unwrap();
+            let table_triggers: Vec<&str> = triggers
+                .iter()
+                .filter(|(name, _)| name == table.name)
+                .map(|(_, sql)| sql.as_str())
+                .collect();
+            assert_eq!(table_triggers.len(), 3, "{}", table.name);
+            for sql in table_triggers {
+                let row = if sql.contains("AFTER INSERT") {
+                    "NEW"
+                } else {
+                    "OLD"
+                };
+                let row_key = row_object(table.key, row);
+                assert!(
+                    sql.contains(&format!("'{}', {},", table.name, row_key))
+                        && sql.contains(&format!("j.row_key = {}", row_key)),
+                    "{}",
+                    sql
+                );
+                if row == "OLD" {
+                    let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+                    assert!(sql.contains(&row_object(&columns, row)), "{}", sql);
+                }
+            }
+        }
+    }
+
+    /// Returns the `json_object` call of a trigger for the columns of a row
+    #[cfg(feature = "sqlite")]
+    fn row_object(columns: &[&str], row: &str) -> String {
+        let args: Vec<String> = columns
+            .iter()
+            .map(|column| format!("'{0}', {1}.{0}", column, row))
+            .collect();
+        format!("json_object({})", args.join(", "))
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_checkpoint_limit() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        for i in 0..=super::CHECKPOINT_LIMIT {
+            store
+                .save_state_changes(&StateChanges::new(format!("s{}", i)))
+                .await
+                .unwrap();
+        }
+        let checkpoints = store.sync_checkpoints().await.unwrap();
+        assert_eq!(checkpoints.len(), 100);
+        assert_eq!(checkpoints[0].sync_token.as_deref(), Some("s100"));
+        assert_eq!(checkpoints[99].sync_token.as_deref(), Some("s1"));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_checkpoint_limit() {
+        let store = crate::schema::postgres_test_store("matrix_checkpoint_limit_test").await;
+        for i in 0..=super::CHECKPOINT_LIMIT {
+            store
+                .save_state_changes(&StateChanges::new(format!("s{}", i)))
+                .await
+                .unwrap();
+        }
+        let checkpoints = store.sync_checkpoints().await.unwrap();
+        assert_eq!(checkpoints.len(), 100);
+        assert_eq!(checkpoints[0].sync_token.as_deref(), Some("s100"));
+        assert_eq!(checkpoints[99].sync_token.as_deref(), Some("s1"));
+    }
+}
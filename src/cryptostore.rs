@@ -3,7 +3,11 @@
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
@@ -63,11 +67,11 @@ pub(crate) struct CryptostoreData {
     /// Account info
     pub(crate) account: RwLock<Option<AccountInfo>>,
     /// In-Memory session store
-    pub(crate) sessions: SessionStore,
+    sessions: RwLock<Arc<SessionStore>>,
     /// In-Memory group session store
-    pub(crate) group_sessions: GroupSessionStore,
+    group_sessions: RwLock<Arc<GroupSessionStore>>,
     /// In-Memory device store
-    pub(crate) devices: DeviceStore,
+    devices: RwLock<Arc<DeviceStore>>,
     /// In-Memory tracked users cache
     pub(crate) tracked_users: Arc<DashSet<OwnedUserId>>,
     /// In-Memory key query cache
@@ -80,9 +84,9 @@ impl CryptostoreData {
         Self {
             cipher: Some(cipher),
             account: RwLock::new(None),
-            sessions: SessionStore::new(),
-            group_sessions: GroupSessionStore::new(),
-            devices: DeviceStore::new(),
+            sessions: RwLock::new(Arc::new(SessionStore::new())),
+            group_sessions: RwLock::new(Arc::new(GroupSessionStore::new())),
+            devices: RwLock::new(Arc::new(DeviceStore::new())),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
         }
@@ -93,14 +97,47 @@ impl CryptostoreData {
         Self {
             cipher: None,
             account: RwLock::new(None),
-            sessions: SessionStore::new(),
-            group_sessions: GroupSessionStore::new(),
-            devices: DeviceStore::new(),
+            sessions: RwLock::new(Arc::new(SessionStore::new())),
+            group_sessions: RwLock::new(Arc::new(GroupSessionStore::new())),
+            devices: RwLock::new(Arc::new(DeviceStore::new())),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
         }
     }
 
+    /// Clears the cached account, sessions, devices and tracked users
+    pub(crate) fn clear(&self) {
+        self.account.write().take();
+        *self.sessions.write() = Arc::new(SessionStore::new());
+        *self.group_sessions.write() = Arc::new(GroupSessionStore::new());
+        *self.devices.write() = Arc::new(DeviceStore::new());
+        self.tracked_users.clear();
+        self.users_for_key_query.clear();
+    }
+
+    /// Returns the in-memory session store
+    pub(crate) fn sessions(&self) -> Arc<SessionStore> {
+        Arc::clone(&self.sessions.read())
+    }
+
+    /// Returns the in-memory group session store
+    pub(crate) fn group_sessions(&self) -> Arc<GroupSessionStore> {
+        Arc::clone(&self.group_sessions.read())
+    }
+
+    /// Returns the in-memory device store
+    pub(crate) fn devices(&self) -> Arc<DeviceStore> {
+        Arc::clone(&self.devices.read())
+    }
+
+    /// Returns the user ID of the loaded account
+    pub(crate) fn own_user_id(&self) -> Option<Arc<UserId>> {
+        self.account
+            .read()
+            .as_ref()
+            .map(|account| account.user_id.clone())
+    }
+
     /// Encode a key
     pub(crate) fn encode_key<'a>(&self, table_name: &str, key: &'a [u8]) -> Cow<'a, [u8]> {
         self.cipher.as_ref().map_or_else(
@@ -140,6 +177,118 @@ impl CryptostoreData {
         }
     }
 }
+
+/// Unlock state of the cryptostore, shared with the idle lock timer
+#[derive(Debug)]
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) struct CryptostoreLock {
+    /// Cryptostore data, `None` while the cryptostore is locked
+    data: RwLock<Option<Arc<CryptostoreData>>>,
+    /// Idle time after which the cryptostore locks itself
+    idle_timeout: RwLock<Option<Duration>>,
+    /// Last time the cryptostore was accessed
+    last_access: parking_lot::Mutex<Instant>,
+    /// Incremented whenever the idle timeout changes, to stop outdated timers
+    timer_generation: AtomicU64,
+}
+
+impl CryptostoreLock {
+    /// Create a new locked cryptostore state
+    pub(crate) fn new() -> Self {
+        Self {
+            data: RwLock::new(None),
+            idle_timeout: RwLock::new(None),
+            last_access: parking_lot::Mutex::new(Instant::now()),
+            timer_generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cryptostore data and records the access
+    ///
+    /// Locks the cryptostore first if it has been idle for longer than the idle timeout, in case
+    /// the timer could not be started.
+    ///
+    /// # Errors
+    /// This function will return an error if the cryptostore is locked
+    pub(crate) fn access(&self) -> Result<Arc<CryptostoreData>> {
+        let mut last_access = self.last_access.lock();
+        if let Some(timeout) = *self.idle_timeout.read() {
+            if last_access.elapsed() >= timeout {
+                self.lock();
+            }
+        }
+        *last_access = Instant::now();
+        self.data
+            .read()
+            .clone()
+            .ok_or(SQLStoreError::DatabaseLocked)
+    }
+
+    /// Returns the cryptostore data without recording an access
+    pub(crate) fn peek(&self) -> Option<Arc<CryptostoreData>> {
+        self.data.read().clone()
+    }
+
+    /// Replaces the cryptostore data, unlocking the cryptostore
+    pub(crate) fn unlock(&self, data: CryptostoreData) {
+        let mut last_access = self.last_access.lock();
+        *self.data.write() = Some(Arc::new(data));
+        *last_access = Instant::now();
+    }
+
+    /// Locks the cryptostore, clearing the cached data
+    pub(crate) fn lock(&self) {
+        let data = self.data.write().take();
+        if let Some(data) = data {
+            data.clear();
+        }
+    }
+
+    /// Sets the idle timeout and starts a timer that locks the cryptostore when it expires
+    ///
+    /// Without a tokio runtime, the timeout is only checked when the cryptostore is accessed.
+    pub(crate) fn set_idle_timeout(self: &Arc<Self>, timeout: Option<Duration>) {
+        *self.idle_timeout.write() = timeout;
+        *self.last_access.lock() = Instant::now();
+        let generation = self.timer_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        if timeout.is_some() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(idle_lock_timer(Arc::downgrade(self), generation));
+            }
+        }
+    }
+}
+
+/// Shortest time between two checks of the idle lock timer
+const MIN_IDLE_LOCK_CHECK: Duration = Duration::from_millis(100);
+
+/// Locks the cryptostore once it has been idle for longer than the idle timeout
+///
+/// The timer stops when the store is dropped or the idle timeout changes.
+async fn idle_lock_timer(state: Weak<CryptostoreLock>, generation: u64) {
+    loop {
+        let remaining = match state.upgrade() {
+            Some(state) => {
+                if state.timer_generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let timeout = match *state.idle_timeout.read() {
+                    Some(timeout) => timeout,
+                    None => return,
+                };
+                let idle = state.last_access.lock().elapsed();
+                if idle >= timeout {
+                    state.lock();
+                    timeout
+                } else {
+                    timeout - idle
+                }
+            }
+            None => return,
+        };
+        tokio::time::sleep(remaining.max(MIN_IDLE_LOCK_CHECK)).await;
+    }
+}
 /// Account information
 #[derive(Clone, Debug)]
 #[allow(clippy::redundant_pub_crate)]
@@ -171,6 +320,7 @@ where
     Vec<u8>: SqlType<DB>,
     String: SqlType<DB>,
     bool: SqlType<DB>,
+    i64: SqlType<DB>,
     Vec<u8>: SqlType<DB>,
     Option<String>: SqlType<DB>,
     Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
@@ -366,7 +516,7 @@ where
             .bind(e2e.encode_value(&session.pickle().await)?)
             .execute(txn)
             .await?;
-        self.ensure_e2e()?.sessions.add(session).await;
+        self.ensure_e2e()?.sessions().add(session).await;
         Ok(())
     }
 
@@ -417,7 +567,7 @@ where
             .bind(e2e.encode_value(&session.pickle().await)?)
             .execute(txn)
             .await?;
-        self.ensure_e2e()?.group_sessions.add(session);
+        self.ensure_e2e()?.group_sessions().add(session);
         Ok(())
     }
 
@@ -436,10 +586,13 @@ where
             "cryptostore_inbound_group_session:room_id",
             session.room_id().as_bytes(),
         );
+        let pickle = session.pickle().await;
         DB::outbound_group_session_store_query()
             .bind(room_id.as_ref())
-            .bind(e2e.encode_value(&session.pickle().await)?)
-            .execute(txn)
+            .bind(e2e.encode_value(&pickle)?)
+            .execute(&mut *txn)
+            .await?;
+        self.save_room_key_shares(txn, session.session_id(), &pickle)
             .await?;
         Ok(())
     }
@@ -494,6 +647,16 @@ where
             "cryptostore_identity:user_id",
             identity.user_id().as_bytes(),
         );
+        let previous = DB::identity_fetch_query()
+            .bind(user_id.as_ref())
+            .fetch_optional(&mut *txn)
+            .await?;
+        if let Some(row) = previous {
+            let data: Vec<u8> = row.try_get("identity_data")?;
+            let previous = e2e.decode_value(&data)?;
+            self.record_identity_change(txn, &previous, &identity)
+                .await?;
+        }
         DB::identity_upsert_query()
             .bind(user_id.as_ref())
             .bind(e2e.encode_value(&identity)?)
@@ -524,7 +687,7 @@ where
             .bind(e2e.encode_value(&device)?)
             .execute(txn)
             .await?;
-        self.ensure_e2e()?.devices.add(device);
+        self.ensure_e2e()?.devices().add(device);
         Ok(())
     }
 
@@ -550,7 +713,7 @@ where
             .execute(txn)
             .await?;
         self.ensure_e2e()?
-            .devices
+            .devices()
             .remove(device.user_id(), device.device_id());
         Ok(())
     }
@@ -639,7 +802,7 @@ where
         sender_key: &str,
     ) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
         let e2e = self.ensure_e2e()?;
-        let sessions = &e2e.sessions;
+        let sessions = e2e.sessions();
         if let Some(v) = sessions.get(sender_key) {
             Ok(Some(v))
         } else {
@@ -669,50 +832,90 @@ where
         }
     }
 
+    /// Loads an incoming group session from the database, bypassing the session cache
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    async fn load_inbound_group_session<'e, E>(
+        &self,
+        executor: E,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>>
+    where
+        E: Executor<'e, Database = DB>,
+    {
+        let e2e = self.ensure_e2e()?;
+        let room_id = e2e.encode_key(
+            "cryptostore_inbound_group_session:room_id",
+            room_id.as_bytes(),
+        );
+        let sender_key = e2e.encode_key(
+            "cryptostore_inbound_group_session:sender_key",
+            sender_key.as_bytes(),
+        );
+        let session_id = e2e.encode_key(
+            "cryptostore_inbound_group_session:session_id",
+            session_id.as_bytes(),
+        );
+        let row = DB::inbound_group_session_fetch_query()
+            .bind(room_id.as_ref())
+            .bind(sender_key.as_ref())
+            .bind(session_id.as_ref())
+            .fetch_optional(executor)
+            .await?;
+        if let Some(row) = row {
+            let data: Vec<u8> = row.try_get("session_data")?;
+            let session = e2e.decode_value(&data)?;
+            Ok(Some(InboundGroupSession::from_pickle(session)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Retrieve an incoming group session as seen by a transaction
+    ///
+    /// Unlike [`Self::get_inbound_group_session`], this bypasses the session cache, so that the
+    /// result reflects the writes made earlier in the transaction.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub(crate) async fn get_inbound_group_session_txn<'c>(
+        &self,
+        txn: &mut Transaction<'c, DB>,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        self.load_inbound_group_session(txn, room_id, sender_key, session_id)
+            .await
+    }
+
     /// Retrieve an incoming group session
     ///
     /// # Errors
     /// This function will return an error if the database has not been unlocked,
     /// or if the query fails.
-    async fn get_inbound_group_session(
+    pub(crate) async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
         sender_key: &str,
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>> {
-        let e2e = self.ensure_e2e()?;
-        let sessions = &e2e.group_sessions;
+        let sessions = self.ensure_e2e()?.group_sessions();
         if let Some(v) = sessions.get(room_id, sender_key, session_id) {
-            Ok(Some(v))
-        } else {
-            let room_id = e2e.encode_key(
-                "cryptostore_inbound_group_session:room_id",
-                room_id.as_bytes(),
-            );
-            let sender_key = e2e.encode_key(
-                "cryptostore_inbound_group_session:sender_key",
-                sender_key.as_bytes(),
-            );
-            let session_id = e2e.encode_key(
-                "cryptostore_inbound_group_session:session_id",
-                session_id.as_bytes(),
-            );
-            let row = DB::inbound_group_session_fetch_query()
-                .bind(room_id.as_ref())
-                .bind(sender_key.as_ref())
-                .bind(session_id.as_ref())
-                .fetch_optional(&*self.db)
-                .await?;
-            if let Some(row) = row {
-                let data: Vec<u8> = row.try_get("session_data")?;
-                let session = e2e.decode_value(&data)?;
-                let session = InboundGroupSession::from_pickle(session)?;
-                sessions.add(session.clone());
-                Ok(Some(session))
-            } else {
-                Ok(None)
-            }
+            return Ok(Some(v));
+        }
+        let session = self
+            .load_inbound_group_session(&*self.db, room_id, sender_key, session_id)
+            .await?;
+        if let Some(session) = &session {
+            sessions.add(session.clone());
         }
+        Ok(session)
     }
 
     /// Fetch all inbound group sessions
@@ -722,11 +925,19 @@ where
     pub(crate) fn get_inbound_group_session_stream(
         &self,
     ) -> Result<impl TryStream<Ok = InboundGroupSession, Error = SQLStoreError> + '_> {
-        let e2e = self.ensure_e2e()?;
-        Ok(DB::inbound_group_sessions_fetch_query()
+        Ok(self.inbound_group_session_stream_for(self.ensure_e2e()?))
+    }
+
+    /// Fetch all inbound group sessions, decoding them with the given cryptostore data
+    fn inbound_group_session_stream_for(
+        &self,
+        e2e: Arc<CryptostoreData>,
+    ) -> impl TryStream<Ok = InboundGroupSession, Error = SQLStoreError> + '_ {
+        DB::inbound_group_sessions_fetch_query()
             .fetch(&*self.db)
             .map_err(Into::into)
             .and_then(move |row| {
+                let e2e = Arc::clone(&e2e);
                 let result = move || {
                     let data: Vec<u8> = row.try_get("session_data")?;
                     let session = e2e.decode_value(&data)?;
@@ -734,7 +945,7 @@ where
                     Ok(session)
                 };
                 futures::future::ready((result)())
-            }))
+            })
     }
 
     /// Fetch all inbound group sessions in a transaction
@@ -751,6 +962,7 @@ where
                 .fetch(txn)
                 .map_err(Into::into)
                 .and_then(move |row| {
+                    let e2e = Arc::clone(&e2e);
                     let result = move || {
                         let data: Vec<u8> = row.try_get("session_data")?;
                         let session = e2e.decode_value(&data)?;
@@ -783,7 +995,18 @@ where
     /// This function will return an error if the database has not been unlocked,
     /// or if the query fails.
     pub(crate) async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
-        self.get_inbound_group_session_stream()?
+        self.count_inbound_group_sessions(self.ensure_e2e()?).await
+    }
+
+    /// Counts the inbound group sessions, decoding them with the given cryptostore data
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails.
+    pub(crate) async fn count_inbound_group_sessions(
+        &self,
+        e2e: Arc<CryptostoreData>,
+    ) -> Result<RoomKeyCounts> {
+        self.inbound_group_session_stream_for(e2e)
             .try_fold(RoomKeyCounts::default(), |mut counts, session| async move {
                 counts.total += 1;
                 if session.backed_up() {
@@ -1120,6 +1343,7 @@ where
     Vec<u8>: SqlType<DB>,
     String: SqlType<DB>,
     bool: SqlType<DB>,
+    i64: SqlType<DB>,
     Vec<u8>: SqlType<DB>,
     Option<String>: SqlType<DB>,
     Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
@@ -1318,7 +1542,7 @@ mod postgres_integration_test {
         }
         let pass = passphrase.unwrap_or("default_test_password");
         let db = Arc::new(sqlx::PgPool::connect(&db_url).await?);
-        let mut store = StateStore::new(&db).await?;
+        let store = StateStore::new(&db).await?;
         store.unlock_with_passphrase(pass).await?;
         Ok(store)
     }
@@ -1362,16 +1586,17 @@ mod postgres_integration_test {
 #[allow(clippy::redundant_pub_crate)]
 #[cfg(all(test, feature = "sqlite"))]
 mod sqlite_integration_test {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Duration};
 
-    use crate::StateStore;
+    use crate::{SQLStoreError, StateStore};
 
     use matrix_sdk_crypto::{
         cryptostore_integration_tests, olm::OutboundGroupSession, EncryptionSettings,
+        ReadOnlyAccount, ReadOnlyDevice,
     };
     use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
-    use ruma::{device_id, room_id};
+    use ruma::{device_id, room_id, user_id};
     use sqlx::migrate::MigrateDatabase;
     use tempfile::{tempdir, TempDir};
     use vodozemac::olm::Account;
@@ -1390,7 +1615,7 @@ mod sqlite_integration_test {
         }
         let pass = passphrase.unwrap_or("default_test_password");
         let db = Arc::new(sqlx::SqlitePool::connect(&db_url).await?);
-        let mut store = StateStore::new(&db).await?;
+        let store = StateStore::new(&db).await?;
         store.unlock_with_passphrase(pass).await?;
         Ok(store)
     }
@@ -1426,5 +1651,63 @@ mod sqlite_integration_test {
         }
     }
 
+    #[async_test]
+    #[allow(clippy::unwrap_used)]
+    async fn cryptostore_lock() {
+        let store = get_store("cryptostore_lock", None).await;
+        let user = user_id!("@alice:localhost");
+        store.update_tracked_user(user, false).await.unwrap();
+        let account = ReadOnlyAccount::new(user, device_id!("ALICEDEVICE"));
+        let device = ReadOnlyDevice::from_account(&account).await;
+        let e2e = store.ensure_e2e().unwrap();
+        e2e.devices().add(device);
+
+        store.lock();
+        assert!(e2e.devices().get(user, device_id!("ALICEDEVICE")).is_none());
+        assert!(e2e.account.read().is_none());
+        drop(e2e);
+        assert!(matches!(
+            store.update_tracked_user(user, false).await,
+            Err(SQLStoreError::DatabaseLocked)
+        ));
+        assert!(store
+            .unlock_with_passphrase("wrong password")
+            .await
+            .is_err());
+        store
+            .unlock_with_passphrase("default_test_password")
+            .await
+            .unwrap();
+        assert!(store.ensure_e2e().unwrap().tracked_users.contains(user));
+
+        store.set_idle_lock_timeout(Some(Duration::ZERO));
+        assert!(matches!(
+            store.ensure_e2e(),
+            Err(SQLStoreError::DatabaseLocked)
+        ));
+        store.set_idle_lock_timeout(None);
+        store
+            .unlock_with_passphrase("default_test_password")
+            .await
+            .unwrap();
+        assert!(store.ensure_e2e().is_ok());
+    }
+
+    #[async_test]
+    #[allow(clippy::unwrap_used)]
+    async fn cryptostore_idle_lock() {
+        let store = get_store("cryptostore_idle_lock", None).await;
+        store.set_idle_lock_timeout(Some(Duration::from_millis(300)));
+        assert!(store.stats().await.unwrap().crypto.is_some());
+        for _ in 0..5 {
+            // Reading the statistics is not an access
+            store.stats().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        // The timer locked the store without a further access
+        assert!(store.cryptostore.peek().is_none());
+        assert!(store.stats().await.unwrap().crypto.is_none());
+    }
+
     cryptostore_integration_tests!();
 }
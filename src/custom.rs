@@ -0,0 +1,418 @@
+//! Custom values with removal, prefix scans, compare-and-swap and expiry
+
+use futures::{Stream, TryStreamExt};
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// Prefix of the keys of custom values in `statestore_kv`
+const CUSTOM_PREFIX: &[u8] = b"custom:";
+
+/// Returns the `statestore_kv` key of a custom value
+pub(crate) fn custom_key(key: &[u8]) -> Vec<u8> {
+    let mut custom_key = Vec::with_capacity(CUSTOM_PREFIX.len() + key.len());
+    custom_key.extend_from_slice(CUSTOM_PREFIX);
+    custom_key.extend_from_slice(key);
+    custom_key
+}
+
+/// Returns the smallest key that is larger than every key starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+/// Returns the current time as stored in the database
+fn now() -> i64 {
+    i64::from(MilliSecondsSinceUnixEpoch::now().0)
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<i64>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Puts arbitrary data into the custom store that is ignored after `expires_at`
+    ///
+    /// Expired values are deleted by [`StateStore::sweep_expired_custom_values`].
+    ///
+    /// # Errors
+    /// This function will return an error if the upsert cannot be performed
+    pub async fn set_custom_value_with_expiry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        DB::kv_upsert_expiring_query()
+            .bind(custom_key(key))
+            .bind(value)
+            .bind(i64::from(expires_at.0))
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a value from the custom store, returning it unless it had expired
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn remove_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let row = DB::kv_remove_query()
+            .bind(custom_key(key))
+            .fetch_optional(&*self.db)
+            .await?;
+        let row = if let Some(row) = row {
+            row
+        } else {
+            return Ok(None);
+        };
+        let expires_at: Option<i64> = row.try_get("expires_at")?;
+        if matches!(expires_at, Some(expires_at) if expires_at <= now()) {
+            return Ok(None);
+        }
+        Ok(Some(row.try_get("kv_value")?))
+    }
+
+    /// Returns the custom values whose keys start with `prefix`, ordered by key
+    ///
+    /// Expired values are skipped. The keys are returned without the prefix of the custom store.
+    pub fn custom_values_with_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let start = custom_key(prefix);
+        let end = prefix_end(&start);
+        DB::kv_range_load_query()
+            .bind(start)
+            .bind(end)
+            .bind(now())
+            .fetch(&*self.db)
+            .map_err(Into::into)
+            .and_then(|row| async move {
+                let mut key: Vec<u8> = row.try_get("kv_key")?;
+                key.drain(..CUSTOM_PREFIX.len());
+                Ok((key, row.try_get("kv_value")?))
+            })
+    }
+
+    /// Atomically replaces a custom value if it currently is `expected`
+    ///
+    /// `None` stands for a missing or expired value, so `expected: None` only creates values and
+    /// `new: None` removes the value. The new value expires at `expires_at`, if given. Returns
+    /// whether the value was replaced.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn compare_and_swap_custom_value(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+        expires_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<bool> {
+        let expires_at = expires_at.map(|expires_at| i64::from(expires_at.0));
+        let result = match (expected, new) {
+            (None, None) => return Ok(self.get_custom_value(key).await?.is_none()),
+            (None, Some(new)) => {
+                DB::kv_insert_absent_query()
+                    .bind(custom_key(key))
+                    .bind(new)
+                    .bind(expires_at)
+                    .bind(now())
+                    .execute(&*self.db)
+                    .await?
+            }
+            (Some(expected), Some(new)) => {
+                DB::kv_swap_query()
+                    .bind(custom_key(key))
+                    .bind(new)
+                    .bind(expires_at)
+                    .bind(expected)
+                    .bind(now())
+                    .execute(&*self.db)
+                    .await?
+            }
+            (Some(expected), None) => {
+                DB::kv_remove_if_query()
+                    .bind(custom_key(key))
+                    .bind(expected)
+                    .bind(now())
+                    .execute(&*self.db)
+                    .await?
+            }
+        };
+        Ok(DB::rows_affected(&result) > 0)
+    }
+
+    /// Deletes the expired values of the custom store, returning how many were deleted
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn sweep_expired_custom_values(&self) -> Result<u64> {
+        let result = DB::kv_sweep_query().bind(now()).execute(&*self.db).await?;
+        Ok(DB::rows_affected(&result))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use futures::TryStreamExt;
+    use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Returns a timestamp relative to now
+    fn from_now(offset: i64) -> MilliSecondsSinceUnixEpoch {
+        let now = i64::from(MilliSecondsSinceUnixEpoch::now().0);
+        MilliSecondsSinceUnixEpoch(UInt::try_from(now + offset).unwrap())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_custom_value_api() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.set_custom_value(b"a", b"1").await.unwrap();
+        store.set_custom_value(b"ab", b"2").await.unwrap();
+        store.set_custom_value(b"b", b"3").await.unwrap();
+        store
+            .set_custom_value_with_expiry(b"ac", b"4", from_now(-1000))
+            .await
+            .unwrap();
+        store
+            .set_custom_value_with_expiry(b"ad", b"5", from_now(60_000))
+            .await
+            .unwrap();
+
+        let values: Vec<_> = store
+            .custom_values_with_prefix(b"a")
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"ab".to_vec(), b"2".to_vec()),
+                (b"ad".to_vec(), b"5".to_vec()),
+            ]
+        );
+        assert_eq!(store.get_custom_value(b"ac").await.unwrap(), None);
+
+        assert_eq!(
+            store.remove_custom_value(b"ab").await.unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(store.remove_custom_value(b"ab").await.unwrap(), None);
+        assert_eq!(store.remove_custom_value(b"ac").await.unwrap(), None);
+
+        // Compare and swap
+        assert!(store
+            .compare_and_swap_custom_value(b"c", None, Some(b"x"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_swap_custom_value(b"c", None, Some(b"y"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_swap_custom_value(b"c", Some(b"y"), Some(b"z"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", Some(b"x"), Some(b"y"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", Some(b"y"), None, None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", None, None, None)
+            .await
+            .unwrap());
+        store
+            .set_custom_value_with_expiry(b"d", b"old", from_now(-1000))
+            .await
+            .unwrap();
+        assert!(!store
+            .compare_and_swap_custom_value(b"d", Some(b"old"), Some(b"new"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"d", None, Some(b"new"), Some(from_now(-1000)))
+            .await
+            .unwrap());
+        assert_eq!(store.get_custom_value(b"d").await.unwrap(), None);
+
+        // Setting a value without expiry keeps it
+        store
+            .set_custom_value_with_expiry(b"e", b"old", from_now(-1000))
+            .await
+            .unwrap();
+        store.set_custom_value(b"e", b"new").await.unwrap();
+        assert_eq!(
+            store.get_custom_value(b"e").await.unwrap(),
+            Some(b"new".to_vec())
+        );
+
+        assert_eq!(store.sweep_expired_custom_values().await.unwrap(), 1);
+        assert_eq!(store.sweep_expired_custom_values().await.unwrap(), 0);
+        assert_eq!(
+            store.get_custom_value(b"ad").await.unwrap(),
+            Some(b"5".to_vec())
+        );
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_custom_value_api() {
+        let store = crate::schema::postgres_test_store("matrix_custom_test").await;
+        store.set_custom_value(b"a", b"1").await.unwrap();
+        store.set_custom_value(b"ab", b"2").await.unwrap();
+        store.set_custom_value(b"b", b"3").await.unwrap();
+        store
+            .set_custom_value_with_expiry(b"ac", b"4", from_now(-1000))
+            .await
+            .unwrap();
+        store
+            .set_custom_value_with_expiry(b"ad", b"5", from_now(60_000))
+            .await
+            .unwrap();
+
+        let values: Vec<_> = store
+            .custom_values_with_prefix(b"a")
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"ab".to_vec(), b"2".to_vec()),
+                (b"ad".to_vec(), b"5".to_vec()),
+            ]
+        );
+        assert_eq!(store.get_custom_value(b"ac").await.unwrap(), None);
+
+        assert_eq!(
+            store.remove_custom_value(b"ab").await.unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(store.remove_custom_value(b"ab").await.unwrap(), None);
+        assert_eq!(store.remove_custom_value(b"ac").await.unwrap(), None);
+
+        // Compare and swap
+        assert!(store
+            .compare_and_swap_custom_value(b"c", None, Some(b"x"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_swap_custom_value(b"c", None, Some(b"y"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_swap_custom_value(b"c", Some(b"y"), Some(b"z"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", Some(b"x"), Some(b"y"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", Some(b"y"), None, None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"c", None, None, None)
+            .await
+            .unwrap());
+        store
+            .set_custom_value_with_expiry(b"d", b"old", from_now(-1000))
+            .await
+            .unwrap();
+        assert!(!store
+            .compare_and_swap_custom_value(b"d", Some(b"old"), Some(b"new"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap_custom_value(b"d", None, Some(b"new"), Some(from_now(-1000)))
+            .await
+            .unwrap());
+        assert_eq!(store.get_custom_value(b"d").await.unwrap(), None);
+
+        // Setting a value without expiry keeps it
+        store
+            .set_custom_value_with_expiry(b"e", b"old", from_now(-1000))
+            .await
+            .unwrap();
+        store.set_custom_value(b"e", b"new").await.unwrap();
+        assert_eq!(
+            store.get_custom_value(b"e").await.unwrap(),
+            Some(b"new".to_vec())
+        );
+
+        assert_eq!(store.sweep_expired_custom_values().await.unwrap(), 1);
+        assert_eq!(store.sweep_expired_custom_values().await.unwrap(), 0);
+        assert_eq!(
+            store.get_custom_value(b"ad").await.unwrap(),
+            Some(b"5".to_vec())
+        );
+    }
+}
@@ -5,6 +5,56 @@ use sqlx::{
 };
 
 use self::private::Sealed;
+use crate::{MemberSort, RoomSort};
+
+/// The select list and filters shared by the room listing queries
+macro_rules! room_list_select {
+    () => {
+        r#"
+            SELECT room_id, room_info, name_sort, counts_updated_at FROM statestore_rooms
+            WHERE ($1 IS NULL OR room_state = $1)
+                AND ($2 IS NULL OR is_encrypted = $2)
+                AND ($3 IS NULL OR is_direct = $3)
+                AND ($4 IS NULL OR room_id IN (SELECT room_id FROM statestore_room_tags WHERE tag = $4))
+                AND ($5 IS NULL OR name_sort LIKE $5 ESCAPE '\')
+                AND ($6 IS NULL OR room_id IN (
+                    SELECT child_id FROM statestore_space_edges WHERE parent_id = $6
+                ))
+        "#
+    };
+}
+
+/// The members matching a search, with their power level and last activity
+macro_rules! member_search_select {
+    () => {
+        r#"
+            SELECT m.user_id, m.displayname, m.joined,
+                COALESCE(p.power_level, d.power_level, 0) AS power_level,
+                COALESCE((
+                    SELECT MAX(r.receipt_ts) FROM statestore_receipts r
+                    WHERE r.user_id = m.user_id AND r.room_id = m.room_id
+                ), 0) AS last_active
+            FROM statestore_members m
+            LEFT JOIN statestore_power_levels p ON p.room_id = m.room_id AND p.user_id = m.user_id
+            LEFT JOIN statestore_power_levels d ON d.room_id = m.room_id AND d.user_id = ''
+            WHERE m.room_id = $1 AND m.member_event IS NOT NULL
+                AND ($2 IS NULL OR m.joined = $2)
+                AND (m.search_name LIKE $3 ESCAPE '\' OR m.search_user_id LIKE $3 ESCAPE '\')
+        "#
+    };
+}
+
+/// Escapes the `LIKE` wildcards in `value` with `\`
+pub(crate) fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
 
 /// Private module for the [`Sealed`] trait.
 mod private {
@@ -48,6 +98,32 @@ pub trait SupportedDatabase: Database + Sealed {
     /// Returns the migrator for the current database type
     fn get_migrator() -> &'static Migrator;
 
+    /// Returns the number of rows changed by a query
+    fn rows_affected(result: &<Self as Database>::QueryResult) -> u64;
+
+    /// Returns a query that yields a row if the migrations table exists
+    fn migrations_table_exists_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT 1 FROM information_schema.tables
+                WHERE table_schema = current_schema() AND table_name = '_sqlx_migrations'
+            "#,
+        )
+    }
+
+    /// Returns a statement that yields a row if a table or `table.column` holds any data
+    #[must_use]
+    fn target_has_data_statement(target: &str) -> String {
+        let condition = match target.split_once('.') {
+            Some((table, column)) => {
+                format!("SELECT 1 FROM {} WHERE {} IS NOT NULL", table, column)
+            }
+            None => format!("SELECT 1 FROM {}", target),
+        };
+        format!("SELECT 1 WHERE EXISTS({})", condition)
+    }
+
     /// Returns a query for upserting into the `statestore_kv` table
     ///
     /// # Arguments
@@ -58,7 +134,7 @@ pub trait SupportedDatabase: Database + Sealed {
             r#"
                 INSERT INTO statestore_kv (kv_key, kv_value)
                 VALUES ($1, $2)
-                ON CONFLICT (kv_key) DO UPDATE SET kv_value = $2
+                ON CONFLICT (kv_key) DO UPDATE SET kv_value = $2, expires_at = NULL
             "#,
         )
     }
@@ -75,6 +151,130 @@ pub trait SupportedDatabase: Database + Sealed {
         )
     }
 
+    /// Upserts a value into the `statestore_kv` table that expires
+    ///
+    /// # Arguments
+    /// * `$1` - The key to insert
+    /// * `$2` - The value to insert
+    /// * `$3` - The expiration time
+    fn kv_upsert_expiring_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_kv (kv_key, kv_value, expires_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (kv_key) DO UPDATE SET kv_value = $2, expires_at = $3
+            "#,
+        )
+    }
+
+    /// Loads a value from the `statestore_kv` table unless it has expired
+    ///
+    /// # Arguments
+    /// * `$1` - The key to load
+    /// * `$2` - The current time
+    fn kv_load_unexpired_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT kv_value FROM statestore_kv
+                WHERE kv_key = $1 AND (expires_at IS NULL OR expires_at > $2)
+            "#,
+        )
+    }
+
+    /// Deletes a value from the `statestore_kv` table, returning it
+    ///
+    /// # Arguments
+    /// * `$1` - The key to delete
+    fn kv_remove_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM statestore_kv WHERE kv_key = $1
+                RETURNING kv_value, expires_at
+            "#,
+        )
+    }
+
+    /// Loads the values of a key range from the `statestore_kv` table that have not expired
+    ///
+    /// # Arguments
+    /// * `$1` - The first key of the range
+    /// * `$2` - The first key after the range
+    /// * `$3` - The current time
+    fn kv_range_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT kv_key, kv_value FROM statestore_kv
+                WHERE kv_key >= $1 AND kv_key < $2 AND (expires_at IS NULL OR expires_at > $3)
+                ORDER BY kv_key
+            "#,
+        )
+    }
+
+    /// Inserts a value into the `statestore_kv` table if the key is missing or has expired
+    ///
+    /// # Arguments
+    /// * `$1` - The key to insert
+    /// * `$2` - The value to insert
+    /// * `$3` - The expiration time, or `NULL`
+    /// * `$4` - The current time
+    fn kv_insert_absent_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_kv (kv_key, kv_value, expires_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (kv_key) DO UPDATE SET kv_value = $2, expires_at = $3
+                WHERE statestore_kv.expires_at IS NOT NULL AND statestore_kv.expires_at <= $4
+            "#,
+        )
+    }
+
+    /// Replaces a value in the `statestore_kv` table if it has the expected value and has not
+    /// expired
+    ///
+    /// # Arguments
+    /// * `$1` - The key to update
+    /// * `$2` - The new value
+    /// * `$3` - The new expiration time, or `NULL`
+    /// * `$4` - The expected value
+    /// * `$5` - The current time
+    fn kv_swap_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                UPDATE statestore_kv SET kv_value = $2, expires_at = $3
+                WHERE kv_key = $1 AND kv_value = $4 AND (expires_at IS NULL OR expires_at > $5)
+            "#,
+        )
+    }
+
+    /// Deletes a value from the `statestore_kv` table if it has the expected value and has not
+    /// expired
+    ///
+    /// # Arguments
+    /// * `$1` - The key to delete
+    /// * `$2` - The expected value
+    /// * `$3` - The current time
+    fn kv_remove_if_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM statestore_kv
+                WHERE kv_key = $1 AND kv_value = $2 AND (expires_at IS NULL OR expires_at > $3)
+            "#,
+        )
+    }
+
+    /// Deletes the expired values from the `statestore_kv` table
+    ///
+    /// # Arguments
+    /// * `$1` - The current time
+    fn kv_sweep_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM statestore_kv
+                WHERE expires_at IS NOT NULL AND expires_at <= $1
+            "#,
+        )
+    }
+
     /// Returns a query for loading from the `statestore_media` table
     ///
     /// # Arguments
@@ -106,6 +306,9 @@ pub trait SupportedDatabase: Database + Sealed {
     }
 
     /// Returns the second query for storing into the `statestore_media` table
+    ///
+    /// # Arguments
+    /// * `$1` - The maximum number of media entries to keep
     fn media_insert_query_2<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
@@ -113,7 +316,7 @@ pub trait SupportedDatabase: Database + Sealed {
                 WHERE media_url NOT IN
                     (SELECT media_url FROM statestore_media
                      ORDER BY last_access DESC
-                     LIMIT 100)
+                     LIMIT $1)
             "#,
         )
     }
@@ -143,9 +346,180 @@ pub trait SupportedDatabase: Database + Sealed {
             sqlx::query("DELETE FROM statestore_members WHERE room_id = $1"),
             sqlx::query("DELETE FROM statestore_state WHERE room_id = $1"),
             sqlx::query("DELETE FROM statestore_receipts WHERE room_id = $1"),
+            sqlx::query("DELETE FROM statestore_state_history WHERE room_id = $1"),
+            sqlx::query("DELETE FROM statestore_membership_history WHERE room_id = $1"),
+            sqlx::query("DELETE FROM statestore_room_tags WHERE room_id = $1"),
+            sqlx::query("DELETE FROM statestore_power_levels WHERE room_id = $1"),
+            sqlx::query("DELETE FROM statestore_space_edges WHERE room_id = $1"),
         ]
     }
 
+    /// Returns queries counting the rows of each table that belong to no stored room
+    #[must_use]
+    fn orphan_count_queries<'q>() -> Vec<(
+        &'static str,
+        Query<'q, Self, <Self as HasArguments<'q>>::Arguments>,
+    )> {
+        vec![
+            (
+                "statestore_accountdata",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_accountdata
+                        WHERE room_id <> '' AND room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_members",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_members
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_state",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_state
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_receipts",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_receipts
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_state_history",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_state_history
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_membership_history",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_membership_history
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_room_tags",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_room_tags
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_power_levels",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_power_levels
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+            (
+                "statestore_space_edges",
+                sqlx::query(
+                    r#"
+                        SELECT COUNT(*) AS count FROM statestore_space_edges
+                        WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                    "#,
+                ),
+            ),
+        ]
+    }
+
+    /// Returns queries deleting the rows of each table that belong to no stored room
+    #[must_use]
+    fn orphan_delete_queries<'q>() -> Vec<Query<'q, Self, <Self as HasArguments<'q>>::Arguments>> {
+        vec![
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_accountdata
+                    WHERE room_id <> '' AND room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_members
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_state
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_receipts
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_state_history
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_membership_history
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_room_tags
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_power_levels
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_space_edges
+                    WHERE room_id NOT IN (SELECT room_id FROM statestore_rooms)
+                "#,
+            ),
+        ]
+    }
+
+    /// Returns a query that reclaims unused space and updates the planner statistics
+    fn vacuum_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("VACUUM ANALYZE")
+    }
+
+    /// Returns a query that checks the database file for corruption, if the database supports it
+    ///
+    /// The query yields one row per problem, or a single `ok` row, in the `integrity_check`
+    /// column.
+    fn integrity_check_query<'q>() -> Option<Query<'q, Self, <Self as HasArguments<'q>>::Arguments>>
+    {
+        None
+    }
+
     /// Upserts account data
     ///
     /// # Arguments
@@ -219,9 +593,184 @@ pub trait SupportedDatabase: Database + Sealed {
         sqlx::query(
             r#"
                 INSERT INTO statestore_members
-                    (room_id, user_id, is_partial, member_event, displayname, joined)
-                VALUES ($1, $2, $3, $4, $5, $6)
-                ON CONFLICT(room_id, user_id) DO UPDATE SET is_partial = $3, member_event = $4, displayname = $5, joined = $6
+                    (room_id, user_id, is_partial, member_event, displayname, joined, search_name, search_user_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT(room_id, user_id) DO UPDATE SET is_partial = $3, member_event = $4, displayname = $5, joined = $6, search_name = $7, search_user_id = $8
+            "#,
+        )
+    }
+
+    /// Deletes the power levels of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    fn power_levels_remove_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("DELETE FROM statestore_power_levels WHERE room_id = $1")
+    }
+
+    /// Inserts the power level of a user in a room
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The user ID, or an empty string for the default power level
+    /// * `$3` - The power level
+    fn power_level_insert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_power_levels (room_id, user_id, power_level)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(room_id, user_id) DO UPDATE SET power_level = $3
+            "#,
+        )
+    }
+
+    /// Loads the display name and search columns of every member
+    fn member_search_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT room_id, user_id, displayname, search_name, search_user_id
+                FROM statestore_members
+            "#,
+        )
+    }
+
+    /// Updates the search columns of a member
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The user ID
+    /// * `$3` - The normalized display name
+    /// * `$4` - The normalized user ID
+    fn member_search_update_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                UPDATE statestore_members SET search_name = $3, search_user_id = $4
+                WHERE room_id = $1 AND user_id = $2
+            "#,
+        )
+    }
+
+    /// Searches the members of a room, in the given order
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - Whether the member has joined or was invited, or `NULL` for both
+    /// * `$3` - A `LIKE` prefix pattern for the normalized display name or user ID, escaped with
+    ///   `\`, or `%` for every member
+    /// * `$4` - The user ID of the last member of the previous page
+    /// * `$5` - The sort key of the last member of the previous page
+    /// * `$6` - The maximum number of members
+    fn member_search_query<'q>(
+        sort: MemberSort,
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(match sort {
+            MemberSort::PowerLevel => concat!(
+                "SELECT * FROM (",
+                member_search_select!(),
+                ") AS members ",
+                "WHERE $4 IS NULL OR power_level < $5 OR (power_level = $5 AND user_id > $4) ",
+                "ORDER BY power_level DESC, user_id LIMIT $6"
+            ),
+            MemberSort::RecentActivity => concat!(
+                "SELECT * FROM (",
+                member_search_select!(),
+                ") AS members ",
+                "WHERE $4 IS NULL OR last_active < $5 OR (last_active = $5 AND user_id > $4) ",
+                "ORDER BY last_active DESC, user_id LIMIT $6"
+            ),
+        })
+    }
+
+    /// Appends a membership change to the membership history, if it differs from the previous one
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The user ID
+    /// * `$3` - The event ID
+    /// * `$4` - The sender of the event
+    /// * `$5` - The membership state
+    /// * `$6` - The timestamp of the event
+    fn membership_history_insert_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_membership_history
+                    (room_id, user_id, event_id, sender, membership, origin_server_ts)
+                SELECT $1, $2, $3, $4, $5, $6
+                WHERE COALESCE((
+                    SELECT membership FROM statestore_membership_history
+                    WHERE room_id = $1 AND user_id = $2
+                    ORDER BY history_id DESC
+                    LIMIT 1
+                ), '') <> $5
+                ON CONFLICT(room_id, event_id) DO NOTHING
+            "#,
+        )
+    }
+
+    /// Retrieves the membership history of a user in a room, oldest first
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The user ID
+    fn membership_history_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT event_id, sender, membership, origin_server_ts
+                FROM statestore_membership_history
+                WHERE room_id = $1 AND user_id = $2
+                ORDER BY history_id ASC
+            "#,
+        )
+    }
+
+    /// Retrieves the rooms a user is joined to
+    ///
+    /// # Arguments
+    /// * `$1` - The user ID
+    fn joined_rooms_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT room_id FROM statestore_members
+                WHERE user_id = $1 AND joined
+                ORDER BY room_id
+            "#,
+        )
+    }
+
+    /// Retrieves the rooms two users are both joined to
+    ///
+    /// # Arguments
+    /// * `$1` - The first user ID
+    /// * `$2` - The second user ID
+    fn shared_rooms_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT a.room_id FROM statestore_members a
+                JOIN statestore_members b ON a.room_id = b.room_id
+                WHERE a.user_id = $1 AND a.joined AND b.user_id = $2 AND b.joined
+                ORDER BY a.room_id
+            "#,
+        )
+    }
+
+    /// Retrieves the joined members of a room that joined since a timestamp
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The timestamp
+    fn recently_joined_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT m.user_id, MAX(h.origin_server_ts) AS joined_at
+                FROM statestore_members m
+                JOIN statestore_membership_history h
+                    ON h.room_id = m.room_id AND h.user_id = m.user_id
+                WHERE m.room_id = $1 AND m.joined AND h.membership = 'join'
+                GROUP BY m.user_id
+                HAVING MAX(h.origin_server_ts) >= $2
+                ORDER BY joined_at DESC
             "#,
         )
     }
@@ -278,23 +827,472 @@ pub trait SupportedDatabase: Database + Sealed {
         )
     }
 
+    /// Upserts a space edge declared by a state event
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID of the state event
+    /// * `$2` - The event type, `m.space.child` or `m.space.parent`
+    /// * `$3` - The state key
+    /// * `$4` - The event ID
+    /// * `$5` - The room ID of the space
+    /// * `$6` - The room ID of the child
+    fn space_edge_upsert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_space_edges
+                    (room_id, event_type, state_key, event_id, parent_id, child_id)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT(room_id, event_type, state_key) DO UPDATE SET event_id = $4, parent_id = $5, child_id = $6
+            "#,
+        )
+    }
+
+    /// Deletes the space edge declared by a state event
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID of the state event
+    /// * `$2` - The event type
+    /// * `$3` - The state key
+    fn space_edge_remove_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM statestore_space_edges
+                WHERE room_id = $1 AND event_type = $2 AND state_key = $3
+            "#,
+        )
+    }
+
+    /// Deletes the space edge declared by a redacted state event
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The state event ID
+    fn space_edge_redact_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM statestore_space_edges
+                WHERE room_id = $1 AND event_id = $2
+            "#,
+        )
+    }
+
+    /// Retrieves the `m.space.child` events of a space
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID of the space
+    fn space_children_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT e.child_id, s.state_event
+                FROM statestore_space_edges e
+                JOIN statestore_state s
+                    ON s.room_id = e.room_id AND s.event_type = e.event_type AND s.state_key = e.state_key
+                WHERE e.parent_id = $1 AND e.event_type = 'm.space.child'
+            "#,
+        )
+    }
+
+    /// Retrieves the `m.space.parent` events of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    fn space_parents_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT e.parent_id, s.state_event
+                FROM statestore_space_edges e
+                JOIN statestore_state s
+                    ON s.room_id = e.room_id AND s.event_type = e.event_type AND s.state_key = e.state_key
+                WHERE e.child_id = $1 AND e.event_type = 'm.space.parent'
+            "#,
+        )
+    }
+
+    /// Retrieves the `m.space.child` events of a space and of its subspaces, recursively
+    ///
+    /// Each path stops before it would revisit a room, so cycles are followed only once.
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID of the space
+    /// * `$2` - The maximum depth, where the children of the space have depth 1
+    fn space_tree_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                WITH RECURSIVE tree (parent_id, child_id, room_id, state_key, depth, path) AS (
+                    SELECT parent_id, child_id, room_id, state_key, CAST(1 AS BIGINT),
+                        ' ' || parent_id || ' ' || child_id || ' '
+                    FROM statestore_space_edges
+                    WHERE parent_id = $1 AND event_type = 'm.space.child'
+                    UNION ALL
+                    SELECT e.parent_id, e.child_id, e.room_id, e.state_key, t.depth + 1,
+                        t.path || e.child_id || ' '
+                    FROM tree t
+                    JOIN statestore_space_edges e
+                        ON e.parent_id = t.child_id AND e.event_type = 'm.space.child'
+                    WHERE t.depth < $2 AND strpos(t.path, ' ' || e.child_id || ' ') = 0
+                )
+                SELECT t.parent_id, t.child_id, t.depth, s.state_event
+                FROM tree t
+                JOIN statestore_state s
+                    ON s.room_id = t.room_id AND s.event_type = 'm.space.child' AND s.state_key = t.state_key
+            "#,
+        )
+    }
+
+    /// Appends a state event to the state history
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The event type
+    /// * `$3` - The state key
+    /// * `$4` - The event ID
+    /// * `$5` - The sender of the event
+    /// * `$6` - The timestamp of the event
+    /// * `$7` - The state event
+    fn state_history_insert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_state_history
+                    (room_id, event_type, state_key, event_id, sender, origin_server_ts, state_event)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT(room_id, event_id) DO NOTHING
+            "#,
+        )
+    }
+
+    /// Retrieves a state event from the state history
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The state event ID
+    fn state_history_event_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT state_event FROM statestore_state_history
+                WHERE room_id = $1 AND event_id = $2
+            "#,
+        )
+    }
+
+    /// Replaces a state event in the state history with its redacted form
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The state event ID
+    /// * `$3` - The redacted state event
+    fn state_history_redact_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                UPDATE statestore_state_history SET redacted = TRUE, state_event = $3
+                WHERE room_id = $1 AND event_id = $2
+            "#,
+        )
+    }
+
+    /// Retrieves the history of a state key, oldest first
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The event type
+    /// * `$3` - The state key
+    fn state_history_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT event_id, sender, origin_server_ts, redacted, state_event
+                FROM statestore_state_history
+                WHERE room_id = $1 AND event_type = $2 AND state_key = $3
+                ORDER BY history_id ASC
+            "#,
+        )
+    }
+
+    /// Retrieves the position of a state event in the state history
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The event ID
+    fn state_history_position_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT history_id FROM statestore_state_history
+                WHERE room_id = $1 AND event_id = $2
+            "#,
+        )
+    }
+
+    /// Retrieves the room state as of a position in the state history
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The position in the state history
+    fn state_at_position_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT h.event_type, h.state_key, h.state_event
+                FROM statestore_state_history h
+                WHERE h.room_id = $1 AND h.history_id = (
+                    SELECT MAX(h2.history_id) FROM statestore_state_history h2
+                    WHERE h2.room_id = h.room_id AND h2.event_type = h.event_type
+                        AND h2.state_key = h.state_key AND h2.history_id <= $2
+                )
+            "#,
+        )
+    }
+
+    /// Retrieves the room state as of a timestamp
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The timestamp
+    fn state_at_timestamp_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT h.event_type, h.state_key, h.state_event
+                FROM statestore_state_history h
+                WHERE h.room_id = $1 AND h.history_id = (
+                    SELECT h2.history_id FROM statestore_state_history h2
+                    WHERE h2.room_id = h.room_id AND h2.event_type = h.event_type
+                        AND h2.state_key = h.state_key AND h2.origin_server_ts <= $2
+                    ORDER BY h2.origin_server_ts DESC, h2.history_id DESC
+                    LIMIT 1
+                )
+            "#,
+        )
+    }
+
+    /// Opens a new sync checkpoint, changes are journaled until it is closed
+    ///
+    /// # Arguments
+    /// * `$1` - The current time
+    fn checkpoint_open_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_checkpoints (created_at, is_open)
+                VALUES ($1, TRUE)
+            "#,
+        )
+    }
+
+    /// Closes the open sync checkpoint
+    ///
+    /// # Arguments
+    /// * `$1` - The sync token stored after the changes were saved
+    fn checkpoint_close_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                UPDATE statestore_checkpoints SET sync_token = $1, is_open = FALSE
+                WHERE is_open
+            "#,
+        )
+    }
+
+    /// Deletes all but the newest sync checkpoints and their journal
+    ///
+    /// # Arguments
+    /// * `$1` - The number of checkpoints to keep
+    #[must_use]
+    fn checkpoint_prune_queries<'q>() -> Vec<Query<'q, Self, <Self as HasArguments<'q>>::Arguments>>
+    {
+        vec![
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_journal WHERE checkpoint_id <= (
+                        SELECT checkpoint_id FROM statestore_checkpoints
+                        ORDER BY checkpoint_id DESC LIMIT 1 OFFSET $1
+                    )
+                "#,
+            ),
+            sqlx::query(
+                r#"
+                    DELETE FROM statestore_checkpoints WHERE checkpoint_id <= (
+                        SELECT checkpoint_id FROM statestore_checkpoints
+                        ORDER BY checkpoint_id DESC LIMIT 1 OFFSET $1
+                    )
+                "#,
+            ),
+        ]
+    }
+
+    /// Deletes the sync checkpoints after a checkpoint and their journal
+    ///
+    /// # Arguments
+    /// * `$1` - The checkpoint ID
+    #[must_use]
+    fn checkpoint_discard_queries<'q>(
+    ) -> Vec<Query<'q, Self, <Self as HasArguments<'q>>::Arguments>> {
+        vec![
+            sqlx::query("DELETE FROM statestore_journal WHERE checkpoint_id > $1"),
+            sqlx::query("DELETE FROM statestore_checkpoints WHERE checkpoint_id > $1"),
+        ]
+    }
+
+    /// Retrieves the sync token of a checkpoint
+    ///
+    /// # Arguments
+    /// * `$1` - The checkpoint ID
+    fn checkpoint_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("SELECT sync_token FROM statestore_checkpoints WHERE checkpoint_id = $1")
+    }
+
+    /// Retrieves all sync checkpoints with the number of rows they changed, newest first
+    fn checkpoints_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT c.checkpoint_id, c.sync_token, c.created_at, COUNT(j.row_key) AS changes
+                FROM statestore_checkpoints c
+                LEFT JOIN statestore_journal j ON j.checkpoint_id = c.checkpoint_id
+                GROUP BY c.checkpoint_id, c.sync_token, c.created_at
+                ORDER BY c.checkpoint_id DESC
+            "#,
+        )
+    }
+
+    /// Returns the columns of a table, in the order they were created
+    ///
+    /// # Arguments
+    /// * `$1` - The table name
+    fn table_columns_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT column_name::TEXT AS name FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = $1
+                ORDER BY ordinal_position
+            "#,
+        )
+    }
+
+    /// Returns the statements that restore the rows of a table changed after a checkpoint
+    ///
+    /// The first statement deletes the changed rows by their primary key, the second one
+    /// reinserts them as they were before the first change after the checkpoint. `key` are the
+    /// primary key columns of the table and `columns` all of its columns.
+    ///
+    /// # Arguments
+    /// * `$1` - The checkpoint ID
+    #[must_use]
+    fn journal_undo_statements(table: &str, key: &[&str], _columns: &[&str]) -> [String; 2] {
+        let row_key = key
+            .iter()
+            .map(|column| format!("k.{}", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        [
+            format!(
+                r#"
+                    DELETE FROM {0} WHERE ({1}) IN (
+                        SELECT {2}
+                        FROM statestore_journal j, jsonb_populate_record(NULL::{0}, j.row_key::JSONB) k
+                        WHERE j.table_name = '{0}' AND j.checkpoint_id > $1
+                    )
+                "#,
+                table,
+                key.join(", "),
+                row_key
+            ),
+            format!(
+                r#"
+                    INSERT INTO {0} OVERRIDING SYSTEM VALUE
+                    SELECT (jsonb_populate_record(NULL::{0}, j.old_row)).*
+                    FROM statestore_journal j
+                    WHERE j.table_name = '{0}' AND j.old_row IS NOT NULL AND j.checkpoint_id = (
+                        SELECT MIN(f.checkpoint_id) FROM statestore_journal f
+                        WHERE f.table_name = j.table_name AND f.row_key = j.row_key
+                            AND f.checkpoint_id > $1
+                    )
+                "#,
+                table
+            ),
+        ]
+    }
+
     /// Upserts room information
     ///
     /// # Arguments
     /// * `$1` - The room ID
     /// * `$2` - Whether or not the state is partial
     /// * `$3` - The room info
+    /// * `$4` - The number of unread notifications
+    /// * `$5` - The number of unread highlights
+    /// * `$6` - The current time
+    /// * `$7` - The room state, `Joined`, `Left` or `Invited`
+    /// * `$8` - Whether or not the room is encrypted
+    /// * `$9` - Whether or not the room is a direct message
+    /// * `$10` - The room name, if any
+    /// * `$11` - The lowercased room name, or an empty string
     fn room_upsert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
                 INSERT INTO statestore_rooms
-                    (room_id, is_partial, room_info)
-                VALUES ($1, $2, $3)
-                ON CONFLICT(room_id) DO UPDATE SET is_partial = $2, room_info = $3
+                    (room_id, is_partial, room_info, notification_count, highlight_count, counts_updated_at, room_state, is_encrypted, is_direct, room_name, name_sort)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                ON CONFLICT(room_id) DO UPDATE SET is_partial = $2, room_info = $3, notification_count = $4, highlight_count = $5, counts_updated_at = $6, room_state = $7, is_encrypted = $8, is_direct = $9, room_name = $10, name_sort = $11
+            "#,
+        )
+    }
+
+    /// Deletes the tags of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    fn room_tags_remove_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("DELETE FROM statestore_room_tags WHERE room_id = $1")
+    }
+
+    /// Inserts a tag of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The room ID
+    /// * `$2` - The tag
+    fn room_tag_insert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO statestore_room_tags (room_id, tag)
+                VALUES ($1, $2)
+                ON CONFLICT(room_id, tag) DO NOTHING
             "#,
         )
     }
 
+    /// Retrieves a page of room infos matching a filter, in the given order
+    ///
+    /// Filter arguments that are `NULL` match every room.
+    ///
+    /// # Arguments
+    /// * `$1` - The room state, `Joined`, `Left` or `Invited`
+    /// * `$2` - Whether or not the room is encrypted
+    /// * `$3` - Whether or not the room is a direct message
+    /// * `$4` - A tag of the room
+    /// * `$5` - A `LIKE` pattern for the lowercased room name, escaped with `\`
+    /// * `$6` - The room ID of a parent space
+    /// * `$7` - The room ID of the last room of the previous page
+    /// * `$8` - The lowercased name of the last room of the previous page
+    /// * `$9` - The update time of the last room of the previous page
+    /// * `$10` - The maximum number of rooms
+    fn room_list_query<'q>(
+        sort: RoomSort,
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(match sort {
+            RoomSort::RoomId => concat!(
+                room_list_select!(),
+                "AND ($7 IS NULL OR room_id > $7) ORDER BY room_id LIMIT $10"
+            ),
+            RoomSort::Name => concat!(
+                room_list_select!(),
+                "AND ($7 IS NULL OR name_sort > $8 OR (name_sort = $8 AND room_id > $7)) ",
+                "ORDER BY name_sort, room_id LIMIT $10"
+            ),
+            RoomSort::RecentlyUpdated => concat!(
+                room_list_select!(),
+                "AND ($7 IS NULL OR counts_updated_at < $9 OR (counts_updated_at = $9 AND room_id < $7)) ",
+                "ORDER BY counts_updated_at DESC, room_id DESC LIMIT $10"
+            ),
+        })
+    }
+
     /// Upserts an event receipt
     ///
     /// # Arguments
@@ -303,13 +1301,16 @@ pub trait SupportedDatabase: Database + Sealed {
     /// * `$3` - The receipt type
     /// * `$4` - The user id
     /// * `$5` - The receipt content
+    /// * `$6` - The thread ID, or an empty string for unthreaded receipts
+    /// * `$7` - The timestamp of the receipt
+    /// * `$8` - The current time
     fn receipt_upsert_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
                 INSERT INTO statestore_receipts
-                    (room_id, event_id, receipt_type, user_id, receipt)
-                VALUES ($1, $2, $3, $4, $5)
-                ON CONFLICT(room_id, receipt_type, user_id) DO UPDATE SET event_id = $2, receipt_type = $3, receipt = $5
+                    (room_id, event_id, receipt_type, user_id, receipt, thread_id, receipt_ts, stored_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT(room_id, receipt_type, thread_id, user_id) DO UPDATE SET event_id = $2, receipt_type = $3, receipt = $5, receipt_ts = $7, stored_at = $8
             "#,
         )
     }
@@ -448,11 +1449,34 @@ pub trait SupportedDatabase: Database + Sealed {
     /// * `$1` - The room ID
     /// * `$2` - The receipt type
     /// * `$3` - The user ID
+    /// * `$4` - The thread ID, or an empty string for unthreaded receipts
     fn receipt_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
-                SELECT event_id, receipt FROM statestore_receipts
-                WHERE room_id = $1 AND receipt_type = $2 AND user_id = $3
+                SELECT event_id, receipt FROM statestore_receipts
+                WHERE room_id = $1 AND receipt_type = $2 AND user_id = $3 AND thread_id = $4
+            "#,
+        )
+    }
+
+    /// Get the notification counts of all rooms, with when the latest read receipt of a user was
+    /// stored
+    ///
+    /// # Arguments
+    /// * `$1` - The user ID
+    fn unread_counts_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT r.room_id, r.notification_count, r.highlight_count, r.counts_updated_at,
+                    COALESCE((
+                        SELECT MAX(rc.stored_at) FROM statestore_receipts rc
+                        WHERE rc.room_id = r.room_id AND rc.user_id = $1
+                            AND rc.receipt_type IN ('m.read', 'm.read.private')
+                            AND rc.thread_id IN ('', 'main')
+                    ), 0) AS receipt_stored_at
+                FROM statestore_rooms r
+                WHERE NOT r.is_partial
+                ORDER BY r.room_id
             "#,
         )
     }
@@ -463,11 +1487,12 @@ pub trait SupportedDatabase: Database + Sealed {
     /// * `$1` - The room ID
     /// * `$2` - The receipt type
     /// * `$3` - The event ID
+    /// * `$4` - The thread ID, or an empty string for unthreaded receipts
     fn event_receipt_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
                 SELECT user_id, receipt FROM statestore_receipts
-                WHERE room_id = $1 AND receipt_type = $2 AND event_id = $3
+                WHERE room_id = $1 AND receipt_type = $2 AND event_id = $3 AND thread_id = $4
             "#,
         )
     }
@@ -652,6 +1677,19 @@ pub trait SupportedDatabase: Database + Sealed {
         )
     }
 
+    /// Fetch the inbound group sessions of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    fn room_inbound_group_sessions_fetch_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT session_data FROM cryptostore_inbound_group_session WHERE room_id = $1
+            "#,
+        )
+    }
+
     /// Load the outbound group session for a room
     ///
     /// # Arguments
@@ -808,6 +1846,307 @@ pub trait SupportedDatabase: Database + Sealed {
             "#,
         )
     }
+
+    /// Returns the on-disk size of a table, including its indexes
+    ///
+    /// # Arguments
+    /// * `$1` - The name of the table
+    fn table_size_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT pg_total_relation_size(to_regclass($1)) AS bytes
+            "#,
+        )
+    }
+
+    /// Returns the number of entries and total size of the media cache
+    fn media_stats_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT COUNT(*) AS entries, COALESCE(SUM(LENGTH(media_data)), 0) AS bytes
+                FROM statestore_media
+            "#,
+        )
+    }
+
+    /// Returns the number of rooms, grouped by whether they are partial
+    fn room_counts_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT is_partial, COUNT(*) AS count FROM statestore_rooms
+                GROUP BY is_partial
+            "#,
+        )
+    }
+
+    /// Stores a received secret
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed secret name
+    /// * `$2` - The encrypted secret
+    #[cfg(feature = "e2e-encryption")]
+    fn secret_inbox_store_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO cryptostore_secret_inbox (secret_name, secret_data)
+                VALUES ($1, $2)
+            "#,
+        )
+    }
+
+    /// Retrieves the received secrets with a name, oldest first
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed secret name
+    #[cfg(feature = "e2e-encryption")]
+    fn secret_inbox_fetch_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT secret_data FROM cryptostore_secret_inbox
+                WHERE secret_name = $1
+                ORDER BY secret_id
+            "#,
+        )
+    }
+
+    /// Deletes the received secrets with a name
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed secret name
+    #[cfg(feature = "e2e-encryption")]
+    fn secret_inbox_delete_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM cryptostore_secret_inbox
+                WHERE secret_name = $1
+            "#,
+        )
+    }
+
+    /// Upserts a room key withheld notice
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed sender key
+    /// * `$3` - The hashed session ID
+    /// * `$4` - The encrypted withheld notice
+    #[cfg(feature = "e2e-encryption")]
+    fn room_key_withheld_store_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                INSERT INTO cryptostore_room_key_withheld (room_id, sender_key, session_id, withheld_data)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (room_id, sender_key, session_id)
+                DO UPDATE SET withheld_data = $4
+            "#,
+        )
+    }
+
+    /// Retrieves a room key withheld notice
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed sender key
+    /// * `$3` - The hashed session ID
+    #[cfg(feature = "e2e-encryption")]
+    fn room_key_withheld_fetch_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT withheld_data FROM cryptostore_room_key_withheld
+                WHERE room_id = $1 AND sender_key = $2 AND session_id = $3
+            "#,
+        )
+    }
+
+    /// Deletes a room key withheld notice
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed sender key
+    /// * `$3` - The hashed session ID
+    #[cfg(feature = "e2e-encryption")]
+    fn room_key_withheld_delete_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                DELETE FROM cryptostore_room_key_withheld
+                WHERE room_id = $1 AND sender_key = $2 AND session_id = $3
+            "#,
+        )
+    }
+
+    /// Upserts the encryption settings of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The encrypted settings
+    #[cfg(feature = "e2e-encryption")]
+    fn room_settings_store_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO cryptostore_room_settings (room_id, settings_data)
+                VALUES ($1, $2)
+                ON CONFLICT (room_id)
+                DO UPDATE SET settings_data = $2
+            "#,
+        )
+    }
+
+    /// Retrieves the encryption settings of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    #[cfg(feature = "e2e-encryption")]
+    fn room_settings_fetch_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT settings_data FROM cryptostore_room_settings
+                WHERE room_id = $1
+            "#,
+        )
+    }
+
+    /// Records that a device received an outbound group session, keeping the first record
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed session ID
+    /// * `$3` - The hashed user ID
+    /// * `$4` - The hashed device ID
+    /// * `$5` - The encrypted share info
+    #[cfg(feature = "e2e-encryption")]
+    fn outbound_session_share_store_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO cryptostore_outbound_session_share (room_id, session_id, user_id, device_id, share_data)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (room_id, session_id, user_id, device_id) DO NOTHING
+            "#,
+        )
+    }
+
+    /// Retrieves the recipients already recorded for an outbound group session
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed session ID
+    #[cfg(feature = "e2e-encryption")]
+    fn outbound_session_share_recipients_fetch_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT user_id, device_id FROM cryptostore_outbound_session_share
+                WHERE room_id = $1 AND session_id = $2
+            "#,
+        )
+    }
+
+    /// Deletes the share info of outbound group sessions of a room that were replaced
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    /// * `$2` - The hashed ID of the current session
+    #[cfg(feature = "e2e-encryption")]
+    fn outbound_session_share_prune_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                DELETE FROM cryptostore_outbound_session_share
+                WHERE room_id = $1 AND session_id <> $2
+            "#,
+        )
+    }
+
+    /// Retrieves the share info of the current outbound group session of a room
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed room ID
+    #[cfg(feature = "e2e-encryption")]
+    fn outbound_session_shares_fetch_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT share_data FROM cryptostore_outbound_session_share
+                WHERE room_id = $1
+            "#,
+        )
+    }
+
+    /// Appends a change to the identity change log
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed user ID
+    /// * `$2` - The encrypted change
+    #[cfg(feature = "e2e-encryption")]
+    fn identity_change_store_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                INSERT INTO cryptostore_identity_change (user_id, change_data)
+                VALUES ($1, $2)
+            "#,
+        )
+    }
+
+    /// Retrieves the identity changes of a user, oldest first
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed user ID
+    #[cfg(feature = "e2e-encryption")]
+    fn identity_changes_fetch_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT change_data, acknowledged FROM cryptostore_identity_change
+                WHERE user_id = $1
+                ORDER BY change_id
+            "#,
+        )
+    }
+
+    /// Retrieves the identity changes that were not acknowledged yet, oldest first
+    #[cfg(feature = "e2e-encryption")]
+    fn identity_changes_unacknowledged_fetch_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT change_data, acknowledged FROM cryptostore_identity_change
+                WHERE acknowledged = FALSE
+                ORDER BY change_id
+            "#,
+        )
+    }
+
+    /// Acknowledges the identity changes of a user
+    ///
+    /// # Arguments
+    /// * `$1` - The hashed user ID
+    #[cfg(feature = "e2e-encryption")]
+    fn identity_changes_acknowledge_query<'q>(
+    ) -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                UPDATE cryptostore_identity_change SET acknowledged = TRUE
+                WHERE user_id = $1 AND acknowledged = FALSE
+            "#,
+        )
+    }
+
+    /// Returns the number of gossip requests by sent state
+    ///
+    /// # Arguments
+    /// * `$1` - The sent state
+    #[cfg(feature = "e2e-encryption")]
+    fn gossip_requests_count_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT COUNT(*) AS count FROM cryptostore_gossip_request
+                WHERE sent_out = $1
+            "#,
+        )
+    }
 }
 
 #[cfg(feature = "postgres")]
@@ -820,6 +2159,10 @@ impl SupportedDatabase for sqlx::postgres::Postgres {
         };
         &MIGRATOR
     }
+
+    fn rows_affected(result: &<Self as Database>::QueryResult) -> u64 {
+        result.rows_affected()
+    }
 }
 
 #[cfg(feature = "sqlite")]
@@ -833,6 +2176,107 @@ impl SupportedDatabase for sqlx::sqlite::Sqlite {
         &MIGRATOR
     }
 
+    fn rows_affected(result: &<Self as Database>::QueryResult) -> u64 {
+        result.rows_affected()
+    }
+
+    fn migrations_table_exists_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments>
+    {
+        sqlx::query(
+            r#"
+                SELECT 1 FROM sqlite_master
+                WHERE type = 'table' AND name = '_sqlx_migrations'
+            "#,
+        )
+    }
+
+    fn vacuum_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("VACUUM; ANALYZE")
+    }
+
+    fn space_tree_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                WITH RECURSIVE tree (parent_id, child_id, room_id, state_key, depth, path) AS (
+                    SELECT parent_id, child_id, room_id, state_key, CAST(1 AS BIGINT),
+                        ' ' || parent_id || ' ' || child_id || ' '
+                    FROM statestore_space_edges
+                    WHERE parent_id = $1 AND event_type = 'm.space.child'
+                    UNION ALL
+                    SELECT e.parent_id, e.child_id, e.room_id, e.state_key, t.depth + 1,
+                        t.path || e.child_id || ' '
+                    FROM tree t
+                    JOIN statestore_space_edges e
+                        ON e.parent_id = t.child_id AND e.event_type = 'm.space.child'
+                    WHERE t.depth < $2 AND instr(t.path, ' ' || e.child_id || ' ') = 0
+                )
+                SELECT t.parent_id, t.child_id, t.depth, s.state_event
+                FROM tree t
+                JOIN statestore_state s
+                    ON s.room_id = t.room_id AND s.event_type = 'm.space.child' AND s.state_key = t.state_key
+            "#,
+        )
+    }
+
+    fn table_columns_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query("SELECT name FROM pragma_table_info($1) ORDER BY cid")
+    }
+
+    fn journal_undo_statements(table: &str, key: &[&str], columns: &[&str]) -> [String; 2] {
+        let row_key = key
+            .iter()
+            .map(|column| format!("json_extract(j.row_key, '$.{}')", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let values = columns
+            .iter()
+            .map(|column| format!("json_extract(j.old_row, '$.{}')", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        [
+            format!(
+                r#"
+                    DELETE FROM {0} WHERE ({1}) IN (
+                        SELECT {2} FROM statestore_journal j
+                        WHERE j.table_name = '{0}' AND j.checkpoint_id > $1
+                    )
+                "#,
+                table,
+                key.join(", "),
+                row_key
+            ),
+            format!(
+                r#"
+                    INSERT INTO {0} ({1})
+                    SELECT {2}
+                    FROM statestore_journal j
+                    WHERE j.table_name = '{0}' AND j.old_row IS NOT NULL AND j.checkpoint_id = (
+                        SELECT MIN(f.checkpoint_id) FROM statestore_journal f
+                        WHERE f.table_name = j.table_name AND f.row_key = j.row_key
+                            AND f.checkpoint_id > $1
+                    )
+                "#,
+                table,
+                columns.join(", "),
+                values
+            ),
+        ]
+    }
+
+    fn integrity_check_query<'q>() -> Option<Query<'q, Self, <Self as HasArguments<'q>>::Arguments>>
+    {
+        Some(sqlx::query("PRAGMA integrity_check"))
+    }
+
+    fn table_size_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
+        sqlx::query(
+            r#"
+                SELECT SUM(pgsize) AS bytes FROM dbstat
+                WHERE name IN (SELECT name FROM sqlite_master WHERE tbl_name = $1)
+            "#,
+        )
+    }
+
     fn media_load_query<'q>() -> Query<'q, Self, <Self as HasArguments<'q>>::Arguments> {
         sqlx::query(
             r#"
@@ -0,0 +1,699 @@
+//! Room state and membership history
+
+use std::collections::BTreeMap;
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent, StateEventType,
+    },
+    serde::Raw,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt,
+    UserId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// The state of a room, by event type and state key
+pub type RoomState = BTreeMap<StateEventType, BTreeMap<String, Raw<AnySyncStateEvent>>>;
+
+/// A single change to the state of a room
+#[derive(Clone, Debug)]
+pub struct StateChange {
+    /// The ID of the state event
+    pub event_id: OwnedEventId,
+    /// The user that sent the state event
+    pub sender: OwnedUserId,
+    /// The timestamp of the state event
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    /// Whether the state event has been redacted since, which strips the event to its redacted form
+    pub redacted: bool,
+    /// The state event
+    pub event: Raw<AnySyncStateEvent>,
+}
+
+/// A single change of the membership of a user in a room
+#[derive(Clone, Debug)]
+pub struct MembershipChange {
+    /// The ID of the member event
+    pub event_id: OwnedEventId,
+    /// The user that sent the member event, which differs from the member for kicks and bans
+    pub sender: OwnedUserId,
+    /// The new membership state
+    pub membership: MembershipState,
+    /// The timestamp of the member event
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// Converts a stored timestamp back into a matrix timestamp
+pub(crate) fn decode_timestamp(ts: i64) -> MilliSecondsSinceUnixEpoch {
+    MilliSecondsSinceUnixEpoch(
+        u64::try_from(ts)
+            .ok()
+            .and_then(UInt::new)
+            .unwrap_or_default(),
+    )
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Returns all changes to a state key of a room, oldest first
+    ///
+    /// Changes are ordered by the time they were stored, which is the order the homeserver sent
+    /// them in.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn state_history(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+        state_key: &str,
+    ) -> Result<Vec<StateChange>> {
+        let mut rows = DB::state_history_load_query()
+            .bind(room_id.as_str())
+            .bind(event_type.to_string())
+            .bind(state_key)
+            .fetch(&*self.db);
+        let mut changes = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let event_id: String = row.try_get("event_id")?;
+            let sender: String = row.try_get("sender")?;
+            let origin_server_ts: i64 = row.try_get("origin_server_ts")?;
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            changes.push(StateChange {
+                event_id: event_id.try_into()?,
+                sender: sender.try_into()?,
+                origin_server_ts: decode_timestamp(origin_server_ts),
+                redacted: row.try_get("redacted")?,
+                event: event.0,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Reconstructs the state of a room as it was right after a state event
+    ///
+    /// Returns `None` if the event is not a known state event of the room. Redacted state events
+    /// are kept in their redacted form.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn state_at_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<RoomState>> {
+        let row = DB::state_history_position_query()
+            .bind(room_id.as_str())
+            .bind(event_id.as_str())
+            .fetch_optional(&*self.db)
+            .await?;
+        let position: i64 = if let Some(row) = row {
+            row.try_get("history_id")?
+        } else {
+            return Ok(None);
+        };
+        let query = DB::state_at_position_load_query()
+            .bind(room_id.as_str())
+            .bind(position);
+        Ok(Some(self.load_room_state(query).await?))
+    }
+
+    /// Reconstructs the state of a room as it was at a point in time
+    ///
+    /// This uses the timestamps of the state events, which are set by the sending homeserver.
+    /// Redacted state events are kept in their redacted form.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn state_at_timestamp(
+        &self,
+        room_id: &RoomId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> Result<RoomState> {
+        let query = DB::state_at_timestamp_load_query()
+            .bind(room_id.as_str())
+            .bind(i64::from(timestamp.0));
+        self.load_room_state(query).await
+    }
+
+    /// Returns the membership changes of a user in a room, oldest first
+    ///
+    /// Member events that do not change the membership, like display name changes, are not
+    /// recorded.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn membership_history(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Vec<MembershipChange>> {
+        let mut rows = DB::membership_history_load_query()
+            .bind(room_id.as_str())
+            .bind(user_id.as_str())
+            .fetch(&*self.db);
+        let mut changes = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let event_id: String = row.try_get("event_id")?;
+            let sender: String = row.try_get("sender")?;
+            let membership: String = row.try_get("membership")?;
+            let origin_server_ts: i64 = row.try_get("origin_server_ts")?;
+            changes.push(MembershipChange {
+                event_id: event_id.try_into()?,
+                sender: sender.try_into()?,
+                membership: membership.into(),
+                origin_server_ts: decode_timestamp(origin_server_ts),
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Returns the rooms a user is joined to
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn joined_rooms_of(&self, user_id: &UserId) -> Result<Vec<OwnedRoomId>> {
+        let query = DB::joined_rooms_load_query().bind(user_id.as_str());
+        self.load_room_ids(query).await
+    }
+
+    /// Returns the rooms both users are joined to
+    ///
+    /// Pass your own user ID as `own_user_id` to find the rooms you share with `user_id`.
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn shared_rooms(
+        &self,
+        own_user_id: &UserId,
+        user_id: &UserId,
+    ) -> Result<Vec<OwnedRoomId>> {
+        let query = DB::shared_rooms_load_query()
+            .bind(own_user_id.as_str())
+            .bind(user_id.as_str());
+        self.load_room_ids(query).await
+    }
+
+    /// Returns the joined members of a room that joined at or after `since`, newest first
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    pub async fn members_joined_since(
+        &self,
+        room_id: &RoomId,
+        since: MilliSecondsSinceUnixEpoch,
+    ) -> Result<Vec<(OwnedUserId, MilliSecondsSinceUnixEpoch)>> {
+        let mut rows = DB::recently_joined_load_query()
+            .bind(room_id.as_str())
+            .bind(i64::from(since.0))
+            .fetch(&*self.db);
+        let mut members = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let user_id: String = row.try_get("user_id")?;
+            let joined_at: i64 = row.try_get("joined_at")?;
+            members.push((user_id.try_into()?, decode_timestamp(joined_at)));
+        }
+        Ok(members)
+    }
+
+    /// Collects the result of a room ID query
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    async fn load_room_ids<'q>(
+        &self,
+        query: sqlx::query::Query<'q, DB, <DB as HasArguments<'q>>::Arguments>,
+    ) -> Result<Vec<OwnedRoomId>> {
+        let mut rows = query.fetch(&*self.db);
+        let mut room_ids = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let room_id: String = row.try_get("room_id")?;
+            room_ids.push(room_id.try_into()?);
+        }
+        Ok(room_ids)
+    }
+
+    /// Collects the result of a room state query
+    ///
+    /// # Errors
+    /// This function will return an error if the query fails
+    async fn load_room_state<'q>(
+        &self,
+        query: sqlx::query::Query<'q, DB, <DB as HasArguments<'q>>::Arguments>,
+    ) -> Result<RoomState> {
+        let mut rows = query.fetch(&*self.db);
+        let mut state = RoomState::new();
+        while let Some(row) = rows.try_next().await? {
+            let event_type: String = row.try_get("event_type")?;
+            let state_key: String = row.try_get("state_key")?;
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            state
+                .entry(event_type.into())
+                .or_default()
+                .insert(state_key, event.0);
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_base::StateChanges;
+    use ruma::{
+        event_id,
+        events::{
+            room::member::{MembershipState, SyncRoomMemberEvent},
+            AnySyncStateEvent, StateEventType,
+        },
+        room_id,
+        serde::Raw,
+        user_id, MilliSecondsSinceUnixEpoch, UInt,
+    };
+    use serde_json::json;
+
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates a room name event
+    fn name_event(event_id: &str, name: &str, ts: u64) -> Raw<AnySyncStateEvent> {
+        Raw::new(&json!({
+            "content": { "name": name },
+            "event_id": event_id,
+            "origin_server_ts": ts,
+            "sender": "@alice:localhost",
+            "state_key": "",
+            "type": "m.room.name",
+        }))
+        .unwrap()
+        .cast()
+    }
+
+    /// Creates a room topic event
+    fn topic_event(event_id: &str, topic: &str, ts: u64) -> Raw<AnySyncStateEvent> {
+        Raw::new(&json!({
+            "content": { "topic": topic },
+            "event_id": event_id,
+            "origin_server_ts": ts,
+            "sender": "@bob:localhost",
+            "state_key": "",
+            "type": "m.room.topic",
+        }))
+        .unwrap()
+        .cast()
+    }
+
+    /// Stores a single state event
+    fn changes(event: Raw<AnySyncStateEvent>, event_type: StateEventType) -> StateChanges {
+        let mut changes = StateChanges::default();
+        changes
+            .state
+            .entry(room_id!("!history:localhost").to_owned())
+            .or_default()
+            .entry(event_type)
+            .or_default()
+            .insert(String::new(), event);
+        changes
+    }
+
+    /// Redacts an event in the history room
+    fn redaction(event_id: &str) -> StateChanges {
+        let redaction = serde_json::from_value(json!({
+            "content": {},
+            "event_id": "$redaction",
+            "origin_server_ts": 4000,
+            "redacts": event_id,
+            "sender": "@alice:localhost",
+            "type": "m.room.redaction",
+        }))
+        .unwrap();
+        let mut changes = StateChanges::default();
+        changes.add_redaction(room_id!("!history:localhost"), redaction);
+        changes
+    }
+
+    /// Returns the name in a room state
+    fn name_of(state: &super::RoomState) -> Option<String> {
+        let event = state.get(&StateEventType::RoomName)?.get("")?;
+        let event: serde_json::Value = event.deserialize_as().unwrap();
+        Some(event["content"]["name"].as_str()?.to_owned())
+    }
+
+    /// Stores a single member event
+    fn member_changes(
+        room_id: &str,
+        event_id: &str,
+        sender: &str,
+        user_id: &str,
+        membership: &str,
+        ts: u64,
+    ) -> StateChanges {
+        let event: SyncRoomMemberEvent = serde_json::from_value(json!({
+            "content": { "membership": membership },
+            "event_id": event_id,
+            "origin_server_ts": ts,
+            "sender": sender,
+            "state_key": user_id,
+            "type": "m.room.member",
+        }))
+        .unwrap();
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.try_into().unwrap())
+            .or_default()
+            .insert(user_id.try_into().unwrap(), event);
+        changes
+    }
+
+    /// Creates a timestamp
+    fn ts(ms: u32) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(UInt::from(ms))
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_state_history() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room_id = room_id!("!history:localhost");
+        store
+            .save_state_changes(&changes(
+                name_event("$a", "first", 1000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+        store
+            .save_state_changes(&changes(
+                topic_event("$b", "topic", 2000),
+                StateEventType::RoomTopic,
+            ))
+            .await
+            .unwrap();
+        store
+            .save_state_changes(&changes(
+                name_event("$c", "second", 3000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+        // Resent state is only recorded once
+        store
+            .save_state_changes(&changes(
+                name_event("$c", "second", 3000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+
+        let history = store
+            .state_history(room_id, StateEventType::RoomName, "")
+            .await
+            .unwrap();
+        let ids: Vec<_> = history.iter().map(|c| c.event_id.as_str()).collect();
+        assert_eq!(ids, ["$a", "$c"]);
+        assert_eq!(history[0].sender, "@alice:localhost");
+        assert_eq!(history[1].origin_server_ts, ts(3000));
+
+        let state = store
+            .state_at_event(room_id, event_id!("$b"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("first"));
+        assert!(state.contains_key(&StateEventType::RoomTopic));
+        assert!(store
+            .state_at_event(room_id, event_id!("$unknown"))
+            .await
+            .unwrap()
+            .is_none());
+
+        let state = store.state_at_timestamp(room_id, ts(1500)).await.unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("first"));
+        assert!(!state.contains_key(&StateEventType::RoomTopic));
+        let state = store.state_at_timestamp(room_id, ts(5000)).await.unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("second"));
+        assert!(store
+            .state_at_timestamp(room_id, ts(500))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Redacted events lose their content in the history as well
+        store.save_state_changes(&redaction("$a")).await.unwrap();
+        let history = store
+            .state_history(room_id, StateEventType::RoomName, "")
+            .await
+            .unwrap();
+        assert!(history[0].redacted);
+        assert!(!history[1].redacted);
+        let event: serde_json::Value = history[0].event.deserialize_as().unwrap();
+        assert_eq!(event["content"], json!({}));
+        assert_eq!(event["event_id"], "$a");
+        let state = store
+            .state_at_event(room_id, event_id!("$b"))
+            .await
+            .unwrap()
+            .unwrap();
+        let event: serde_json::Value = state[&StateEventType::RoomName][""]
+            .deserialize_as()
+            .unwrap();
+        assert_eq!(event["content"], json!({}));
+        assert_eq!(event["event_id"], "$a");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_state_history() {
+        let store = crate::schema::postgres_test_store("matrix_history_test").await;
+        let room_id = room_id!("!history:localhost");
+        store
+            .save_state_changes(&changes(
+                name_event("$a", "first", 1000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+        store
+            .save_state_changes(&changes(
+                topic_event("$b", "topic", 2000),
+                StateEventType::RoomTopic,
+            ))
+            .await
+            .unwrap();
+        store
+            .save_state_changes(&changes(
+                name_event("$c", "second", 3000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+        // Resent state is only recorded once
+        store
+            .save_state_changes(&changes(
+                name_event("$c", "second", 3000),
+                StateEventType::RoomName,
+            ))
+            .await
+            .unwrap();
+
+        let history = store
+            .state_history(room_id, StateEventType::RoomName, "")
+            .await
+            .unwrap();
+        let ids: Vec<_> = history.iter().map(|c| c.event_id.as_str()).collect();
+        assert_eq!(ids, ["$a", "$c"]);
+        assert_eq!(history[0].sender, "@alice:localhost");
+        assert_eq!(history[1].origin_server_ts, ts(3000));
+
+        let state = store
+            .state_at_event(room_id, event_id!("$b"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("first"));
+        assert!(state.contains_key(&StateEventType::RoomTopic));
+        assert!(store
+            .state_at_event(room_id, event_id!("$unknown"))
+            .await
+            .unwrap()
+            .is_none());
+
+        let state = store.state_at_timestamp(room_id, ts(1500)).await.unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("first"));
+        assert!(!state.contains_key(&StateEventType::RoomTopic));
+        let state = store.state_at_timestamp(room_id, ts(5000)).await.unwrap();
+        assert_eq!(name_of(&state).as_deref(), Some("second"));
+        assert!(store
+            .state_at_timestamp(room_id, ts(500))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Redacted events lose their content in the history as well
+        store.save_state_changes(&redaction("$a")).await.unwrap();
+        let history = store
+            .state_history(room_id, StateEventType::RoomName, "")
+            .await
+            .unwrap();
+        assert!(history[0].redacted);
+        assert!(!history[1].redacted);
+        let event: serde_json::Value = history[0].event.deserialize_as().unwrap();
+        assert_eq!(event["content"], json!({}));
+        assert_eq!(event["event_id"], "$a");
+        let state = store
+            .state_at_event(room_id, event_id!("$b"))
+            .await
+            .unwrap()
+            .unwrap();
+        let event: serde_json::Value = state[&StateEventType::RoomName][""]
+            .deserialize_as()
+            .unwrap();
+        assert_eq!(event["content"], json!({}));
+        assert_eq!(event["event_id"], "$a");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_membership_history() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let (room1, room2) = ("!room1:localhost", "!room2:localhost");
+        let (alice, bob) = ("@alice:localhost", "@bob:localhost");
+        for changes in [
+            member_changes(room1, "$1", alice, alice, "join", 1000),
+            member_changes(room1, "$2", bob, bob, "join", 2000),
+            // A display name change is not a membership change
+            member_changes(room1, "$3", bob, bob, "join", 2500),
+            member_changes(room2, "$4", alice, alice, "join", 3000),
+            member_changes(room2, "$5", bob, bob, "join", 4000),
+            member_changes(room1, "$6", alice, bob, "ban", 5000),
+        ] {
+            store.save_state_changes(&changes).await.unwrap();
+        }
+
+        let history = store
+            .membership_history(room_id!("!room1:localhost"), user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].membership, MembershipState::Join);
+        assert_eq!(history[1].membership, MembershipState::Ban);
+        assert_eq!(history[1].sender, alice);
+        assert_eq!(history[1].origin_server_ts, ts(5000));
+
+        let rooms = store
+            .joined_rooms_of(user_id!("@alice:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(rooms, [room1, room2]);
+        let rooms = store
+            .shared_rooms(user_id!("@alice:localhost"), user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(rooms, [room2]);
+
+        let room_id = room_id!("!room2:localhost");
+        let members = store.members_joined_since(room_id, ts(3500)).await.unwrap();
+        assert_eq!(members, [(user_id!("@bob:localhost").to_owned(), ts(4000))]);
+        let members = store.members_joined_since(room_id, ts(0)).await.unwrap();
+        assert_eq!(members.len(), 2);
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_membership_history() {
+        let store = crate::schema::postgres_test_store("matrix_membership_test").await;
+        let (room1, room2) = ("!room1:localhost", "!room2:localhost");
+        let (alice, bob) = ("@alice:localhost", "@bob:localhost");
+        for changes in [
+            member_changes(room1, "$1", alice, alice, "join", 1000),
+            member_changes(room1, "$2", bob, bob, "join", 2000),
+            // A display name change is not a membership change
+            member_changes(room1, "$3", bob, bob, "join", 2500),
+            member_changes(room2, "$4", alice, alice, "join", 3000),
+            member_changes(room2, "$5", bob, bob, "join", 4000),
+            member_changes(room1, "$6", alice, bob, "ban", 5000),
+        ] {
+            store.save_state_changes(&changes).await.unwrap();
+        }
+
+        let history = store
+            .membership_history(room_id!("!room1:localhost"), user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].membership, MembershipState::Join);
+        assert_eq!(history[1].membership, MembershipState::Ban);
+        assert_eq!(history[1].sender, alice);
+        assert_eq!(history[1].origin_server_ts, ts(5000));
+
+        let rooms = store
+            .joined_rooms_of(user_id!("@alice:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(rooms, [room1, room2]);
+        let rooms = store
+            .shared_rooms(user_id!("@alice:localhost"), user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(rooms, [room2]);
+
+        let room_id = room_id!("!room2:localhost");
+        let members = store.members_joined_since(room_id, ts(3500)).await.unwrap();
+        assert_eq!(members, [(user_id!("@bob:localhost").to_owned(), ts(4000))]);
+        let members = store.members_joined_since(room_id, ts(0)).await.unwrap();
+        assert_eq!(members.len(), 2);
+    }
+}
@@ -0,0 +1,499 @@
+//! Log of cross-signing identity changes
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use matrix_sdk_crypto::{types::CrossSigningKey, MasterPubkey, ReadOnlyUserIdentities};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedUserId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// A change of the master key of a user's cross-signing identity
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityChange {
+    /// The user whose identity changed
+    pub user_id: OwnedUserId,
+    /// The base64 encoded master key before the change
+    pub old_master_key: Option<String>,
+    /// The base64 encoded master key after the change
+    pub new_master_key: Option<String>,
+    /// When the change was stored
+    pub changed_at: MilliSecondsSinceUnixEpoch,
+    /// Whether the identity was verified before the change
+    ///
+    /// Our own identity is verified if it was marked as verified, the identities of other users
+    /// if their master key was signed by our user-signing key.
+    pub was_verified: bool,
+    /// Whether the change was acknowledged
+    #[serde(skip)]
+    pub acknowledged: bool,
+}
+
+/// Returns the base64 encoded master key of an identity
+fn master_key_fingerprint(identity: &ReadOnlyUserIdentities) -> Option<String> {
+    identity
+        .master_key()
+        .get_first_key()
+        .map(|key| key.to_base64())
+}
+
+/// Returns the canonical JSON of a cross-signing key that its signatures are made over
+pub(crate) fn canonical_json(key: &CrossSigningKey) -> Option<String> {
+    let mut value = serde_json::to_value(key).ok()?;
+    let object = value.as_object_mut()?;
+    object.remove("signatures");
+    object.remove("unsigned");
+    CanonicalJsonValue::try_from(value)
+        .ok()
+        .map(|value| value.to_string())
+}
+
+/// Returns whether a master key carries a valid signature of a user-signing key
+fn is_signed_by(master_key: &MasterPubkey, signer: &CrossSigningKey) -> bool {
+    let master_key: &CrossSigningKey = master_key.as_ref();
+    signer
+        .get_first_key_and_id()
+        .and_then(|(key_id, public_key)| {
+            let signature = master_key
+                .signatures
+                .get_signature(&signer.user_id, key_id)?;
+            let json = canonical_json(master_key)?;
+            Some(public_key.verify(json.as_bytes(), &signature).is_ok())
+        })
+        .unwrap_or(false)
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Appends an identity change to the log if the master key of `identity` differs from the
+    /// one of `previous`
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub(crate) async fn record_identity_change<'c>(
+        &self,
+        txn: &mut Transaction<'c, DB>,
+        previous: &ReadOnlyUserIdentities,
+        identity: &ReadOnlyUserIdentities,
+    ) -> Result<()> {
+        let old_master_key = master_key_fingerprint(previous);
+        let new_master_key = master_key_fingerprint(identity);
+        if old_master_key == new_master_key {
+            return Ok(());
+        }
+
+        let e2e = self.ensure_e2e()?;
+        let was_verified = match previous {
+            ReadOnlyUserIdentities::Own(own) => own.is_verified(),
+            ReadOnlyUserIdentities::Other(other) => {
+                let own_identity = match e2e.own_user_id() {
+                    Some(own_user_id) => {
+                        let own_user_id =
+                            e2e.encode_key("cryptostore_identity:user_id", own_user_id.as_bytes());
+                        DB::identity_fetch_query()
+                            .bind(own_user_id.as_ref())
+                            .fetch_optional(&mut *txn)
+                            .await?
+                    }
+                    None => None,
+                };
+                if let Some(row) = own_identity {
+                    let data: Vec<u8> = row.try_get("identity_data")?;
+                    let own_identity: ReadOnlyUserIdentities = e2e.decode_value(&data)?;
+                    own_identity.own().map_or(false, |own| {
+                        is_signed_by(other.master_key(), own.user_signing_key().as_ref())
+                    })
+                } else {
+                    false
+                }
+            }
+        };
+
+        let change = IdentityChange {
+            user_id: identity.user_id().to_owned(),
+            old_master_key,
+            new_master_key,
+            changed_at: MilliSecondsSinceUnixEpoch::now(),
+            was_verified,
+            acknowledged: false,
+        };
+        let user_id = e2e.encode_key(
+            "cryptostore_identity_change:user_id",
+            identity.user_id().as_bytes(),
+        );
+        DB::identity_change_store_query()
+            .bind(user_id.as_ref())
+            .bind(e2e.encode_value(&change)?)
+            .execute(txn)
+            .await?;
+        Ok(())
+    }
+
+    /// Retrieves the master key changes of a user's identity, oldest first
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn identity_changes(&self, user_id: &UserId) -> Result<Vec<IdentityChange>> {
+        let e2e = self.ensure_e2e()?;
+        let user_id = e2e.encode_key("cryptostore_identity_change:user_id", user_id.as_bytes());
+        let mut rows = DB::identity_changes_fetch_query()
+            .bind(user_id.as_ref())
+            .fetch(&*self.db);
+        let mut changes = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let data: Vec<u8> = row.try_get("change_data")?;
+            let mut change: IdentityChange = e2e.decode_value(&data)?;
+            change.acknowledged = row.try_get("acknowledged")?;
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// Retrieves the identity changes that were not acknowledged yet, oldest first
+    ///
+    /// The users of these changes should be treated as flagged until
+    /// [`acknowledge_identity_changes`](Self::acknowledge_identity_changes) is called.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn unacknowledged_identity_changes(&self) -> Result<Vec<IdentityChange>> {
+        let e2e = self.ensure_e2e()?;
+        let mut rows = DB::identity_changes_unacknowledged_fetch_query().fetch(&*self.db);
+        let mut changes = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let data: Vec<u8> = row.try_get("change_data")?;
+            changes.push(e2e.decode_value(&data)?);
+        }
+        Ok(changes)
+    }
+
+    /// Returns whether a user has identity changes that were not acknowledged yet
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn is_identity_change_flagged(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .identity_changes(user_id)
+            .await?
+            .iter()
+            .any(|change| !change.acknowledged))
+    }
+
+    /// Acknowledges the identity changes of a user, removing the flag
+    ///
+    /// Returns whether there were changes to acknowledge.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn acknowledge_identity_changes(&self, user_id: &UserId) -> Result<bool> {
+        let e2e = self.ensure_e2e()?;
+        let user_id = e2e.encode_key("cryptostore_identity_change:user_id", user_id.as_bytes());
+        let result = DB::identity_changes_acknowledge_query()
+            .bind(user_id.as_ref())
+            .execute(&*self.db)
+            .await?;
+        Ok(DB::rows_affected(&result) > 0)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_crypto::{types::CrossSigningKey, ReadOnlyAccount, ReadOnlyUserIdentities};
+    use ruma::{device_id, user_id, UserId};
+    use serde_json::{json, Value};
+    use vodozemac::Ed25519Keypair;
+
+    use super::canonical_json;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates the JSON of a cross-signing key, signed by `signer` if given
+    fn cross_signing_key(
+        user_id: &UserId,
+        usage: &str,
+        key: &Ed25519Keypair,
+        signer: Option<(&UserId, &Ed25519Keypair)>,
+    ) -> Value {
+        let public_key = key.public_key().to_base64();
+        let mut value = json!({
+            "user_id": user_id,
+            "usage": [usage],
+            "keys": { format!("ed25519:{}", public_key): public_key },
+        });
+        if let Some((signer_id, signer)) = signer {
+            let parsed: CrossSigningKey = serde_json::from_value(value.clone()).unwrap();
+            let signature = signer.sign(canonical_json(&parsed).unwrap().as_bytes());
+            let signer_key = signer.public_key().to_base64();
+            value["signatures"] = json!({
+                signer_id: { format!("ed25519:{}", signer_key): signature.to_base64() }
+            });
+        }
+        value
+    }
+
+    /// Creates our own identity with the given user-signing key
+    fn own_identity(
+        user_id: &UserId,
+        user_signing_key: &Ed25519Keypair,
+        verified: bool,
+    ) -> ReadOnlyUserIdentities {
+        serde_json::from_value(json!({
+            "Own": {
+                "user_id": user_id,
+                "master_key": cross_signing_key(user_id, "master", &Ed25519Keypair::new(), None),
+                "self_signing_key":
+                    cross_signing_key(user_id, "self_signing", &Ed25519Keypair::new(), None),
+                "user_signing_key":
+                    cross_signing_key(user_id, "user_signing", user_signing_key, None),
+                "verified": verified,
+            }
+        }))
+        .unwrap()
+    }
+
+    /// Creates the identity of another user, with its master key signed by `signer` if given
+    fn other_identity(
+        user_id: &UserId,
+        master_key: &Ed25519Keypair,
+        signer: Option<(&UserId, &Ed25519Keypair)>,
+    ) -> ReadOnlyUserIdentities {
+        serde_json::from_value(json!({
+            "Other": {
+                "user_id": user_id,
+                "master_key": cross_signing_key(user_id, "master", master_key, signer),
+                "self_signing_key":
+                    cross_signing_key(user_id, "self_signing", &Ed25519Keypair::new(), None),
+            }
+        }))
+        .unwrap()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_identity_changes() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock().await.unwrap();
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let carol = user_id!("@carol:localhost");
+        store
+            .save_account(ReadOnlyAccount::new(alice, device_id!("ALICEDEVICE")))
+            .await
+            .unwrap();
+        let user_signing_key = Ed25519Keypair::new();
+        let bob_key = Ed25519Keypair::new();
+        let bob_new_key = Ed25519Keypair::new();
+        let carol_key = Ed25519Keypair::new();
+
+        let mut txn = store.db.begin().await.unwrap();
+        for identity in [
+            own_identity(alice, &user_signing_key, true),
+            other_identity(bob, &bob_key, Some((alice, &user_signing_key))),
+            other_identity(carol, &carol_key, None),
+        ] {
+            store
+                .save_crypto_identity(&mut txn, identity)
+                .await
+                .unwrap();
+        }
+        txn.commit().await.unwrap();
+        assert!(store
+            .unacknowledged_identity_changes()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut txn = store.db.begin().await.unwrap();
+        for identity in [
+            other_identity(bob, &bob_new_key, None),
+            other_identity(carol, &carol_key, None),
+            other_identity(carol, &Ed25519Keypair::new(), None),
+            own_identity(alice, &Ed25519Keypair::new(), false),
+        ] {
+            store
+                .save_crypto_identity(&mut txn, identity)
+                .await
+                .unwrap();
+        }
+        txn.commit().await.unwrap();
+
+        let changes = store.identity_changes(bob).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].old_master_key,
+            Some(bob_key.public_key().to_base64())
+        );
+        assert_eq!(
+            changes[0].new_master_key,
+            Some(bob_new_key.public_key().to_base64())
+        );
+        assert!(changes[0].was_verified);
+        assert!(!changes[0].acknowledged);
+        let changes = store.identity_changes(carol).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].was_verified);
+        assert!(store.identity_changes(alice).await.unwrap()[0].was_verified);
+        assert_eq!(
+            store
+                .unacknowledged_identity_changes()
+                .await
+                .unwrap()
+                .iter()
+                .map(|c| c.user_id.as_str())
+                .collect::<Vec<_>>(),
+            ["@bob:localhost", "@carol:localhost", "@alice:localhost"]
+        );
+
+        assert!(store.is_identity_change_flagged(bob).await.unwrap());
+        assert!(store.acknowledge_identity_changes(bob).await.unwrap());
+        assert!(!store.acknowledge_identity_changes(bob).await.unwrap());
+        assert!(!store.is_identity_change_flagged(bob).await.unwrap());
+        assert!(store.identity_changes(bob).await.unwrap()[0].acknowledged);
+        assert_eq!(
+            store.unacknowledged_identity_changes().await.unwrap().len(),
+            2
+        );
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_identity_changes() {
+        let store = crate::schema::postgres_test_store("matrix_identity_changes_test").await;
+        store.unlock().await.unwrap();
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let carol = user_id!("@carol:localhost");
+        store
+            .save_account(ReadOnlyAccount::new(alice, device_id!("ALICEDEVICE")))
+            .await
+            .unwrap();
+        let user_signing_key = Ed25519Keypair::new();
+        let bob_key = Ed25519Keypair::new();
+        let bob_new_key = Ed25519Keypair::new();
+        let carol_key = Ed25519Keypair::new();
+
+        let mut txn = store.db.begin().await.unwrap();
+        for identity in [
+            own_identity(alice, &user_signing_key, true),
+            other_identity(bob, &bob_key, Some((alice, &user_signing_key))),
+            other_identity(carol, &carol_key, None),
+        ] {
+            store
+                .save_crypto_identity(&mut txn, identity)
+                .await
+                .unwrap();
+        }
+        txn.commit().await.unwrap();
+        assert!(store
+            .unacknowledged_identity_changes()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut txn = store.db.begin().await.unwrap();
+        for identity in [
+            other_identity(bob, &bob_new_key, None),
+            other_identity(carol, &carol_key, None),
+            other_identity(carol, &Ed25519Keypair::new(), None),
+            own_identity(alice, &Ed25519Keypair::new(), false),
+        ] {
+            store
+                .save_crypto_identity(&mut txn, identity)
+                .await
+                .unwrap();
+        }
+        txn.commit().await.unwrap();
+
+        let changes = store.identity_changes(bob).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].old_master_key,
+            Some(bob_key.public_key().to_base64())
+        );
+        assert_eq!(
+            changes[0].new_master_key,
+            Some(bob_new_key.public_key().to_base64())
+        );
+        assert!(changes[0].was_verified);
+        assert!(!changes[0].acknowledged);
+        let changes = store.identity_changes(carol).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].was_verified);
+        assert!(store.identity_changes(alice).await.unwrap()[0].was_verified);
+        assert_eq!(
+            store
+                .unacknowledged_identity_changes()
+                .await
+                .unwrap()
+                .iter()
+                .map(|c| c.user_id.as_str())
+                .collect::<Vec<_>>(),
+            ["@bob:localhost", "@carol:localhost", "@alice:localhost"]
+        );
+
+        assert!(store.is_identity_change_flagged(bob).await.unwrap());
+        assert!(store.acknowledge_identity_changes(bob).await.unwrap());
+        assert!(!store.acknowledge_identity_changes(bob).await.unwrap());
+        assert!(!store.is_identity_change_flagged(bob).await.unwrap());
+        assert!(store.identity_changes(bob).await.unwrap()[0].acknowledged);
+        assert_eq!(
+            store.unacknowledged_identity_changes().await.unwrap().len(),
+            2
+        );
+    }
+}
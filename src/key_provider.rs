@@ -0,0 +1,306 @@
+//! Sources for the key that unlocks the cryptostore
+
+use std::{
+    fmt::{self, Write},
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{Result, SQLStoreError};
+
+/// The secret that unlocks the cryptostore
+///
+/// The secret is zeroized when it is dropped.
+#[derive(Clone)]
+pub enum StoreKey {
+    /// A passphrase, stretched with PBKDF2
+    Passphrase(String),
+    /// A raw 256 bit key
+    Key([u8; 32]),
+}
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Passphrase(_) => f.write_str("StoreKey::Passphrase(..)"),
+            Self::Key(_) => f.write_str("StoreKey::Key(..)"),
+        }
+    }
+}
+
+/// Provides the secret that unlocks the cryptostore
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Returns the secret that unlocks the cryptostore
+    ///
+    /// # Errors
+    /// This function will return an error if the secret is not available.
+    async fn store_key(&self) -> Result<StoreKey>;
+}
+
+impl Drop for StoreKey {
+    fn drop(&mut self) {
+        match self {
+            Self::Passphrase(passphrase) => passphrase.zeroize(),
+            Self::Key(key) => key.zeroize(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for StoreKey {
+    async fn store_key(&self) -> Result<StoreKey> {
+        Ok(self.clone())
+    }
+}
+
+/// Returns the passphrase that a raw key protects the store cipher with
+///
+/// matrix-sdk-store-encryption 0.2 can only export the store cipher with a passphrase, so the key
+/// is hex encoded and still goes through PBKDF2. Its entropy makes the key stretching unnecessary,
+/// but harmless.
+pub(crate) fn key_passphrase(key: &[u8; 32]) -> Zeroizing<String> {
+    let mut passphrase = Zeroizing::new(String::with_capacity(64));
+    for byte in key {
+        // Writing to a string cannot fail
+        let _ = write!(passphrase, "{:02x}", byte);
+    }
+    passphrase
+}
+
+/// Decodes a hex encoded 256 bit key
+fn decode_hex_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0_u8; 32];
+    for (byte, chunk) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Reads a hex encoded 256 bit key from an environment variable
+#[derive(Clone, Debug)]
+pub struct EnvKeyProvider {
+    /// The name of the environment variable
+    variable: String,
+}
+
+impl EnvKeyProvider {
+    /// Creates a provider that reads the key from the given environment variable
+    #[must_use]
+    pub fn new(variable: impl Into<String>) -> Self {
+        Self {
+            variable: variable.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for EnvKeyProvider {
+    async fn store_key(&self) -> Result<StoreKey> {
+        let value = std::env::var(&self.variable)
+            .map_err(|e| SQLStoreError::InvalidKey(format!("{}: {}", self.variable, e)))?;
+        decode_hex_key(&value).map(StoreKey::Key).ok_or_else(|| {
+            SQLStoreError::InvalidKey(format!(
+                "{} does not contain a hex encoded 32 byte key",
+                self.variable
+            ))
+        })
+    }
+}
+
+/// Reads a 256 bit key from a file
+///
+/// The file contains either the 32 bytes of the key, or the key hex encoded.
+#[derive(Clone, Debug)]
+pub struct FileKeyProvider {
+    /// The path of the key file
+    path: PathBuf,
+}
+
+impl FileKeyProvider {
+    /// Creates a provider that reads the key from the given file
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for FileKeyProvider {
+    async fn store_key(&self) -> Result<StoreKey> {
+        let contents = Zeroizing::new(std::fs::read(&self.path)?);
+        let key = <[u8; 32]>::try_from(contents.as_slice())
+            .ok()
+            .or_else(|| std::str::from_utf8(&contents).ok().and_then(decode_hex_key));
+        key.map(StoreKey::Key).ok_or_else(|| {
+            SQLStoreError::InvalidKey(format!(
+                "{} does not contain a 32 byte key",
+                self.path.display()
+            ))
+        })
+    }
+}
+
+/// Calls a function to obtain the secret, for example to ask a secret manager
+pub struct CallbackKeyProvider<F> {
+    /// The function returning the secret
+    callback: F,
+}
+
+impl<F> CallbackKeyProvider<F>
+where
+    F: Fn() -> Result<StoreKey> + Send + Sync,
+{
+    /// Creates a provider that calls the given function
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> fmt::Debug for CallbackKeyProvider<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackKeyProvider")
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F> KeyProvider for CallbackKeyProvider<F>
+where
+    F: Fn() -> Result<StoreKey> + Send + Sync,
+{
+    async fn store_key(&self) -> Result<StoreKey> {
+        (self.callback)()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use ruma::{device_id, events::secret::request::SecretName, user_id, TransactionId};
+
+    use super::{
+        decode_hex_key, key_passphrase, CallbackKeyProvider, EnvKeyProvider, FileKeyProvider,
+        KeyProvider, StoreKey,
+    };
+    use crate::{ReceivedSecret, SQLStoreError, StateStore};
+
+    const KEY: [u8; 32] = [0xab; 32];
+
+    #[tokio::test]
+    async fn test_key_providers() {
+        let hex = key_passphrase(&KEY).as_str().to_owned();
+        assert_eq!(decode_hex_key(&format!("{}\n", hex)), Some(KEY));
+        assert_eq!(decode_hex_key(&hex[1..]), None);
+        assert_eq!(decode_hex_key(&"zz".repeat(32)), None);
+
+        std::env::set_var("MATRIX_SDK_SQL_TEST_KEY", &hex);
+        let key = EnvKeyProvider::new("MATRIX_SDK_SQL_TEST_KEY")
+            .store_key()
+            .await
+            .unwrap();
+        assert!(matches!(key, StoreKey::Key(k) if k == KEY));
+        assert!(matches!(
+            EnvKeyProvider::new("MATRIX_SDK_SQL_MISSING_KEY")
+                .store_key()
+                .await,
+            Err(SQLStoreError::InvalidKey(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("raw");
+        std::fs::write(&raw, KEY).unwrap();
+        let encoded = dir.path().join("hex");
+        std::fs::write(&encoded, format!("{}\n", hex)).unwrap();
+        let invalid = dir.path().join("invalid");
+        std::fs::write(&invalid, "secret").unwrap();
+        for path in [&raw, &encoded] {
+            let key = FileKeyProvider::new(path).store_key().await.unwrap();
+            assert!(matches!(key, StoreKey::Key(k) if k == KEY));
+        }
+        assert!(matches!(
+            FileKeyProvider::new(&invalid).store_key().await,
+            Err(SQLStoreError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            FileKeyProvider::new(dir.path().join("missing"))
+                .store_key()
+                .await,
+            Err(SQLStoreError::Io(_))
+        ));
+
+        let callback = CallbackKeyProvider::new(|| Ok(StoreKey::Passphrase("secret".to_owned())));
+        let key = callback.store_key().await.unwrap();
+        assert!(matches!(&key, StoreKey::Passphrase(p) if p == "secret"));
+        assert_eq!(format!("{:?}", key), "StoreKey::Passphrase(..)");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_unlock_with_key() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock_with_key(&KEY).await.unwrap();
+        let secret = ReceivedSecret {
+            secret_name: SecretName::RecoveryKey,
+            request_id: TransactionId::new(),
+            sender: user_id!("@alice:localhost").to_owned(),
+            sender_device: device_id!("ALICEDEVICE").to_owned(),
+            secret: "backup key".to_owned().into(),
+        };
+        store.save_secret_to_inbox(&secret).await.unwrap();
+
+        let store = StateStore::new(&db).await.unwrap();
+        assert!(store.unlock_with_key(&[0; 32]).await.is_err());
+        store
+            .unlock_with_key_provider(&CallbackKeyProvider::new(|| Ok(StoreKey::Key(KEY))))
+            .await
+            .unwrap();
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::RecoveryKey)
+            .await
+            .unwrap();
+        assert_eq!(secrets[0].secret.as_str(), "backup key");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_unlock_with_key() {
+        let store = crate::schema::postgres_test_store("key_provider").await;
+        let db = Arc::clone(&store.db);
+        store.unlock_with_key(&KEY).await.unwrap();
+        let secret = ReceivedSecret {
+            secret_name: SecretName::RecoveryKey,
+            request_id: TransactionId::new(),
+            sender: user_id!("@alice:localhost").to_owned(),
+            sender_device: device_id!("ALICEDEVICE").to_owned(),
+            secret: "backup key".to_owned().into(),
+        };
+        store.save_secret_to_inbox(&secret).await.unwrap();
+
+        let store = StateStore::new(&db).await.unwrap();
+        assert!(store.unlock_with_key(&[0; 32]).await.is_err());
+        store
+            .unlock_with_key_provider(&CallbackKeyProvider::new(|| Ok(StoreKey::Key(KEY))))
+            .await
+            .unwrap();
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::RecoveryKey)
+            .await
+            .unwrap();
+        assert_eq!(secrets[0].secret.as_str(), "backup key");
+    }
+}
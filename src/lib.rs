@@ -25,7 +25,7 @@
 //! Before you can use cryptostore functionality, you need to unlock the cryptostore:
 //!
 //! ```rust,ignore
-//! let mut state_store = /* as above */;
+//! let state_store = /* as above */;
 //!
 //! state_store.unlock_with_passphrase(std::env::var("MYAPP_SECRET_KEY")?).await?;
 //! ```
@@ -36,25 +36,93 @@
 //!
 //! Make sure to set `ignore_missing` to true in your migrator, otherwise the migration will not find the migrations in this repository and fail.
 //!
+//! On postgres you can instead keep the store in a dedicated schema, which also keeps its `_sqlx_migrations` table apart from the one of your application:
+//!
+//! ```rust,ignore
+//! let options = matrix_sdk_sql::postgres_schema_options(PgConnectOptions::from_str(url)?, "matrix")?;
+//! let sql_pool = Arc::new(PgPoolOptions::new().connect_with(options).await?);
+//! let state_store = StateStore::new_in_schema(&sql_pool, "matrix").await?;
+//! ```
+//!
+//! On sqlite, use a separate database file for the store instead.
+//!
+//! ### Applying migrations separately
+//!
+//! If the database user of your application is not allowed to perform DDL, apply the migrations from a separate deploy job and open the store with [`StateStore::open_existing`]. It verifies that the schema is up to date and fails with [`SQLStoreError::SchemaMismatch`] otherwise.
+//!
 //! ## About Trait bounds
 //!
 //! The list of trait bounds may seem daunting, however all enabled database backends are supported.
 
 use std::sync::Arc;
+#[cfg(feature = "e2e-encryption")]
+use std::time::Duration;
 
 // These crate imports are due to bugs, regressions, etc
+#[cfg(feature = "sqlcipher")]
+use libsqlite3_sys as _;
 use sqlx_core as _;
 use tracing as _;
 
 #[cfg(feature = "e2e-encryption")]
-use cryptostore::CryptostoreData;
+use cryptostore::{CryptostoreData, CryptostoreLock};
 use helpers::{BorrowedSqlType, SqlType};
 use matrix_sdk_base::store::StoreConfig;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_store_encryption::StoreCipher;
 
+mod checkpoints;
+pub use checkpoints::SyncCheckpoint;
+mod connect;
+pub use connect::{connect, ConnectOptions};
+mod custom;
 mod helpers;
 pub use helpers::SupportedDatabase;
+mod history;
+pub use history::{MembershipChange, RoomState, StateChange};
+#[cfg(feature = "e2e-encryption")]
+mod key_provider;
+#[cfg(feature = "e2e-encryption")]
+pub use key_provider::{
+    CallbackKeyProvider, EnvKeyProvider, FileKeyProvider, KeyProvider, StoreKey,
+};
+#[cfg(feature = "e2e-encryption")]
+mod identity_changes;
+#[cfg(feature = "e2e-encryption")]
+pub use identity_changes::IdentityChange;
+mod maintenance;
+#[cfg(feature = "e2e-encryption")]
+pub use maintenance::CryptoStats;
+pub use maintenance::{
+    MaintenanceReport, MediaCacheStats, OrphanedRows, RoomStats, StoreStats, TableStats,
+};
+mod members;
+pub use members::{MemberCursor, MemberFilter, MemberMatch, MemberPage, MemberSort};
+mod receipts;
+pub use receipts::{ReceiptThread, UnreadCounts};
+#[cfg(feature = "e2e-encryption")]
+mod room_key_shares;
+#[cfg(feature = "e2e-encryption")]
+pub use room_key_shares::RoomKeyShare;
+#[cfg(feature = "e2e-encryption")]
+mod room_keys;
+#[cfg(feature = "e2e-encryption")]
+mod room_settings;
+#[cfg(feature = "e2e-encryption")]
+pub use room_settings::RoomSettings;
+mod rooms;
+pub use rooms::{RoomCursor, RoomFilter, RoomListPage, RoomSort};
+mod schema;
+#[cfg(feature = "e2e-encryption")]
+mod secret_inbox;
+#[cfg(feature = "e2e-encryption")]
+pub use secret_inbox::ReceivedSecret;
+mod snapshot;
+mod spaces;
+#[cfg(feature = "sqlcipher")]
+mod sqlcipher;
+#[cfg(feature = "e2e-encryption")]
+mod withheld;
 use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
 use ruma::{
     events::{
@@ -66,11 +134,19 @@ use ruma::{
     },
     serde::Raw,
 };
+#[cfg(feature = "postgres")]
+pub use schema::postgres_schema_options;
+pub use schema::{MigrationInfo, SchemaMismatch};
+pub use spaces::{SpaceChild, SpaceParent, SpaceTreeEntry};
+#[cfg(feature = "sqlcipher")]
+pub use sqlcipher::sqlcipher_options;
 use sqlx::{
     database::HasArguments, migrate::Migrate, types::Json, ColumnIndex, Database, Executor,
     IntoArguments, Pool, Transaction,
 };
 use thiserror::Error;
+#[cfg(feature = "e2e-encryption")]
+pub use withheld::{RoomKeyWithheld, WithheldCode};
 
 #[cfg(feature = "e2e-encryption")]
 mod cryptostore;
@@ -83,13 +159,61 @@ pub enum SQLStoreError {
     /// Database error
     #[error("Database error: {0}")]
     Database(#[from] sqlx::Error),
+    /// The database URL has a scheme of a backend that is not supported or not enabled
+    #[error("Unsupported database URL scheme {0:?}")]
+    UnsupportedUrlScheme(String),
     /// Migration failed
     #[error("Migration for database failed: {0}")]
     Migration(#[from] sqlx::migrate::MigrateError),
+    /// The database schema does not match the migrations shipped with this crate
+    #[error("Database schema does not match: {0}")]
+    SchemaMismatch(SchemaMismatch),
+    /// The migration version is not known
+    #[error("Unknown migration version {0}")]
+    UnknownMigration(i64),
+    /// Reverting a destructive migration would remove data
+    #[error(
+        "Reverting migration {version} would delete data in {tables:?}, use force to revert anyway"
+    )]
+    DataLoss {
+        /// The migration that would remove data
+        version: i64,
+        /// The tables and columns destroyed by the down migration that hold data
+        tables: Vec<String>,
+    },
+    /// The schema name cannot be used
+    #[cfg(feature = "postgres")]
+    #[error("Invalid schema name {0:?}, expected a lowercase identifier")]
+    InvalidSchemaName(String),
+    /// The connection pool does not use the requested schema
+    #[cfg(feature = "postgres")]
+    #[error("The connections do not use schema {0:?}, configure the pool with `postgres_schema_options`")]
+    SchemaNotSelected(String),
     /// Database is still locked
     #[cfg(feature = "e2e-encryption")]
     #[error("A cryptostore access ocurred without the database being unlocked")]
     DatabaseLocked,
+    /// A room list cursor was used with a different sort order than it was created for
+    #[error("The cursor does not belong to the requested room order")]
+    InvalidCursor,
+    /// The sync checkpoint to roll back to does not exist
+    #[error("Unknown sync checkpoint {0}")]
+    UnknownCheckpoint(i64),
+    /// The snapshot was taken at a different migration version than the store is at
+    #[error("The snapshot was taken at migration {snapshot}, but the store is at {store}")]
+    SnapshotVersion {
+        /// The migration version of the snapshot
+        snapshot: i64,
+        /// The migration version of the store
+        store: i64,
+    },
+    /// The snapshot file cannot be restored
+    #[cfg(feature = "postgres")]
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    /// Reading or writing a file failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
     /// An UTF-8 string has failed to decode
     #[error("Data failed to decode: UTF-8 error {0}")]
     DecodeUtf8(#[from] std::string::FromUtf8Error),
@@ -104,6 +228,17 @@ pub enum SQLStoreError {
     #[cfg(feature = "e2e-encryption")]
     #[error("Failed to encode/decode data as bincode: {0}")]
     Bincode(#[from] bincode::Error),
+    /// A room key export failed to decrypt
+    #[cfg(feature = "e2e-encryption")]
+    #[error("Failed to decrypt the room key export: {0}")]
+    KeyExport(#[from] matrix_sdk_crypto::KeyExportError),
+    /// The key to unlock the cryptostore is missing or malformed
+    #[cfg(feature = "e2e-encryption")]
+    #[error("Invalid store key: {0}")]
+    InvalidKey(String),
+    /// A stored event could not be redacted
+    #[error("Failed to redact event: {0}")]
+    Redaction(#[from] ruma::canonical_json::RedactionError),
     /// Failed to decode a JSON value
     #[error("Failed to encode/decode data as json: {0}")]
     Json(#[from] serde_json::Error),
@@ -131,8 +266,8 @@ pub struct StateStore<DB: SupportedDatabase> {
     /// The database connection
     db: Arc<Pool<DB>>,
     #[cfg(feature = "e2e-encryption")]
-    /// Extra cryptostore data
-    cryptostore: Option<CryptostoreData>,
+    /// Extra cryptostore data and its lock state
+    cryptostore: Arc<CryptostoreLock>,
 }
 
 #[allow(single_use_lifetimes)]
@@ -144,39 +279,105 @@ impl<DB: SupportedDatabase> StateStore<DB> {
     pub async fn new(db: &Arc<Pool<DB>>) -> Result<Self>
     where
         <DB as Database>::Connection: Migrate,
+        for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+        for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+        for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+        Vec<u8>: SqlType<DB>,
+        Option<String>: SqlType<DB>,
+        String: SqlType<DB>,
+        for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
     {
-        let db = Arc::clone(db);
         let migrator = DB::get_migrator();
-        migrator.run(&*db).await?;
+        migrator.run(&**db).await?;
+        members::normalize_member_search(db).await?;
+        Ok(Self::from_pool(db))
+    }
+
+    /// Opens an existing State Store without performing migrations
+    ///
+    /// Use this if the migrations are applied by a separate process, and the database user lacks
+    /// the permissions to perform DDL.
+    ///
+    /// # Errors
+    /// This function will return an error if any of the migrations shipped with this crate has
+    /// not been applied, or if an applied migration differs from the shipped one.
+    pub async fn open_existing(db: &Arc<Pool<DB>>) -> Result<Self>
+    where
+        <DB as Database>::Connection: Migrate,
+        for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+        for<'c> &'c mut <DB as Database>::Connection: Executor<'c, Database = DB>,
+        for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+        for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+        Vec<u8>: SqlType<DB>,
+        Option<String>: SqlType<DB>,
+        String: SqlType<DB>,
+        for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+    {
+        schema::verify_schema(db).await?;
+        members::normalize_member_search(db).await?;
+        Ok(Self::from_pool(db))
+    }
+
+    /// Creates the store struct for a database pool
+    fn from_pool(db: &Arc<Pool<DB>>) -> Self {
+        let db = Arc::clone(db);
         #[cfg(not(feature = "e2e-encryption"))]
         {
-            Ok(Self { db })
+            Self { db }
         }
         #[cfg(feature = "e2e-encryption")]
         {
-            Ok(Self {
+            Self {
                 db,
-                cryptostore: None,
-            })
+                cryptostore: Arc::new(CryptostoreLock::new()),
+            }
         }
     }
 
-    /// Returns a reference to the cryptostore specific data if the store has been unlocked
+    /// Returns the cryptostore specific data if the store has been unlocked
+    ///
+    /// Every call counts as an access for the idle lock timeout.
     ///
     /// # Errors
     /// This function will return an error if the database has not been unlocked
     #[cfg(feature = "e2e-encryption")]
-    pub(crate) fn ensure_e2e(&self) -> Result<&CryptostoreData> {
-        self.cryptostore
-            .as_ref()
-            .ok_or(SQLStoreError::DatabaseLocked)
+    pub(crate) fn ensure_e2e(&self) -> Result<Arc<CryptostoreData>> {
+        self.cryptostore.access()
+    }
+
+    /// Replaces the cryptostore specific data, unlocking the store
+    #[cfg(feature = "e2e-encryption")]
+    fn set_e2e(&self, data: CryptostoreData) {
+        self.cryptostore.unlock(data);
+    }
+
+    /// Locks the e2e encryption database
+    ///
+    /// The cached account, sessions, group sessions, devices and tracked users are cleared, and
+    /// the store cipher is dropped, which zeroizes its keys. Operations that are still running
+    /// keep the store cipher until they finish. The store has to be unlocked again before the
+    /// cryptostore can be used.
+    #[cfg(feature = "e2e-encryption")]
+    pub fn lock(&self) {
+        self.cryptostore.lock();
+    }
+
+    /// Sets the idle time after which the e2e encryption database locks itself
+    ///
+    /// A timer task on the current tokio runtime locks the store once the cryptostore has not
+    /// been accessed for the given time. Reading the [`StateStore::stats`] does not count as an
+    /// access. Without a tokio runtime, the timeout is only checked on the next access. `None`
+    /// disables the automatic lock, which is the default.
+    #[cfg(feature = "e2e-encryption")]
+    pub fn set_idle_lock_timeout(&self, timeout: Option<Duration>) {
+        self.cryptostore.set_idle_timeout(timeout);
     }
 
     /// Unlocks the e2e encryption database
     /// # Errors
     /// This function will fail if the database could not be unlocked
     #[cfg(feature = "e2e-encryption")]
-    pub async fn unlock(&mut self) -> Result<()>
+    pub async fn unlock(&self) -> Result<()>
     where
         for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
         for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
@@ -186,6 +387,7 @@ impl<DB: SupportedDatabase> StateStore<DB> {
         Vec<u8>: SqlType<DB>,
         String: SqlType<DB>,
         bool: SqlType<DB>,
+        i64: SqlType<DB>,
         Vec<u8>: SqlType<DB>,
         Option<String>: SqlType<DB>,
         Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
@@ -201,7 +403,7 @@ impl<DB: SupportedDatabase> StateStore<DB> {
         Json<MemberEvent>: SqlType<DB>,
         for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
     {
-        self.cryptostore = Some(CryptostoreData::new_unencrypted());
+        self.set_e2e(CryptostoreData::new_unencrypted());
         self.load_tracked_users().await?;
         Ok(())
     }
@@ -210,7 +412,7 @@ impl<DB: SupportedDatabase> StateStore<DB> {
     /// # Errors
     /// This function will fail if the passphrase is wrong
     #[cfg(feature = "e2e-encryption")]
-    pub async fn unlock_with_passphrase(&mut self, passphrase: &str) -> Result<()>
+    pub async fn unlock_with_passphrase(&self, passphrase: &str) -> Result<()>
     where
         for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
         for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
@@ -220,6 +422,7 @@ impl<DB: SupportedDatabase> StateStore<DB> {
         Vec<u8>: SqlType<DB>,
         String: SqlType<DB>,
         bool: SqlType<DB>,
+        i64: SqlType<DB>,
         Vec<u8>: SqlType<DB>,
         Option<String>: SqlType<DB>,
         Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
@@ -239,7 +442,7 @@ impl<DB: SupportedDatabase> StateStore<DB> {
 
         let cipher_export = self.get_kv(b"cipher").await?;
         if let Some(cipher) = cipher_export {
-            self.cryptostore = Some(CryptostoreData::new(StoreCipher::import(
+            self.set_e2e(CryptostoreData::new(StoreCipher::import(
                 passphrase, &cipher,
             )?));
         } else {
@@ -247,11 +450,90 @@ impl<DB: SupportedDatabase> StateStore<DB> {
             let cipher = StoreCipher::new()?;
             self.insert_kv(b"cipher", &cipher.export(passphrase)?)
                 .await?;
-            self.cryptostore = Some(CryptostoreData::new(cipher));
+            self.set_e2e(CryptostoreData::new(cipher));
         }
         self.load_tracked_users().await?;
         Ok(())
     }
+
+    /// Unlocks the e2e encryption database with a raw 256 bit key
+    ///
+    /// matrix-sdk-store-encryption 0.2 can only export the store cipher with a passphrase, so the
+    /// key is hex encoded and used as the passphrase, including the PBKDF2 key stretching. The
+    /// store is therefore also unlocked by [`StateStore::unlock_with_passphrase`] with the hex
+    /// encoded key, and a store that was first unlocked with a key must always be unlocked with
+    /// the same key.
+    /// # Errors
+    /// This function will fail if the key is wrong
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn unlock_with_key(&self, key: &[u8; 32]) -> Result<()>
+    where
+        for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+        for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+        for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+        for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+        for<'a> &'a str: BorrowedSqlType<'a, DB>,
+        Vec<u8>: SqlType<DB>,
+        String: SqlType<DB>,
+        bool: SqlType<DB>,
+        i64: SqlType<DB>,
+        Vec<u8>: SqlType<DB>,
+        Option<String>: SqlType<DB>,
+        Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+        Json<Raw<PresenceEvent>>: SqlType<DB>,
+        Json<SyncRoomMemberEvent>: SqlType<DB>,
+        Json<MinimalRoomMemberEvent>: SqlType<DB>,
+        Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+        Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+        Json<RoomInfo>: SqlType<DB>,
+        Json<Receipt>: SqlType<DB>,
+        Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+        Json<StrippedRoomMemberEvent>: SqlType<DB>,
+        Json<MemberEvent>: SqlType<DB>,
+        for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+    {
+        self.unlock_with_passphrase(&key_provider::key_passphrase(key))
+            .await
+    }
+
+    /// Unlocks the e2e encryption database with the secret returned by a key provider
+    /// # Errors
+    /// This function will fail if the provider fails or the secret is wrong
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn unlock_with_key_provider(
+        &self,
+        provider: &(impl KeyProvider + ?Sized),
+    ) -> Result<()>
+    where
+        for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+        for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+        for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+        for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+        for<'a> &'a str: BorrowedSqlType<'a, DB>,
+        Vec<u8>: SqlType<DB>,
+        String: SqlType<DB>,
+        bool: SqlType<DB>,
+        i64: SqlType<DB>,
+        Vec<u8>: SqlType<DB>,
+        Option<String>: SqlType<DB>,
+        Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+        Json<Raw<PresenceEvent>>: SqlType<DB>,
+        Json<SyncRoomMemberEvent>: SqlType<DB>,
+        Json<MinimalRoomMemberEvent>: SqlType<DB>,
+        Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+        Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+        Json<RoomInfo>: SqlType<DB>,
+        Json<Receipt>: SqlType<DB>,
+        Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+        Json<StrippedRoomMemberEvent>: SqlType<DB>,
+        Json<MemberEvent>: SqlType<DB>,
+        for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+    {
+        match &provider.store_key().await? {
+            StoreKey::Passphrase(passphrase) => self.unlock_with_passphrase(passphrase).await,
+            StoreKey::Key(key) => self.unlock_with_key(key).await,
+        }
+    }
 }
 
 /// Creates a new store confiig
@@ -273,6 +555,7 @@ where
     Vec<u8>: SqlType<DB>,
     String: SqlType<DB>,
     bool: SqlType<DB>,
+    i64: SqlType<DB>,
     Vec<u8>: SqlType<DB>,
     Option<String>: SqlType<DB>,
     Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
@@ -296,15 +579,57 @@ where
     }
     #[cfg(feature = "e2e-encryption")]
     {
-        let state_store = StateStore::new(db).await?;
-        let mut crypto_store = StateStore::new(db).await?;
-        if let Some(passphrase) = passphrase {
-            crypto_store.unlock_with_passphrase(passphrase).await?;
-        } else {
-            crypto_store.unlock().await?;
-        }
-        Ok(StoreConfig::new()
-            .state_store(state_store)
-            .crypto_store(crypto_store))
+        let key = passphrase.map(|passphrase| StoreKey::Passphrase(passphrase.to_owned()));
+        store_config_with_key_provider(db, key.as_ref().map(|key| key as &dyn KeyProvider)).await
+    }
+}
+
+/// Creates a new store config, unlocking the cryptostore with the secret of a key provider
+///
+/// Without a key provider, the cryptostore is left unencrypted.
+///
+/// # Errors
+/// This function will return an error if the migration cannot be applied,
+/// or if the key provider fails or returns an incorrect secret
+#[cfg(feature = "e2e-encryption")]
+pub async fn store_config_with_key_provider<DB: SupportedDatabase>(
+    db: &Arc<Pool<DB>>,
+    key_provider: Option<&dyn KeyProvider>,
+) -> Result<StoreConfig>
+where
+    <DB as Database>::Connection: Migrate,
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    let state_store = StateStore::new(db).await?;
+    let crypto_store = StateStore::new(db).await?;
+    if let Some(key_provider) = key_provider {
+        crypto_store.unlock_with_key_provider(key_provider).await?;
+    } else {
+        crypto_store.unlock().await?;
     }
+    Ok(StoreConfig::new()
+        .state_store(state_store)
+        .crypto_store(crypto_store))
 }
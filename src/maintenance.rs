@@ -0,0 +1,445 @@
+//! Statistics and maintenance routines
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    statestore::MEDIA_CACHE_LIMIT,
+    Result, StateStore, SupportedDatabase,
+};
+
+/// The tables created by the migrations of this crate
+pub(crate) const TABLES: &[&str] = &[
+    "statestore_kv",
+    "statestore_media",
+    "statestore_rooms",
+    "statestore_accountdata",
+    "statestore_presence",
+    "statestore_members",
+    "statestore_state",
+    "statestore_receipts",
+    "statestore_state_history",
+    "statestore_membership_history",
+    "statestore_room_tags",
+    "statestore_power_levels",
+    "statestore_space_edges",
+    "statestore_checkpoints",
+    "statestore_journal",
+    "cryptostore_session",
+    "cryptostore_message_hash",
+    "cryptostore_inbound_group_session",
+    "cryptostore_outbound_group_session",
+    "cryptostore_gossip_request",
+    "cryptostore_identity",
+    "cryptostore_device",
+    "cryptostore_tracked_user",
+    "cryptostore_secret_inbox",
+    "cryptostore_room_key_withheld",
+    "cryptostore_room_settings",
+    "cryptostore_outbound_session_share",
+    "cryptostore_identity_change",
+];
+
+/// Statistics for a single table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableStats {
+    /// The name of the table
+    pub name: &'static str,
+    /// The number of rows in the table
+    pub rows: i64,
+    /// The approximate size of the table and its indexes in bytes, if the database reports it
+    pub bytes: Option<i64>,
+}
+
+/// Usage of the media cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaCacheStats {
+    /// The number of cached media files
+    pub entries: i64,
+    /// The total size of the cached media files in bytes
+    pub bytes: i64,
+    /// The maximum number of cached media files
+    pub limit: i64,
+}
+
+/// Number of stored rooms
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomStats {
+    /// Rooms with full room info
+    pub full: i64,
+    /// Rooms only known from stripped state, like invites
+    pub partial: i64,
+}
+
+/// Statistics about the cryptostore
+#[cfg(feature = "e2e-encryption")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CryptoStats {
+    /// Tracked users whose device list is outdated
+    pub tracked_users_dirty: usize,
+    /// Tracked users whose device list is up to date
+    pub tracked_users_clean: usize,
+    /// The number of inbound group sessions
+    pub inbound_group_sessions: usize,
+    /// The number of inbound group sessions that have been backed up
+    pub inbound_group_sessions_backed_up: usize,
+    /// Gossip requests that have not been sent out yet
+    pub pending_gossip_requests: i64,
+}
+
+/// Statistics about the store
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StoreStats {
+    /// Per-table statistics
+    pub tables: Vec<TableStats>,
+    /// Usage of the media cache
+    pub media: MediaCacheStats,
+    /// Number of stored rooms
+    pub rooms: RoomStats,
+    /// Cryptostore statistics, if the cryptostore is unlocked
+    #[cfg(feature = "e2e-encryption")]
+    pub crypto: Option<CryptoStats>,
+}
+
+/// Rows that refer to a room that is no longer stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrphanedRows {
+    /// The name of the table
+    pub table: &'static str,
+    /// The number of orphaned rows
+    pub rows: i64,
+}
+
+/// Results of a maintenance run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MaintenanceReport {
+    /// Problems reported by the integrity check, or `None` if the database has none
+    pub integrity_errors: Option<Vec<String>>,
+    /// Tables containing orphaned rows
+    pub orphaned_rows: Vec<OrphanedRows>,
+    /// Whether the orphaned rows have been deleted
+    pub repaired: bool,
+}
+
+impl MaintenanceReport {
+    /// Returns true if no problems have been found
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.orphaned_rows.is_empty()
+            && self
+                .integrity_errors
+                .as_ref()
+                .map_or(true, |errors| errors.is_empty())
+    }
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<i64>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Returns row counts, sizes and usage statistics of the store
+    ///
+    /// Table sizes are approximate. On sqlite they require the `dbstat` virtual table, and are
+    /// reported as `None` if it is not available.
+    ///
+    /// # Errors
+    /// This function will return an error if any of the queries fail
+    pub async fn stats(&self) -> Result<StoreStats> {
+        let mut tables = Vec::with_capacity(TABLES.len());
+        for &name in TABLES {
+            tables.push(self.table_stats(name).await?);
+        }
+
+        let row = DB::media_stats_query().fetch_one(&*self.db).await?;
+        let media = MediaCacheStats {
+            entries: row.try_get("entries")?,
+            bytes: row.try_get("bytes")?,
+            limit: MEDIA_CACHE_LIMIT,
+        };
+
+        let mut rooms = RoomStats::default();
+        let mut rows = DB::room_counts_query().fetch(&*self.db);
+        while let Some(row) = rows.try_next().await? {
+            let is_partial: bool = row.try_get("is_partial")?;
+            let count: i64 = row.try_get("count")?;
+            if is_partial {
+                rooms.partial += count;
+            } else {
+                rooms.full += count;
+            }
+        }
+        drop(rows);
+
+        Ok(StoreStats {
+            tables,
+            media,
+            rooms,
+            #[cfg(feature = "e2e-encryption")]
+            crypto: self.crypto_stats().await?,
+        })
+    }
+
+    /// Runs database maintenance and checks the store for inconsistencies
+    ///
+    /// On sqlite, this checks the integrity of the database file and runs `VACUUM` and
+    /// `ANALYZE`. On postgres, this runs `VACUUM ANALYZE`. On both, rows belonging to rooms that
+    /// are no longer stored are reported, and deleted if `repair` is set.
+    ///
+    /// Vacuuming rewrites the database, so this should be run while the store is idle.
+    ///
+    /// # Errors
+    /// This function will return an error if any of the queries fail
+    pub async fn maintenance(&self, repair: bool) -> Result<MaintenanceReport> {
+        let mut report = MaintenanceReport::default();
+
+        if let Some(query) = DB::integrity_check_query() {
+            let mut errors = Vec::new();
+            let mut rows = query.fetch(&*self.db);
+            while let Some(row) = rows.try_next().await? {
+                let message: String = row.try_get("integrity_check")?;
+                if message != "ok" {
+                    errors.push(message);
+                }
+            }
+            report.integrity_errors = Some(errors);
+        }
+
+        let mut txn = self.db.begin().await?;
+        for (table, query) in DB::orphan_count_queries() {
+            let rows: i64 = query.fetch_one(&mut txn).await?.try_get("count")?;
+            if rows > 0 {
+                report.orphaned_rows.push(OrphanedRows { table, rows });
+            }
+        }
+        if repair && !report.orphaned_rows.is_empty() {
+            for query in DB::orphan_delete_queries() {
+                query.execute(&mut txn).await?;
+            }
+            report.repaired = true;
+        }
+        txn.commit().await?;
+
+        DB::vacuum_query().execute(&*self.db).await?;
+        Ok(report)
+    }
+
+    /// Returns the statistics for a single table
+    ///
+    /// # Errors
+    /// This function will return an error if the table cannot be counted
+    async fn table_stats(&self, name: &'static str) -> Result<TableStats> {
+        let query = format!("SELECT COUNT(*) AS count FROM {}", name);
+        let rows: i64 = sqlx::query(&query)
+            .fetch_one(&*self.db)
+            .await?
+            .try_get("count")?;
+        let bytes = match DB::table_size_query().bind(name).fetch_one(&*self.db).await {
+            Ok(row) => row.try_get("bytes")?,
+            // sqlite without the dbstat virtual table
+            Err(sqlx::Error::Database(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(TableStats { name, rows, bytes })
+    }
+
+    /// Returns the cryptostore statistics, or `None` if the store is locked
+    ///
+    /// # Errors
+    /// This function will return an error if any of the queries fail
+    #[cfg(feature = "e2e-encryption")]
+    async fn crypto_stats(&self) -> Result<Option<CryptoStats>> {
+        // Reading the statistics does not keep the cryptostore from locking itself when idle
+        let e2e = match self.cryptostore.peek() {
+            Some(e2e) => e2e,
+            None => return Ok(None),
+        };
+        let tracked_users_dirty = e2e.users_for_key_query.len();
+        let tracked_users_clean = e2e.tracked_users.len().saturating_sub(tracked_users_dirty);
+        let counts = self.count_inbound_group_sessions(e2e).await?;
+        let pending_gossip_requests: i64 = DB::gossip_requests_count_query()
+            .bind(false)
+            .fetch_one(&*self.db)
+            .await?
+            .try_get("count")?;
+        Ok(Some(CryptoStats {
+            tracked_users_dirty,
+            tracked_users_clean,
+            inbound_group_sessions: counts.total,
+            inbound_group_sessions_backed_up: counts.backed_up,
+            pending_gossip_requests,
+        }))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use ruma::MxcUri;
+
+    use super::{OrphanedRows, TABLES};
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Inserts a member of a room that is not stored
+    const ORPHAN_MEMBER: &str = r#"
+        INSERT INTO statestore_members (room_id, user_id, is_partial)
+        VALUES ('!orphan:localhost', '@alice:localhost', FALSE)
+    "#;
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_stats() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.tables.len(), TABLES.len());
+        // Only the member search normalization version is stored
+        assert!(stats
+            .tables
+            .iter()
+            .all(|t| t.rows == i64::from(t.name == "statestore_kv")));
+        assert_eq!(stats.media.entries, 0);
+        assert_eq!(stats.rooms.full + stats.rooms.partial, 0);
+        #[cfg(feature = "e2e-encryption")]
+        assert_eq!(stats.crypto, None);
+
+        let entry = <&MxcUri>::from("mxc://localhost:8080/media/0");
+        store.insert_media(entry, b"0123456789").await.unwrap();
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.media.entries, 1);
+        assert_eq!(stats.media.bytes, 10);
+        assert_eq!(stats.media.limit, 100);
+        let media = stats.tables.iter().find(|t| t.name == "statestore_media");
+        assert_eq!(media.unwrap().rows, 1);
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_stats() {
+        let store = crate::schema::postgres_test_store("matrix_stats_test").await;
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.tables.len(), TABLES.len());
+        // Only the member search normalization version is stored
+        assert!(stats
+            .tables
+            .iter()
+            .all(|t| t.rows == i64::from(t.name == "statestore_kv")));
+        assert!(stats.tables.iter().all(|t| t.bytes.is_some()));
+        assert_eq!(stats.media.entries, 0);
+        assert_eq!(stats.rooms.full + stats.rooms.partial, 0);
+
+        let entry = <&MxcUri>::from("mxc://localhost:8080/media/0");
+        store.insert_media(entry, b"0123456789").await.unwrap();
+
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.media.entries, 1);
+        assert_eq!(stats.media.bytes, 10);
+        assert_eq!(stats.media.limit, 100);
+        let media = stats.tables.iter().find(|t| t.name == "statestore_media");
+        assert_eq!(media.unwrap().rows, 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_maintenance() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let report = store.maintenance(false).await.unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(report.integrity_errors, Some(Vec::new()));
+
+        sqlx::query(ORPHAN_MEMBER).execute(&*db).await.unwrap();
+        let report = store.maintenance(false).await.unwrap();
+        assert!(!report.is_healthy());
+        assert!(!report.repaired);
+        assert_eq!(
+            report.orphaned_rows,
+            vec![OrphanedRows {
+                table: "statestore_members",
+                rows: 1
+            }]
+        );
+
+        let report = store.maintenance(true).await.unwrap();
+        assert!(report.repaired);
+        assert!(store.maintenance(false).await.unwrap().is_healthy());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_maintenance() {
+        let store = crate::schema::postgres_test_store("matrix_maintenance_test").await;
+        let db = Arc::clone(&store.db);
+        let report = store.maintenance(false).await.unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(report.integrity_errors, None);
+
+        sqlx::query(ORPHAN_MEMBER).execute(&*db).await.unwrap();
+        let report = store.maintenance(false).await.unwrap();
+        assert!(!report.is_healthy());
+        assert!(!report.repaired);
+        assert_eq!(
+            report.orphaned_rows,
+            vec![OrphanedRows {
+                table: "statestore_members",
+                rows: 1
+            }]
+        );
+
+        let report = store.maintenance(true).await.unwrap();
+        assert!(report.repaired);
+        assert!(store.maintenance(false).await.unwrap().is_healthy());
+    }
+}
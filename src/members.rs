@@ -0,0 +1,766 @@
+//! Member search with filters and pagination
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Pool, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{escape_like, BorrowedSqlType, SqlType},
+    history::decode_timestamp,
+    Result, SQLStoreError, StateStore, SupportedDatabase,
+};
+
+/// Filter for [`StateStore::search_members`]
+///
+/// The default filter matches every joined or invited member.
+#[derive(Clone, Debug, Default)]
+pub struct MemberFilter {
+    /// Only members whose display name or user ID starts with this string
+    ///
+    /// The comparison ignores case and repeated whitespace, and a leading `@` is optional.
+    pub term: Option<String>,
+    /// Only joined or only invited members
+    pub membership: Option<MembershipState>,
+}
+
+/// Order of the members returned by [`StateStore::search_members`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MemberSort {
+    /// Highest power level first, then by user ID
+    #[default]
+    PowerLevel,
+    /// Most recent read receipt first, then by user ID
+    RecentActivity,
+}
+
+/// Position after the last member of a [`MemberPage`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberCursor {
+    /// The order the cursor was created for
+    sort: MemberSort,
+    /// The ID of the last member
+    user_id: String,
+    /// The sort key of the last member
+    key: i64,
+}
+
+/// A member found by [`StateStore::search_members`]
+#[derive(Clone, Debug)]
+pub struct MemberMatch {
+    /// The ID of the member
+    pub user_id: OwnedUserId,
+    /// The display name of the member in the room, if any
+    pub display_name: Option<String>,
+    /// Either [`MembershipState::Join`] or [`MembershipState::Invite`]
+    pub membership: MembershipState,
+    /// The power level of the member
+    pub power_level: i64,
+    /// The timestamp of the most recent read receipt of the member, if any
+    pub last_active: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// A page of members returned by [`StateStore::search_members`]
+#[derive(Clone, Debug)]
+pub struct MemberPage {
+    /// The members of this page
+    pub members: Vec<MemberMatch>,
+    /// The cursor for the next page, if there are more members
+    pub next: Option<MemberCursor>,
+}
+
+/// Normalizes a display name, user ID or search term for member search
+pub(crate) fn normalize_search(value: &str) -> String {
+    let value = value.trim_start().strip_prefix('@').unwrap_or(value);
+    value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Key of the `statestore_kv` entry holding the version of [`normalize_search`] applied to the
+/// stored members
+const MEMBER_SEARCH_VERSION_KEY: &[u8] = b"member_search_version";
+
+/// Version of [`normalize_search`], to be increased whenever its output changes
+const MEMBER_SEARCH_VERSION: &[u8] = b"1";
+
+/// Recomputes the search columns of all members if they were computed by an older
+/// [`normalize_search`], or by the SQL backfill of the migration that added them
+///
+/// # Errors
+/// This function will return an error if the database query fails
+pub(crate) async fn normalize_member_search<DB: SupportedDatabase>(db: &Pool<DB>) -> Result<()>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    let mut txn = db.begin().await?;
+    let version: Option<Vec<u8>> = DB::kv_load_query()
+        .bind(MEMBER_SEARCH_VERSION_KEY)
+        .fetch_optional(&mut txn)
+        .await?
+        .map(|row| row.try_get("kv_value"))
+        .transpose()?;
+    if version.as_deref() == Some(MEMBER_SEARCH_VERSION) {
+        return Ok(());
+    }
+    let mut updates = Vec::new();
+    let mut rows = DB::member_search_load_query().fetch(&mut txn);
+    while let Some(row) = rows.try_next().await? {
+        let user_id: String = row.try_get("user_id")?;
+        let displayname: Option<String> = row.try_get("displayname")?;
+        let search_name = normalize_search(displayname.as_deref().unwrap_or_default());
+        let search_user_id = normalize_search(&user_id);
+        if row.try_get::<String, _>("search_name")? != search_name
+            || row.try_get::<String, _>("search_user_id")? != search_user_id
+        {
+            let room_id: String = row.try_get("room_id")?;
+            updates.push((room_id, user_id, search_name, search_user_id));
+        }
+    }
+    drop(rows);
+    for (room_id, user_id, search_name, search_user_id) in updates {
+        DB::member_search_update_query()
+            .bind(room_id)
+            .bind(user_id)
+            .bind(search_name)
+            .bind(search_user_id)
+            .execute(&mut txn)
+            .await?;
+    }
+    DB::kv_upsert_query()
+        .bind(MEMBER_SEARCH_VERSION_KEY)
+        .bind(MEMBER_SEARCH_VERSION)
+        .execute(&mut txn)
+        .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<bool>: SqlType<DB>,
+    Option<i64>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Returns a page of at most `limit` members of a room matching `filter`, ordered by `sort`
+    ///
+    /// Pass the [`MemberPage::next`] cursor of a page as `after` to get the following page.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails, or if `after` was created for
+    /// a different order.
+    pub async fn search_members(
+        &self,
+        room_id: &RoomId,
+        filter: &MemberFilter,
+        sort: MemberSort,
+        after: Option<&MemberCursor>,
+        limit: u32,
+    ) -> Result<MemberPage> {
+        if matches!(after, Some(cursor) if cursor.sort != sort) {
+            return Err(SQLStoreError::InvalidCursor);
+        }
+        let joined = match filter.membership {
+            None => None,
+            Some(MembershipState::Join) => Some(true),
+            Some(MembershipState::Invite) => Some(false),
+            // Members that left or were banned are not kept
+            Some(_) => {
+                return Ok(MemberPage {
+                    members: Vec::new(),
+                    next: None,
+                })
+            }
+        };
+        let mut rows = DB::member_search_query(sort)
+            .bind(room_id.as_str())
+            .bind(joined)
+            .bind(filter.term.as_deref().map_or_else(
+                || "%".to_owned(),
+                |term| format!("{}%", escape_like(&normalize_search(term))),
+            ))
+            .bind(after.map(|cursor| cursor.user_id.clone()))
+            .bind(after.map(|cursor| cursor.key))
+            .bind(i64::from(limit) + 1)
+            .fetch(&*self.db);
+        let mut members = Vec::new();
+        let mut last = None;
+        while let Some(row) = rows.try_next().await? {
+            if members.len() == limit as usize {
+                // One more member than requested was fetched, so there is a next page
+                return Ok(MemberPage {
+                    members,
+                    next: last,
+                });
+            }
+            let user_id: String = row.try_get("user_id")?;
+            let power_level: i64 = row.try_get("power_level")?;
+            let last_active: i64 = row.try_get("last_active")?;
+            last = Some(MemberCursor {
+                sort,
+                user_id: user_id.clone(),
+                key: match sort {
+                    MemberSort::PowerLevel => power_level,
+                    MemberSort::RecentActivity => last_active,
+                },
+            });
+            members.push(MemberMatch {
+                user_id: user_id.try_into()?,
+                display_name: row.try_get("displayname")?,
+                membership: if row.try_get("joined")? {
+                    MembershipState::Join
+                } else {
+                    MembershipState::Invite
+                },
+                power_level,
+                last_active: (last_active > 0).then(|| decode_timestamp(last_active)),
+            });
+        }
+        Ok(MemberPage {
+            members,
+            next: None,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use matrix_sdk_base::StateChanges;
+    use ruma::{
+        event_id,
+        events::{
+            receipt::{Receipt, ReceiptType},
+            room::member::{MembershipState, SyncRoomMemberEvent},
+            StateEventType,
+        },
+        room_id,
+        serde::Raw,
+        user_id, MilliSecondsSinceUnixEpoch, UInt,
+    };
+    use serde_json::{json, Value};
+
+    use super::{MemberFilter, MemberMatch, MemberSort};
+    use crate::{ReceiptThread, SQLStoreError, StateStore};
+
+    /// Adds a member event to changes
+    fn add_member(
+        changes: &mut StateChanges,
+        user_id: &str,
+        membership: &str,
+        displayname: Option<&str>,
+    ) {
+        let event: SyncRoomMemberEvent = serde_json::from_value(json!({
+            "content": { "membership": membership, "displayname": displayname },
+            "event_id": format!("${}", user_id),
+            "origin_server_ts": 1,
+            "sender": user_id,
+            "state_key": user_id,
+            "type": "m.room.member",
+        }))
+        .unwrap();
+        changes
+            .members
+            .entry(room_id!("!members:localhost").to_owned())
+            .or_default()
+            .insert(user_id.try_into().unwrap(), event);
+    }
+
+    /// Returns changes that set the power levels of the room
+    fn power_levels(event_id: &str, users: Value, users_default: i64) -> StateChanges {
+        let mut changes = StateChanges::default();
+        changes
+            .state
+            .entry(room_id!("!members:localhost").to_owned())
+            .or_default()
+            .entry(StateEventType::RoomPowerLevels)
+            .or_default()
+            .insert(
+                String::new(),
+                Raw::new(&json!({
+                    "content": { "users": users, "users_default": users_default },
+                    "event_id": event_id,
+                    "origin_server_ts": 1,
+                    "sender": "@alice:localhost",
+                    "state_key": "",
+                    "type": "m.room.power_levels",
+                }))
+                .unwrap()
+                .cast(),
+            );
+        changes
+    }
+
+    /// Returns changes that add four members and their power levels
+    fn changes() -> StateChanges {
+        let mut changes = power_levels(
+            "$power",
+            json!({ "@alice:localhost": 100, "@bob:localhost": 50 }),
+            0,
+        );
+        add_member(
+            &mut changes,
+            "@alice:localhost",
+            "join",
+            Some("Alice  Smith"),
+        );
+        add_member(&mut changes, "@alfred:localhost", "join", None);
+        add_member(&mut changes, "@bob:localhost", "join", Some("Bobby"));
+        add_member(&mut changes, "@carol:localhost", "invite", Some("Alina"));
+        add_member(&mut changes, "@dave:localhost", "leave", Some("Alan"));
+        changes
+    }
+
+    /// Creates a receipt
+    fn receipt(ts: u32) -> Receipt {
+        Receipt::new(MilliSecondsSinceUnixEpoch(UInt::from(ts)))
+    }
+
+    /// Returns the user IDs of members
+    fn user_ids(members: &[MemberMatch]) -> Vec<&str> {
+        members
+            .iter()
+            .map(|member| member.user_id.as_str())
+            .collect()
+    }
+
+    /// Returns a filter for a search term
+    fn term(term: &str) -> MemberFilter {
+        MemberFilter {
+            term: Some(term.to_owned()),
+            ..MemberFilter::default()
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_search_members() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room_id = room_id!("!members:localhost");
+        store.save_state_changes(&changes()).await.unwrap();
+
+        for (filter, expected) in [
+            (
+                term("al"),
+                vec!["@alice:localhost", "@alfred:localhost", "@carol:localhost"],
+            ),
+            (term("ALICE S"), vec!["@alice:localhost"]),
+            (term("@bob"), vec!["@bob:localhost"]),
+            (term("a%"), vec![]),
+            (
+                MemberFilter {
+                    membership: Some(MembershipState::Invite),
+                    ..MemberFilter::default()
+                },
+                vec!["@carol:localhost"],
+            ),
+            (
+                MemberFilter {
+                    membership: Some(MembershipState::Leave),
+                    ..MemberFilter::default()
+                },
+                vec![],
+            ),
+        ] {
+            let page = store
+                .search_members(room_id, &filter, MemberSort::PowerLevel, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(user_ids(&page.members), expected, "{:?}", filter);
+            assert!(page.next.is_none());
+        }
+
+        let all = MemberFilter::default();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alice:localhost", "@bob:localhost"]
+        );
+        assert_eq!(page.members[0].power_level, 100);
+        assert_eq!(
+            page.members[0].display_name.as_deref(),
+            Some("Alice  Smith")
+        );
+        let next = page.next.unwrap();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, Some(&next), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alfred:localhost", "@carol:localhost"]
+        );
+        assert_eq!(page.members[1].membership, MembershipState::Invite);
+        assert!(page.next.is_none());
+        assert!(matches!(
+            store
+                .search_members(room_id, &all, MemberSort::RecentActivity, Some(&next), 2)
+                .await,
+            Err(SQLStoreError::InvalidCursor)
+        ));
+
+        for (user_id, ts) in [
+            (user_id!("@alice:localhost"), 1000),
+            (user_id!("@alfred:localhost"), 3000),
+        ] {
+            store
+                .save_thread_receipt(
+                    room_id,
+                    event_id!("$read"),
+                    &ReceiptType::Read,
+                    &ReceiptThread::Unthreaded,
+                    user_id,
+                    receipt(ts),
+                )
+                .await
+                .unwrap();
+        }
+        let page = store
+            .search_members(room_id, &all, MemberSort::RecentActivity, None, 3)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alfred:localhost", "@alice:localhost", "@bob:localhost"]
+        );
+        assert!(page.members[2].last_active.is_none());
+        let page = store
+            .search_members(
+                room_id,
+                &all,
+                MemberSort::RecentActivity,
+                page.next.as_ref(),
+                3,
+            )
+            .await
+            .unwrap();
+        assert_eq!(user_ids(&page.members), ["@carol:localhost"]);
+
+        // New power levels replace the previous ones
+        store
+            .save_state_changes(&power_levels(
+                "$power2",
+                json!({ "@bob:localhost": 75 }),
+                10,
+            ))
+            .await
+            .unwrap();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            [
+                "@bob:localhost",
+                "@alfred:localhost",
+                "@alice:localhost",
+                "@carol:localhost"
+            ]
+        );
+        assert_eq!(page.members[1].power_level, 10);
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_search_members() {
+        let store = crate::schema::postgres_test_store("matrix_members_test").await;
+        let room_id = room_id!("!members:localhost");
+        store.save_state_changes(&changes()).await.unwrap();
+
+        for (filter, expected) in [
+            (
+                term("al"),
+                vec!["@alice:localhost", "@alfred:localhost", "@carol:localhost"],
+            ),
+            (term("ALICE S"), vec!["@alice:localhost"]),
+            (term("@bob"), vec!["@bob:localhost"]),
+            (term("a%"), vec![]),
+            (
+                MemberFilter {
+                    membership: Some(MembershipState::Invite),
+                    ..MemberFilter::default()
+                },
+                vec!["@carol:localhost"],
+            ),
+            (
+                MemberFilter {
+                    membership: Some(MembershipState::Leave),
+                    ..MemberFilter::default()
+                },
+                vec![],
+            ),
+        ] {
+            let page = store
+                .search_members(room_id, &filter, MemberSort::PowerLevel, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(user_ids(&page.members), expected, "{:?}", filter);
+            assert!(page.next.is_none());
+        }
+
+        let all = MemberFilter::default();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alice:localhost", "@bob:localhost"]
+        );
+        assert_eq!(page.members[0].power_level, 100);
+        assert_eq!(
+            page.members[0].display_name.as_deref(),
+            Some("Alice  Smith")
+        );
+        let next = page.next.unwrap();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, Some(&next), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alfred:localhost", "@carol:localhost"]
+        );
+        assert_eq!(page.members[1].membership, MembershipState::Invite);
+        assert!(page.next.is_none());
+        assert!(matches!(
+            store
+                .search_members(room_id, &all, MemberSort::RecentActivity, Some(&next), 2)
+                .await,
+            Err(SQLStoreError::InvalidCursor)
+        ));
+
+        for (user_id, ts) in [
+            (user_id!("@alice:localhost"), 1000),
+            (user_id!("@alfred:localhost"), 3000),
+        ] {
+            store
+                .save_thread_receipt(
+                    room_id,
+                    event_id!("$read"),
+                    &ReceiptType::Read,
+                    &ReceiptThread::Unthreaded,
+                    user_id,
+                    receipt(ts),
+                )
+                .await
+                .unwrap();
+        }
+        let page = store
+            .search_members(room_id, &all, MemberSort::RecentActivity, None, 3)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            ["@alfred:localhost", "@alice:localhost", "@bob:localhost"]
+        );
+        assert!(page.members[2].last_active.is_none());
+        let page = store
+            .search_members(
+                room_id,
+                &all,
+                MemberSort::RecentActivity,
+                page.next.as_ref(),
+                3,
+            )
+            .await
+            .unwrap();
+        assert_eq!(user_ids(&page.members), ["@carol:localhost"]);
+
+        // New power levels replace the previous ones
+        store
+            .save_state_changes(&power_levels(
+                "$power2",
+                json!({ "@bob:localhost": 75 }),
+                10,
+            ))
+            .await
+            .unwrap();
+        let page = store
+            .search_members(room_id, &all, MemberSort::PowerLevel, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            user_ids(&page.members),
+            [
+                "@bob:localhost",
+                "@alfred:localhost",
+                "@alice:localhost",
+                "@carol:localhost"
+            ]
+        );
+        assert_eq!(page.members[1].power_level, 10);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_member_search_plan() {
+        use sqlx::{Execute, Row};
+
+        use crate::SupportedDatabase;
+
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        StateStore::new(&db).await.unwrap();
+        let query = sqlx::Sqlite::member_search_query(MemberSort::PowerLevel);
+        let rows = sqlx::query(&format!("EXPLAIN QUERY PLAN {}", query.sql()))
+            .bind("!members:localhost")
+            .bind(Option::<bool>::None)
+            .bind("al%")
+            .bind(Option::<String>::None)
+            .bind(Option::<i64>::None)
+            .bind(10_i64)
+            .fetch_all(&*db)
+            .await
+            .unwrap();
+        let plan: Vec<String> = rows.iter().map(|row| row.get("detail")).collect();
+        for column in ["search_name", "search_user_id"] {
+            assert!(
+                plan.iter()
+                    .any(|detail| detail.contains(&format!("{}>? AND {}<?", column, column))),
+                "{:?}",
+                plan
+            );
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_member_search_normalization() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room_id = room_id!("!members:localhost");
+        let mut changes = changes();
+        add_member(&mut changes, "@eve:localhost", "join", Some("Élodie"));
+        store.save_state_changes(&changes).await.unwrap();
+
+        // Search columns as computed by the SQL backfill of the migration
+        sqlx::query("UPDATE statestore_members SET search_name = LOWER(COALESCE(displayname, ''))")
+            .execute(&*db)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM statestore_kv WHERE kv_key = $1")
+            .bind(&b"member_search_version"[..])
+            .execute(&*db)
+            .await
+            .unwrap();
+        let page = store
+            .search_members(room_id, &term("alice s"), MemberSort::PowerLevel, None, 10)
+            .await
+            .unwrap();
+        assert!(page.members.is_empty());
+
+        let store = StateStore::new(&db).await.unwrap();
+        for (filter, expected) in [
+            (term("alice s"), "@alice:localhost"),
+            (term("élodie"), "@eve:localhost"),
+        ] {
+            let page = store
+                .search_members(room_id, &filter, MemberSort::PowerLevel, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(user_ids(&page.members), [expected], "{:?}", filter);
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_member_search_normalization() {
+        let store = crate::schema::postgres_test_store("matrix_members_norm_test").await;
+        let db = Arc::clone(&store.db);
+        let room_id = room_id!("!members:localhost");
+        let mut changes = changes();
+        add_member(&mut changes, "@eve:localhost", "join", Some("Élodie"));
+        store.save_state_changes(&changes).await.unwrap();
+
+        // Search columns as computed by the SQL backfill of the migration
+        sqlx::query("UPDATE statestore_members SET search_name = LOWER(COALESCE(displayname, ''))")
+            .execute(&*db)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM statestore_kv WHERE kv_key = $1")
+            .bind(&b"member_search_version"[..])
+            .execute(&*db)
+            .await
+            .unwrap();
+        let page = store
+            .search_members(room_id, &term("alice s"), MemberSort::PowerLevel, None, 10)
+            .await
+            .unwrap();
+        assert!(page.members.is_empty());
+
+        let store = StateStore::new(&db).await.unwrap();
+        for (filter, expected) in [
+            (term("alice s"), "@alice:localhost"),
+            (term("élodie"), "@eve:localhost"),
+        ] {
+            let page = store
+                .search_members(room_id, &filter, MemberSort::PowerLevel, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(user_ids(&page.members), [expected], "{:?}", filter);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Threaded read receipts and unread counts
+
+use std::collections::BTreeMap;
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::{Receipt, ReceiptType},
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, RoomId, UserId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// The thread a receipt applies to
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ReceiptThread {
+    /// The receipt applies to the whole room
+    #[default]
+    Unthreaded,
+    /// The receipt applies to the main timeline, outside of any thread
+    Main,
+    /// The receipt applies to the thread with the given root event
+    Thread(OwnedEventId),
+}
+
+impl ReceiptThread {
+    /// Returns the stored thread ID
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Self::Unthreaded => "",
+            Self::Main => "main",
+            Self::Thread(event_id) => event_id.as_str(),
+        }
+    }
+}
+
+/// Unread counts of a room
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnreadCounts {
+    /// The number of unread notifications
+    pub notification_count: u64,
+    /// The number of unread notifications that are highlighted, like mentions
+    pub highlight_count: u64,
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Stores a receipt for an event in a thread of a room
+    ///
+    /// Receipts received through sync are stored as unthreaded receipts, because matrix-sdk-base
+    /// passes them on without their thread ID. A threaded receipt from sync therefore replaces
+    /// the unthreaded receipt of the user. Use this to store threaded receipts, which replace the
+    /// previous receipt of the user in the same thread only.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails
+    pub async fn save_thread_receipt(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        receipt_type: &ReceiptType,
+        thread: &ReceiptThread,
+        user_id: &UserId,
+        receipt: Receipt,
+    ) -> Result<()> {
+        let mut txn = self.db.begin().await?;
+        Self::set_receipt(
+            &mut txn,
+            room_id,
+            event_id,
+            receipt_type,
+            thread,
+            user_id,
+            receipt,
+            i64::from(MilliSecondsSinceUnixEpoch::now().0),
+        )
+        .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the unread counts of the own user for every room, except rooms only known from
+    /// invites
+    ///
+    /// The counts are the ones last reported by the homeserver. They are reset to zero if a read
+    /// receipt of `own_user_id` for the room or its main timeline has been stored after the counts,
+    /// for example one stored with [`StateStore::save_thread_receipt`]. Receipts saved together
+    /// with the counts in the same sync do not reset them, as the homeserver already counted
+    /// them. The local time the receipt was stored is compared, not the timestamp of the receipt.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails
+    pub async fn unread_counts(
+        &self,
+        own_user_id: &UserId,
+    ) -> Result<BTreeMap<OwnedRoomId, UnreadCounts>> {
+        let mut rows = DB::unread_counts_load_query()
+            .bind(own_user_id.as_str())
+            .fetch(&*self.db);
+        let mut counts = BTreeMap::new();
+        while let Some(row) = rows.try_next().await? {
+            let room_id: String = row.try_get("room_id")?;
+            let counts_updated_at: i64 = row.try_get("counts_updated_at")?;
+            let receipt_stored_at: i64 = row.try_get("receipt_stored_at")?;
+            let room_counts = if receipt_stored_at > counts_updated_at {
+                UnreadCounts::default()
+            } else {
+                let notification_count: i64 = row.try_get("notification_count")?;
+                let highlight_count: i64 = row.try_get("highlight_count")?;
+                UnreadCounts {
+                    notification_count: notification_count.try_into().unwrap_or_default(),
+                    highlight_count: highlight_count.try_into().unwrap_or_default(),
+                }
+            };
+            counts.insert(room_id.try_into()?, room_counts);
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use ruma::{
+        event_id,
+        events::receipt::{Receipt, ReceiptType},
+        room_id, user_id, MilliSecondsSinceUnixEpoch, UInt,
+    };
+
+    use matrix_sdk_base::{RoomInfo, RoomType, StateChanges};
+    use serde_json::json;
+
+    use super::{ReceiptThread, UnreadCounts};
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates a receipt
+    fn receipt(ts: u64) -> Receipt {
+        Receipt::new(MilliSecondsSinceUnixEpoch(UInt::new(ts).unwrap()))
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_thread_receipts() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room_id = room_id!("!receipts:localhost");
+        let user_id = user_id!("@alice:localhost");
+        let thread = ReceiptThread::Thread(event_id!("$root").to_owned());
+        for (event_id, thread) in [
+            (event_id!("$main"), &ReceiptThread::Unthreaded),
+            (event_id!("$timeline"), &ReceiptThread::Main),
+            (event_id!("$reply"), &thread),
+        ] {
+            store
+                .save_thread_receipt(
+                    room_id,
+                    event_id,
+                    &ReceiptType::Read,
+                    thread,
+                    user_id,
+                    receipt(1000),
+                )
+                .await
+                .unwrap();
+        }
+
+        for (event_id, thread) in [
+            ("$main", &ReceiptThread::Unthreaded),
+            ("$timeline", &ReceiptThread::Main),
+            ("$reply", &thread),
+        ] {
+            let (stored, _) = store
+                .get_user_room_thread_receipt_event(room_id, ReceiptType::Read, thread, user_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored, event_id);
+        }
+        let receipts = store
+            .get_event_room_thread_receipt_events(
+                room_id,
+                ReceiptType::Read,
+                &thread,
+                event_id!("$reply"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert!(store
+            .get_event_room_thread_receipt_events(
+                room_id,
+                ReceiptType::Read,
+                &ReceiptThread::Unthreaded,
+                event_id!("$reply"),
+            )
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_thread_receipts() {
+        let store = crate::schema::postgres_test_store("matrix_receipts_test").await;
+        let room_id = room_id!("!receipts:localhost");
+        let user_id = user_id!("@alice:localhost");
+        let thread = ReceiptThread::Thread(event_id!("$root").to_owned());
+        for (event_id, thread) in [
+            (event_id!("$main"), &ReceiptThread::Unthreaded),
+            (event_id!("$timeline"), &ReceiptThread::Main),
+            (event_id!("$reply"), &thread),
+        ] {
+            store
+                .save_thread_receipt(
+                    room_id,
+                    event_id,
+                    &ReceiptType::Read,
+                    thread,
+                    user_id,
+                    receipt(1000),
+                )
+                .await
+                .unwrap();
+        }
+
+        for (event_id, thread) in [
+            ("$main", &ReceiptThread::Unthreaded),
+            ("$timeline", &ReceiptThread::Main),
+            ("$reply", &thread),
+        ] {
+            let (stored, _) = store
+                .get_user_room_thread_receipt_event(room_id, ReceiptType::Read, thread, user_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored, event_id);
+        }
+        let receipts = store
+            .get_event_room_thread_receipt_events(
+                room_id,
+                ReceiptType::Read,
+                &thread,
+                event_id!("$reply"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert!(store
+            .get_event_room_thread_receipt_events(
+                room_id,
+                ReceiptType::Read,
+                &ReceiptThread::Unthreaded,
+                event_id!("$reply"),
+            )
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_unread_counts() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let room_id = room_id!("!unread:localhost");
+        let user_id = user_id!("@alice:localhost");
+        let mut room_info = serde_json::to_value(RoomInfo::new(room_id, RoomType::Joined)).unwrap();
+        room_info["notification_counts"] = json!({
+            "highlight_count": 1,
+            "notification_count": 3,
+        });
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info.clone()).unwrap());
+        store.save_state_changes(&changes).await.unwrap();
+
+        let expected = UnreadCounts {
+            notification_count: 3,
+            highlight_count: 1,
+        };
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Receipts synced together with the counts were already counted by the homeserver
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info.clone()).unwrap());
+        changes.add_receipts(
+            room_id,
+            serde_json::from_value(json!({
+                "$synced": { "m.read": { "@alice:localhost": { "ts": 1 } } },
+            }))
+            .unwrap(),
+        );
+        store.save_state_changes(&changes).await.unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Receipts in threads keep the counts
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        store
+            .save_thread_receipt(
+                room_id,
+                event_id!("$read"),
+                &ReceiptType::Read,
+                &ReceiptThread::Thread(event_id!("$root").to_owned()),
+                user_id,
+                receipt(1),
+            )
+            .await
+            .unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // The local time the receipt was stored is compared, not its timestamp
+        store
+            .save_thread_receipt(
+                room_id,
+                event_id!("$read"),
+                &ReceiptType::Read,
+                &ReceiptThread::Main,
+                user_id,
+                receipt(1),
+            )
+            .await
+            .unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&UnreadCounts::default()));
+        let counts = store
+            .unread_counts(user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Counts synced afterwards are returned again
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info).unwrap());
+        store.save_state_changes(&changes).await.unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_unread_counts() {
+        let store = crate::schema::postgres_test_store("matrix_unread_test").await;
+        let room_id = room_id!("!unread:localhost");
+        let user_id = user_id!("@alice:localhost");
+        let mut room_info = serde_json::to_value(RoomInfo::new(room_id, RoomType::Joined)).unwrap();
+        room_info["notification_counts"] = json!({
+            "highlight_count": 1,
+            "notification_count": 3,
+        });
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info.clone()).unwrap());
+        store.save_state_changes(&changes).await.unwrap();
+
+        let expected = UnreadCounts {
+            notification_count: 3,
+            highlight_count: 1,
+        };
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Receipts synced together with the counts were already counted by the homeserver
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info.clone()).unwrap());
+        changes.add_receipts(
+            room_id,
+            serde_json::from_value(json!({
+                "$synced": { "m.read": { "@alice:localhost": { "ts": 1 } } },
+            }))
+            .unwrap(),
+        );
+        store.save_state_changes(&changes).await.unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Receipts in threads keep the counts
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        store
+            .save_thread_receipt(
+                room_id,
+                event_id!("$read"),
+                &ReceiptType::Read,
+                &ReceiptThread::Thread(event_id!("$root").to_owned()),
+                user_id,
+                receipt(1),
+            )
+            .await
+            .unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // The local time the receipt was stored is compared, not its timestamp
+        store
+            .save_thread_receipt(
+                room_id,
+                event_id!("$read"),
+                &ReceiptType::Read,
+                &ReceiptThread::Main,
+                user_id,
+                receipt(1),
+            )
+            .await
+            .unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&UnreadCounts::default()));
+        let counts = store
+            .unread_counts(user_id!("@bob:localhost"))
+            .await
+            .unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+
+        // Counts synced afterwards are returned again
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let mut changes = StateChanges::default();
+        changes.add_room(serde_json::from_value(room_info).unwrap());
+        store.save_state_changes(&changes).await.unwrap();
+        let counts = store.unread_counts(user_id).await.unwrap();
+        assert_eq!(counts.get(room_id), Some(&expected));
+    }
+}
@@ -0,0 +1,430 @@
+//! Queryable records of the devices that received outbound group sessions
+
+use std::collections::BTreeSet;
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use matrix_sdk_crypto::{olm::PickledOutboundGroupSession, ReadOnlyDevice};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// A device that received the current outbound group session of a room
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomKeyShare {
+    /// The owner of the device
+    pub user_id: OwnedUserId,
+    /// The device that received the session
+    pub device_id: OwnedDeviceId,
+    /// The first message index the device can decrypt
+    pub message_index: u32,
+    /// When the share was first stored
+    pub shared_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Records the devices an outbound group session was shared with
+    ///
+    /// Records of earlier sessions of the room are removed, so only the current session is
+    /// tracked. Devices that are already recorded for the session are skipped.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub(crate) async fn save_room_key_shares<'c>(
+        &self,
+        txn: &mut Transaction<'c, DB>,
+        session_id: &str,
+        pickle: &PickledOutboundGroupSession,
+    ) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let room_id = e2e.encode_key(
+            "cryptostore_outbound_session_share:room_id",
+            pickle.room_id.as_bytes(),
+        );
+        let session_id = e2e.encode_key(
+            "cryptostore_outbound_session_share:session_id",
+            session_id.as_bytes(),
+        );
+        DB::outbound_session_share_prune_query()
+            .bind(room_id.as_ref())
+            .bind(session_id.as_ref())
+            .execute(&mut *txn)
+            .await?;
+
+        let mut recorded = BTreeSet::new();
+        let mut rows = DB::outbound_session_share_recipients_fetch_query()
+            .bind(room_id.as_ref())
+            .bind(session_id.as_ref())
+            .fetch(&mut *txn);
+        while let Some(row) = rows.try_next().await? {
+            let user_id: Vec<u8> = row.try_get("user_id")?;
+            let device_id: Vec<u8> = row.try_get("device_id")?;
+            recorded.insert((user_id, device_id));
+        }
+        drop(rows);
+
+        let shared_at = MilliSecondsSinceUnixEpoch::now();
+        for (user_id, devices) in &pickle.shared_with_set {
+            let hashed_user_id = e2e.encode_key(
+                "cryptostore_outbound_session_share:user_id",
+                user_id.as_bytes(),
+            );
+            for (device_id, info) in devices {
+                let hashed_device_id = e2e.encode_key(
+                    "cryptostore_outbound_session_share:device_id",
+                    device_id.as_bytes(),
+                );
+                if recorded.contains(&(hashed_user_id.to_vec(), hashed_device_id.to_vec())) {
+                    continue;
+                }
+                let share = RoomKeyShare {
+                    user_id: user_id.clone(),
+                    device_id: device_id.clone(),
+                    message_index: info.message_index,
+                    shared_at,
+                };
+                DB::outbound_session_share_store_query()
+                    .bind(room_id.as_ref())
+                    .bind(session_id.as_ref())
+                    .bind(hashed_user_id.as_ref())
+                    .bind(hashed_device_id.as_ref())
+                    .bind(e2e.encode_value(&share)?)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the devices that received the current outbound group session of a room
+    ///
+    /// The shares are sorted by user and device ID.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn room_key_recipients(&self, room_id: &RoomId) -> Result<Vec<RoomKeyShare>> {
+        let e2e = self.ensure_e2e()?;
+        let room_id = e2e.encode_key(
+            "cryptostore_outbound_session_share:room_id",
+            room_id.as_bytes(),
+        );
+        let mut rows = DB::outbound_session_shares_fetch_query()
+            .bind(room_id.as_ref())
+            .fetch(&*self.db);
+        let mut shares: Vec<RoomKeyShare> = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let data: Vec<u8> = row.try_get("share_data")?;
+            shares.push(e2e.decode_value(&data)?);
+        }
+        shares.sort_by(|a, b| (&a.user_id, &a.device_id).cmp(&(&b.user_id, &b.device_id)));
+        Ok(shares)
+    }
+
+    /// Finds the known devices of `users` that did not receive the current outbound group session
+    /// of a room
+    ///
+    /// Deleted devices are skipped. If the room has no outbound group session, all devices are
+    /// returned.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn devices_without_room_key(
+        &self,
+        room_id: &RoomId,
+        users: &[&UserId],
+    ) -> Result<Vec<ReadOnlyDevice>> {
+        let recipients: BTreeSet<_> = self
+            .room_key_recipients(room_id)
+            .await?
+            .into_iter()
+            .map(|share| (share.user_id, share.device_id))
+            .collect();
+        let mut missing = Vec::new();
+        for &user_id in users {
+            let mut devices: Vec<_> = self
+                .get_user_devices(user_id)
+                .await?
+                .into_values()
+                .filter(|device| {
+                    !device.is_deleted()
+                        && !recipients
+                            .contains(&(device.user_id().to_owned(), device.device_id().to_owned()))
+                })
+                .collect();
+            devices.sort_by(|a, b| a.device_id().cmp(b.device_id()));
+            missing.extend(devices);
+        }
+        Ok(missing)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use matrix_sdk_crypto::{
+        olm::{OutboundGroupSession, ShareInfo},
+        EncryptionSettings, ReadOnlyAccount, ReadOnlyDevice,
+    };
+    use ruma::{device_id, room_id, user_id, RoomId};
+    use vodozemac::olm::Account;
+
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates an outbound group session shared with the given devices
+    async fn shared_session(
+        room_id: &RoomId,
+        devices: &[&ReadOnlyDevice],
+        message_index: u32,
+    ) -> OutboundGroupSession {
+        let identity_keys = Arc::new(Account::new().identity_keys());
+        let session = OutboundGroupSession::new(
+            From::from(device_id!("ALICEDEVICE")),
+            identity_keys.clone(),
+            room_id,
+            EncryptionSettings::default(),
+        )
+        .unwrap();
+        let mut pickle = session.pickle().await;
+        for device in devices {
+            pickle
+                .shared_with_set
+                .entry(device.user_id().to_owned())
+                .or_default()
+                .insert(
+                    device.device_id().to_owned(),
+                    ShareInfo {
+                        sender_key: identity_keys.curve25519,
+                        message_index,
+                    },
+                );
+        }
+        OutboundGroupSession::from_pickle(
+            From::from(device_id!("ALICEDEVICE")),
+            identity_keys,
+            pickle,
+        )
+        .unwrap()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_room_key_shares() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let bob = user_id!("@bob:localhost");
+        let carol = user_id!("@carol:localhost");
+        let bob_phone =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(bob, device_id!("PHONE"))).await;
+        let bob_laptop =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(bob, device_id!("LAPTOP"))).await;
+        let carol_phone =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(carol, device_id!("PHONE"))).await;
+        let mut txn = store.db.begin().await.unwrap();
+        for device in [&bob_phone, &bob_laptop, &carol_phone] {
+            store.save_device(&mut txn, device.clone()).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+        assert!(store.room_key_recipients(room).await.unwrap().is_empty());
+        assert_eq!(
+            store
+                .devices_without_room_key(room, &[bob, carol])
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let session = shared_session(room, &[&bob_phone, &carol_phone], 3).await;
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, session.clone())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let recipients = store.room_key_recipients(room).await.unwrap();
+        assert_eq!(
+            recipients
+                .iter()
+                .map(|s| (s.user_id.as_str(), s.device_id.as_str(), s.message_index))
+                .collect::<Vec<_>>(),
+            [
+                ("@bob:localhost", "PHONE", 3),
+                ("@carol:localhost", "PHONE", 3)
+            ]
+        );
+        let missing = store
+            .devices_without_room_key(room, &[bob, carol])
+            .await
+            .unwrap();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].device_id(), device_id!("LAPTOP"));
+        assert!(store
+            .room_key_recipients(room_id!("!other:localhost"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, session)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(store.room_key_recipients(room).await.unwrap(), recipients);
+
+        let rotated = shared_session(room, &[&bob_laptop], 0).await;
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, rotated)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let recipients = store.room_key_recipients(room).await.unwrap();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].device_id, device_id!("LAPTOP"));
+        let missing = store
+            .devices_without_room_key(room, &[bob, carol])
+            .await
+            .unwrap();
+        assert_eq!(missing.len(), 2);
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_room_key_shares() {
+        let store = crate::schema::postgres_test_store("matrix_room_key_shares_test").await;
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let bob = user_id!("@bob:localhost");
+        let carol = user_id!("@carol:localhost");
+        let bob_phone =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(bob, device_id!("PHONE"))).await;
+        let bob_laptop =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(bob, device_id!("LAPTOP"))).await;
+        let carol_phone =
+            ReadOnlyDevice::from_account(&ReadOnlyAccount::new(carol, device_id!("PHONE"))).await;
+        let mut txn = store.db.begin().await.unwrap();
+        for device in [&bob_phone, &bob_laptop, &carol_phone] {
+            store.save_device(&mut txn, device.clone()).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+        assert!(store.room_key_recipients(room).await.unwrap().is_empty());
+        assert_eq!(
+            store
+                .devices_without_room_key(room, &[bob, carol])
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let session = shared_session(room, &[&bob_phone, &carol_phone], 3).await;
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, session.clone())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let recipients = store.room_key_recipients(room).await.unwrap();
+        assert_eq!(
+            recipients
+                .iter()
+                .map(|s| (s.user_id.as_str(), s.device_id.as_str(), s.message_index))
+                .collect::<Vec<_>>(),
+            [
+                ("@bob:localhost", "PHONE", 3),
+                ("@carol:localhost", "PHONE", 3)
+            ]
+        );
+        let missing = store
+            .devices_without_room_key(room, &[bob, carol])
+            .await
+            .unwrap();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].device_id(), device_id!("LAPTOP"));
+        assert!(store
+            .room_key_recipients(room_id!("!other:localhost"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, session)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(store.room_key_recipients(room).await.unwrap(), recipients);
+
+        let rotated = shared_session(room, &[&bob_laptop], 0).await;
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_outbound_group_session(&mut txn, rotated)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let recipients = store.room_key_recipients(room).await.unwrap();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].device_id, device_id!("LAPTOP"));
+        let missing = store
+            .devices_without_room_key(room, &[bob, carol])
+            .await
+            .unwrap();
+        assert_eq!(missing.len(), 2);
+    }
+}
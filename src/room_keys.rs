@@ -0,0 +1,405 @@
+//! Room key export and import in the `MEGOLM SESSION DATA` file format
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use matrix_sdk_crypto::{
+    decrypt_room_key_export, encrypt_room_key_export, olm::InboundGroupSession, RoomKeyImportResult,
+};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    RoomId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Exports inbound group sessions to the passphrase protected `MEGOLM SESSION DATA` format
+    ///
+    /// Only the sessions of `room_id` are exported if it is given, otherwise those of all rooms.
+    /// `rounds` is the number of PBKDF2 rounds used to derive the key from the passphrase, and
+    /// should be at least 100000.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked, or if the query
+    /// fails.
+    pub async fn export_room_keys(
+        &self,
+        room_id: Option<&RoomId>,
+        passphrase: &str,
+        rounds: u32,
+    ) -> Result<String> {
+        let sessions: Vec<InboundGroupSession> = match room_id {
+            Some(room_id) => {
+                let e2e = self.ensure_e2e()?;
+                let room_id = e2e.encode_key(
+                    "cryptostore_inbound_group_session:room_id",
+                    room_id.as_bytes(),
+                );
+                let mut rows = DB::room_inbound_group_sessions_fetch_query()
+                    .bind(room_id.as_ref())
+                    .fetch(&*self.db);
+                let mut sessions = Vec::new();
+                while let Some(row) = rows.try_next().await? {
+                    let data: Vec<u8> = row.try_get("session_data")?;
+                    sessions.push(InboundGroupSession::from_pickle(e2e.decode_value(&data)?)?);
+                }
+                sessions
+            }
+            None => self.get_inbound_group_sessions().await?,
+        };
+
+        let mut keys = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            keys.push(session.export().await);
+        }
+        Ok(encrypt_room_key_export(&keys, passphrase, rounds)?)
+    }
+
+    /// Imports inbound group sessions from the passphrase protected `MEGOLM SESSION DATA` format
+    ///
+    /// Sessions that are already known are only replaced if the imported session can decrypt
+    /// earlier messages, that is if its first known index is lower. Sessions that cannot be
+    /// created from the export are skipped.
+    ///
+    /// # Errors
+    /// This function will return an error if the export cannot be decrypted, if the database has
+    /// not been unlocked, or if a query fails.
+    pub async fn import_room_keys(
+        &self,
+        export: impl std::io::Read,
+        passphrase: &str,
+    ) -> Result<RoomKeyImportResult> {
+        let keys = decrypt_room_key_export(export, passphrase)?;
+        let total_count = keys.len();
+        let mut imported: BTreeMap<_, BTreeMap<_, BTreeSet<_>>> = BTreeMap::new();
+
+        let mut txn = self.db.begin().await?;
+        for key in keys {
+            let session = match InboundGroupSession::from_export(&key) {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::warn!(
+                        session_id = key.session_id.as_str(),
+                        "Skipping room key that cannot be imported: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            let sender_key = session.sender_key().to_base64();
+            let existing = self
+                .get_inbound_group_session_txn(
+                    &mut txn,
+                    session.room_id(),
+                    &sender_key,
+                    session.session_id(),
+                )
+                .await?;
+            if matches!(existing, Some(existing) if existing.first_known_index() <= session.first_known_index())
+            {
+                continue;
+            }
+            imported
+                .entry(session.room_id().to_owned())
+                .or_default()
+                .entry(sender_key)
+                .or_default()
+                .insert(session.session_id().to_owned());
+            self.save_inbound_group_session(&mut txn, session).await?;
+        }
+        txn.commit().await?;
+
+        Ok(RoomKeyImportResult {
+            imported_count: imported
+                .values()
+                .flat_map(BTreeMap::values)
+                .map(BTreeSet::len)
+                .sum(),
+            total_count,
+            keys: imported,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_crypto::olm::InboundGroupSession;
+    use ruma::{events::room::history_visibility::HistoryVisibility, room_id, RoomId};
+    use vodozemac::{
+        megolm::{GroupSession, SessionConfig},
+        olm::Account,
+    };
+
+    use crate::SQLStoreError;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates a new inbound group session for the given room
+    fn inbound_session(room_id: &RoomId) -> InboundGroupSession {
+        let account = Account::new();
+        let outbound = GroupSession::new(SessionConfig::version_1());
+        InboundGroupSession::new(
+            account.curve25519_key(),
+            account.ed25519_key(),
+            room_id,
+            &outbound.session_key(),
+            matrix_sdk_crypto::types::EventEncryptionAlgorithm::MegolmV1AesSha2,
+            Some(HistoryVisibility::Shared),
+        )
+        .unwrap()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_room_keys() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let other_room = room_id!("!other:localhost");
+        let session = inbound_session(room);
+        let other_session = inbound_session(other_room);
+
+        let late = InboundGroupSession::from_export(&session.export_at_index(5).await).unwrap();
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_inbound_group_session(&mut txn, late)
+            .await
+            .unwrap();
+        store
+            .save_inbound_group_session(&mut txn, other_session.clone())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let export = store
+            .export_room_keys(Some(room), "pass", 10)
+            .await
+            .unwrap();
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 1);
+        assert_eq!(result.imported_count, 0);
+
+        let export = matrix_sdk_crypto::encrypt_room_key_export(
+            &[session.export().await, other_session.export().await],
+            "pass",
+            10,
+        )
+        .unwrap();
+        assert!(matches!(
+            store.import_room_keys(export.as_bytes(), "wrong").await,
+            Err(SQLStoreError::KeyExport(_))
+        ));
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 2);
+        assert_eq!(result.imported_count, 1);
+        assert!(result.keys[room]
+            .values()
+            .any(|s| s.contains(session.session_id())));
+        let stored = store
+            .get_inbound_group_session(
+                room,
+                &session.sender_key().to_base64(),
+                session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.first_known_index(), 0);
+
+        let third_session = inbound_session(room);
+        let export = matrix_sdk_crypto::encrypt_room_key_export(
+            &[
+                third_session.export().await,
+                third_session.export_at_index(3).await,
+            ],
+            "pass",
+            10,
+        )
+        .unwrap();
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 2);
+        assert_eq!(result.imported_count, 1);
+        let stored = store
+            .get_inbound_group_session(
+                room,
+                &third_session.sender_key().to_base64(),
+                third_session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.first_known_index(), 0);
+
+        let export = store.export_room_keys(None, "pass", 10).await.unwrap();
+        let keys = matrix_sdk_crypto::decrypt_room_key_export(export.as_bytes(), "pass").unwrap();
+        assert_eq!(keys.len(), 3);
+        let export = store
+            .export_room_keys(Some(other_room), "pass", 10)
+            .await
+            .unwrap();
+        let keys = matrix_sdk_crypto::decrypt_room_key_export(export.as_bytes(), "pass").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].session_id, other_session.session_id());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_room_keys() {
+        let store = crate::schema::postgres_test_store("matrix_room_keys_test").await;
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let other_room = room_id!("!other:localhost");
+        let session = inbound_session(room);
+        let other_session = inbound_session(other_room);
+
+        let late = InboundGroupSession::from_export(&session.export_at_index(5).await).unwrap();
+        let mut txn = store.db.begin().await.unwrap();
+        store
+            .save_inbound_group_session(&mut txn, late)
+            .await
+            .unwrap();
+        store
+            .save_inbound_group_session(&mut txn, other_session.clone())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let export = store
+            .export_room_keys(Some(room), "pass", 10)
+            .await
+            .unwrap();
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 1);
+        assert_eq!(result.imported_count, 0);
+
+        let export = matrix_sdk_crypto::encrypt_room_key_export(
+            &[session.export().await, other_session.export().await],
+            "pass",
+            10,
+        )
+        .unwrap();
+        assert!(matches!(
+            store.import_room_keys(export.as_bytes(), "wrong").await,
+            Err(SQLStoreError::KeyExport(_))
+        ));
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 2);
+        assert_eq!(result.imported_count, 1);
+        assert!(result.keys[room]
+            .values()
+            .any(|s| s.contains(session.session_id())));
+        let stored = store
+            .get_inbound_group_session(
+                room,
+                &session.sender_key().to_base64(),
+                session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.first_known_index(), 0);
+
+        let third_session = inbound_session(room);
+        let export = matrix_sdk_crypto::encrypt_room_key_export(
+            &[
+                third_session.export().await,
+                third_session.export_at_index(3).await,
+            ],
+            "pass",
+            10,
+        )
+        .unwrap();
+        let result = store
+            .import_room_keys(export.as_bytes(), "pass")
+            .await
+            .unwrap();
+        assert_eq!(result.total_count, 2);
+        assert_eq!(result.imported_count, 1);
+        let stored = store
+            .get_inbound_group_session(
+                room,
+                &third_session.sender_key().to_base64(),
+                third_session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.first_known_index(), 0);
+
+        let export = store.export_room_keys(None, "pass", 10).await.unwrap();
+        let keys = matrix_sdk_crypto::decrypt_room_key_export(export.as_bytes(), "pass").unwrap();
+        assert_eq!(keys.len(), 3);
+        let export = store
+            .export_room_keys(Some(other_room), "pass", 10)
+            .await
+            .unwrap();
+        let keys = matrix_sdk_crypto::decrypt_room_key_export(export.as_bytes(), "pass").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].session_id, other_session.session_id());
+    }
+}
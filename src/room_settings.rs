@@ -0,0 +1,254 @@
+//! Storage for the encryption policy of rooms
+
+use std::time::Duration;
+
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::{
+            encryption::RoomEncryptionEventContent,
+            member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        },
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    EventEncryptionAlgorithm, RoomId,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// The rotation period recommended by the specification, one week
+const DEFAULT_ROTATION_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The rotation message count recommended by the specification
+const DEFAULT_ROTATION_PERIOD_MSGS: u64 = 100;
+
+/// The encryption policy of a room
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// The algorithm used to encrypt messages
+    pub algorithm: EventEncryptionAlgorithm,
+    /// How long an outbound session is used before it is rotated
+    pub rotation_period: Duration,
+    /// How many messages are sent with an outbound session before it is rotated
+    pub rotation_period_msgs: u64,
+    /// Whether room keys are only shared with verified devices
+    pub only_allow_trusted_devices: bool,
+}
+
+impl RoomSettings {
+    /// Creates the settings described by the content of a `m.room.encryption` event
+    ///
+    /// Missing rotation periods are replaced by the defaults recommended by the specification.
+    #[must_use]
+    pub fn from_encryption_event(
+        content: &RoomEncryptionEventContent,
+        only_allow_trusted_devices: bool,
+    ) -> Self {
+        Self {
+            algorithm: content.algorithm.clone(),
+            rotation_period: content
+                .rotation_period_ms
+                .map_or(DEFAULT_ROTATION_PERIOD, |ms| {
+                    Duration::from_millis(ms.into())
+                }),
+            rotation_period_msgs: content
+                .rotation_period_msgs
+                .map_or(DEFAULT_ROTATION_PERIOD_MSGS, Into::into),
+            only_allow_trusted_devices,
+        }
+    }
+
+    /// Returns whether changing to `new` would weaken the encryption of the room
+    ///
+    /// This is the case if the algorithm changes, if sessions are rotated less often, or if
+    /// keys are no longer restricted to verified devices.
+    #[must_use]
+    pub fn is_downgraded_by(&self, new: &Self) -> bool {
+        self.algorithm != new.algorithm
+            || new.rotation_period > self.rotation_period
+            || new.rotation_period_msgs > self.rotation_period_msgs
+            || (self.only_allow_trusted_devices && !new.only_allow_trusted_devices)
+    }
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Stores the encryption settings of a room, replacing the previous ones
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn set_room_settings(&self, room_id: &RoomId, settings: &RoomSettings) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let room_id = e2e.encode_key("cryptostore_room_settings:room_id", room_id.as_bytes());
+        DB::room_settings_store_query()
+            .bind(room_id.as_ref())
+            .bind(e2e.encode_value(settings)?)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Retrieves the encryption settings of a room
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
+        let e2e = self.ensure_e2e()?;
+        let room_id = e2e.encode_key("cryptostore_room_settings:room_id", room_id.as_bytes());
+        let row = DB::room_settings_fetch_query()
+            .bind(room_id.as_ref())
+            .fetch_optional(&*self.db)
+            .await?;
+        if let Some(row) = row {
+            let data: Vec<u8> = row.try_get("settings_data")?;
+            Ok(Some(e2e.decode_value(&data)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use ruma::{
+        events::room::encryption::RoomEncryptionEventContent, room_id, uint,
+        EventEncryptionAlgorithm,
+    };
+
+    use super::RoomSettings;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    #[test]
+    fn test_room_settings_downgrade() {
+        let mut content =
+            RoomEncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2);
+        let settings = RoomSettings::from_encryption_event(&content, true);
+        assert_eq!(settings.rotation_period, Duration::from_secs(604_800));
+        assert_eq!(settings.rotation_period_msgs, 100);
+        assert!(!settings.is_downgraded_by(&settings));
+
+        content.rotation_period_msgs = Some(uint!(10));
+        let stricter = RoomSettings::from_encryption_event(&content, true);
+        assert!(!settings.is_downgraded_by(&stricter));
+        assert!(stricter.is_downgraded_by(&settings));
+        assert!(settings.is_downgraded_by(&RoomSettings::from_encryption_event(&content, false)));
+
+        content.rotation_period_msgs = None;
+        content.rotation_period_ms = Some(uint!(1_000_000_000));
+        assert!(settings.is_downgraded_by(&RoomSettings::from_encryption_event(&content, true)));
+        let content =
+            RoomEncryptionEventContent::new(EventEncryptionAlgorithm::OlmV1Curve25519AesSha2);
+        assert!(settings.is_downgraded_by(&RoomSettings::from_encryption_event(&content, true)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_room_settings() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        assert_eq!(store.get_room_settings(room).await.unwrap(), None);
+
+        let mut settings = RoomSettings::from_encryption_event(
+            &RoomEncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2),
+            true,
+        );
+        store.set_room_settings(room, &settings).await.unwrap();
+        assert_eq!(
+            store.get_room_settings(room).await.unwrap(),
+            Some(settings.clone())
+        );
+        assert_eq!(
+            store
+                .get_room_settings(room_id!("!other:localhost"))
+                .await
+                .unwrap(),
+            None
+        );
+
+        settings.rotation_period_msgs = 5;
+        settings.only_allow_trusted_devices = false;
+        store.set_room_settings(room, &settings).await.unwrap();
+        assert_eq!(store.get_room_settings(room).await.unwrap(), Some(settings));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_room_settings() {
+        let store = crate::schema::postgres_test_store("matrix_room_settings_test").await;
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        assert_eq!(store.get_room_settings(room).await.unwrap(), None);
+
+        let mut settings = RoomSettings::from_encryption_event(
+            &RoomEncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2),
+            true,
+        );
+        store.set_room_settings(room, &settings).await.unwrap();
+        assert_eq!(
+            store.get_room_settings(room).await.unwrap(),
+            Some(settings.clone())
+        );
+        assert_eq!(
+            store
+                .get_room_settings(room_id!("!other:localhost"))
+                .await
+                .unwrap(),
+            None
+        );
+
+        settings.rotation_period_msgs = 5;
+        settings.only_allow_trusted_devices = false;
+        store.set_room_settings(room, &settings).await.unwrap();
+        assert_eq!(store.get_room_settings(room).await.unwrap(), Some(settings));
+    }
+}
@@ -0,0 +1,492 @@
+//! Room listing with filters and pagination
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{
+    deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo, RoomType,
+};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    OwnedRoomId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{escape_like, BorrowedSqlType, SqlType},
+    Result, SQLStoreError, StateStore, SupportedDatabase,
+};
+
+/// Filter for [`StateStore::list_rooms`]
+///
+/// Every field that is set has to match. The default filter matches every room.
+#[derive(Clone, Debug, Default)]
+pub struct RoomFilter {
+    /// Only rooms in this state
+    pub state: Option<RoomType>,
+    /// Only encrypted or only unencrypted rooms
+    pub encrypted: Option<bool>,
+    /// Only direct messages or only other rooms
+    pub direct: Option<bool>,
+    /// Only rooms with this tag, like `m.favourite`
+    pub tag: Option<String>,
+    /// Only rooms whose name contains this string, ignoring case
+    pub name_contains: Option<String>,
+    /// Only rooms in this space, declared by either the space or the room
+    pub space_parent: Option<OwnedRoomId>,
+}
+
+/// Order of the rooms returned by [`StateStore::list_rooms`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RoomSort {
+    /// By room ID
+    #[default]
+    RoomId,
+    /// By room name, ignoring case, then by room ID. Rooms without a name come first.
+    Name,
+    /// Most recently stored room info first
+    RecentlyUpdated,
+}
+
+/// Position after the last room of a [`RoomListPage`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomCursor {
+    /// The order the cursor was created for
+    sort: RoomSort,
+    /// The ID of the last room
+    room_id: String,
+    /// The lowercased name of the last room
+    name_sort: String,
+    /// The update time of the last room
+    updated_at: i64,
+}
+
+/// A page of rooms returned by [`StateStore::list_rooms`]
+#[derive(Clone, Debug)]
+pub struct RoomListPage {
+    /// The rooms of this page
+    pub rooms: Vec<RoomInfo>,
+    /// The cursor for the next page, if there are more rooms
+    pub next: Option<RoomCursor>,
+}
+
+/// Returns the stored state of a room
+fn room_state(state: &RoomType) -> &'static str {
+    match state {
+        RoomType::Joined => "Joined",
+        RoomType::Left => "Left",
+        RoomType::Invited => "Invited",
+    }
+}
+
+/// Returns a `LIKE` pattern matching lowercased names that contain `needle`
+fn contains_pattern(needle: &str) -> String {
+    format!("%{}%", escape_like(&needle.to_lowercase()))
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<bool>: SqlType<DB>,
+    Option<i64>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Returns a page of at most `limit` rooms matching `filter`, ordered by `sort`
+    ///
+    /// Pass the [`RoomListPage::next`] cursor of a page as `after` to get the following page.
+    /// Rooms that change while paginating may be skipped or returned twice when sorting by name
+    /// or update time.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails, or if `after` was created for
+    /// a different order.
+    pub async fn list_rooms(
+        &self,
+        filter: &RoomFilter,
+        sort: RoomSort,
+        after: Option<&RoomCursor>,
+        limit: u32,
+    ) -> Result<RoomListPage> {
+        if matches!(after, Some(cursor) if cursor.sort != sort) {
+            return Err(SQLStoreError::InvalidCursor);
+        }
+        let mut rows = DB::room_list_query(sort)
+            .bind(
+                filter
+                    .state
+                    .as_ref()
+                    .map(|state| room_state(state).to_owned()),
+            )
+            .bind(filter.encrypted)
+            .bind(filter.direct)
+            .bind(filter.tag.clone())
+            .bind(filter.name_contains.as_deref().map(contains_pattern))
+            .bind(filter.space_parent.as_ref().map(ToString::to_string))
+            .bind(after.map(|cursor| cursor.room_id.clone()))
+            .bind(after.map(|cursor| cursor.name_sort.clone()))
+            .bind(after.map(|cursor| cursor.updated_at))
+            .bind(i64::from(limit) + 1)
+            .fetch(&*self.db);
+        let mut rooms = Vec::new();
+        let mut last = None;
+        while let Some(row) = rows.try_next().await? {
+            if rooms.len() == limit as usize {
+                // One more room than requested was fetched, so there is a next page
+                return Ok(RoomListPage { rooms, next: last });
+            }
+            last = Some(RoomCursor {
+                sort,
+                room_id: row.try_get("room_id")?,
+                name_sort: row.try_get("name_sort")?,
+                updated_at: row.try_get("counts_updated_at")?,
+            });
+            rooms.push((row.try_get::<'_, Json<RoomInfo>, _>("room_info")?).0);
+        }
+        Ok(RoomListPage { rooms, next: None })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_base::{RoomInfo, RoomType, StateChanges};
+    use ruma::{
+        events::{RoomAccountDataEventType, StateEventType},
+        room_id,
+        serde::Raw,
+        OwnedRoomId, RoomId,
+    };
+    use serde_json::{json, Value};
+
+    use super::{RoomFilter, RoomSort};
+    use crate::SQLStoreError;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates a room info with the given fields set in its serialized form
+    fn room_info(
+        room_id: &RoomId,
+        room_type: RoomType,
+        update: impl FnOnce(&mut Value),
+    ) -> RoomInfo {
+        let mut room_info = serde_json::to_value(RoomInfo::new(room_id, room_type)).unwrap();
+        update(&mut room_info["base_info"]);
+        serde_json::from_value(room_info).unwrap()
+    }
+
+    /// Sets the name of a serialized base room info
+    fn set_name(base_info: &mut Value, name: &str) {
+        base_info["name"] = json!({ "Original": { "content": { "name": name } } });
+    }
+
+    /// Returns changes that add five rooms in different states
+    fn changes() -> StateChanges {
+        let mut changes = StateChanges::default();
+        changes.add_room(room_info(
+            room_id!("!a:localhost"),
+            RoomType::Joined,
+            |info| {
+                set_name(info, "Work Chat");
+                info["encryption"] = json!({ "algorithm": "m.megolm.v1.aes-sha2" });
+            },
+        ));
+        changes.add_room(room_info(
+            room_id!("!b:localhost"),
+            RoomType::Joined,
+            |info| {
+                set_name(info, "alice_bob");
+                info["dm_targets"] = json!(["@bob:localhost"]);
+            },
+        ));
+        changes.add_room(room_info(room_id!("!c:localhost"), RoomType::Left, |_| {}));
+        changes.add_stripped_room(room_info(
+            room_id!("!d:localhost"),
+            RoomType::Invited,
+            |info| set_name(info, "aliceXbob"),
+        ));
+        changes.add_room(room_info(
+            room_id!("!e:localhost"),
+            RoomType::Joined,
+            |info| {
+                set_name(info, "100% Fun");
+            },
+        ));
+        changes
+            .room_account_data
+            .entry(room_id!("!a:localhost").to_owned())
+            .or_default()
+            .insert(
+                RoomAccountDataEventType::Tag,
+                Raw::new(&json!({
+                    "type": "m.tag",
+                    "content": { "tags": { "u.work": { "order": 0.5 } } },
+                }))
+                .unwrap()
+                .cast(),
+            );
+        changes
+            .state
+            .entry(room_id!("!a:localhost").to_owned())
+            .or_default()
+            .entry(StateEventType::SpaceParent)
+            .or_default()
+            .insert(
+                "!space:localhost".to_owned(),
+                Raw::new(&json!({
+                    "type": "m.space.parent",
+                    "state_key": "!space:localhost",
+                    "content": { "via": ["localhost"], "canonical": true },
+                    "event_id": "$parent",
+                    "sender": "@alice:localhost",
+                    "origin_server_ts": 1,
+                }))
+                .unwrap()
+                .cast(),
+            );
+        changes
+    }
+
+    /// Returns the room IDs of room infos
+    fn room_ids(rooms: &[RoomInfo]) -> Vec<OwnedRoomId> {
+        rooms.iter().map(|room| room.room_id().to_owned()).collect()
+    }
+
+    /// Returns the filters of the test, with the rooms they match
+    fn filters() -> Vec<(RoomFilter, Vec<&'static str>)> {
+        vec![
+            (
+                RoomFilter {
+                    state: Some(RoomType::Joined),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost", "!b:localhost", "!e:localhost"],
+            ),
+            (
+                RoomFilter {
+                    state: Some(RoomType::Invited),
+                    ..RoomFilter::default()
+                },
+                vec!["!d:localhost"],
+            ),
+            (
+                RoomFilter {
+                    encrypted: Some(true),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost"],
+            ),
+            (
+                RoomFilter {
+                    direct: Some(false),
+                    state: Some(RoomType::Joined),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost", "!e:localhost"],
+            ),
+            (
+                RoomFilter {
+                    tag: Some("u.work".to_owned()),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost"],
+            ),
+            (
+                RoomFilter {
+                    name_contains: Some("CHAT".to_owned()),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost"],
+            ),
+            (
+                RoomFilter {
+                    name_contains: Some("e_b".to_owned()),
+                    ..RoomFilter::default()
+                },
+                vec!["!b:localhost"],
+            ),
+            (
+                RoomFilter {
+                    name_contains: Some("0%".to_owned()),
+                    ..RoomFilter::default()
+                },
+                vec!["!e:localhost"],
+            ),
+            (
+                RoomFilter {
+                    space_parent: Some(room_id!("!space:localhost").to_owned()),
+                    ..RoomFilter::default()
+                },
+                vec!["!a:localhost"],
+            ),
+        ]
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_list_rooms() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.save_state_changes(&changes()).await.unwrap();
+
+        for (filter, expected) in filters() {
+            let page = store
+                .list_rooms(&filter, RoomSort::RoomId, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(room_ids(&page.rooms), expected, "{:?}", filter);
+            assert!(page.next.is_none());
+        }
+
+        let all = RoomFilter::default();
+        let page = store
+            .list_rooms(&all, RoomSort::Name, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!c:localhost", "!e:localhost"]);
+        let page = store
+            .list_rooms(&all, RoomSort::Name, page.next.as_ref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!b:localhost", "!d:localhost"]);
+        let next = page.next.unwrap();
+        let page = store
+            .list_rooms(&all, RoomSort::Name, Some(&next), 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!a:localhost"]);
+        assert!(page.next.is_none());
+        assert!(matches!(
+            store
+                .list_rooms(&all, RoomSort::RoomId, Some(&next), 2)
+                .await,
+            Err(SQLStoreError::InvalidCursor)
+        ));
+
+        let mut rooms = Vec::new();
+        let mut next = None;
+        loop {
+            let page = store
+                .list_rooms(&all, RoomSort::RecentlyUpdated, next.as_ref(), 2)
+                .await
+                .unwrap();
+            rooms.extend(room_ids(&page.rooms));
+            next = page.next;
+            if next.is_none() {
+                break;
+            }
+        }
+        rooms.sort();
+        assert_eq!(
+            rooms,
+            [
+                "!a:localhost",
+                "!b:localhost",
+                "!c:localhost",
+                "!d:localhost",
+                "!e:localhost"
+            ]
+        );
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_list_rooms() {
+        let store = crate::schema::postgres_test_store("matrix_rooms_test").await;
+        store.save_state_changes(&changes()).await.unwrap();
+
+        for (filter, expected) in filters() {
+            let page = store
+                .list_rooms(&filter, RoomSort::RoomId, None, 10)
+                .await
+                .unwrap();
+            assert_eq!(room_ids(&page.rooms), expected, "{:?}", filter);
+            assert!(page.next.is_none());
+        }
+
+        let all = RoomFilter::default();
+        let page = store
+            .list_rooms(&all, RoomSort::Name, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!c:localhost", "!e:localhost"]);
+        let page = store
+            .list_rooms(&all, RoomSort::Name, page.next.as_ref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!b:localhost", "!d:localhost"]);
+        let next = page.next.unwrap();
+        let page = store
+            .list_rooms(&all, RoomSort::Name, Some(&next), 2)
+            .await
+            .unwrap();
+        assert_eq!(room_ids(&page.rooms), ["!a:localhost"]);
+        assert!(page.next.is_none());
+        assert!(matches!(
+            store
+                .list_rooms(&all, RoomSort::RoomId, Some(&next), 2)
+                .await,
+            Err(SQLStoreError::InvalidCursor)
+        ));
+
+        let mut rooms = Vec::new();
+        let mut next = None;
+        loop {
+            let page = store
+                .list_rooms(&all, RoomSort::RecentlyUpdated, next.as_ref(), 2)
+                .await
+                .unwrap();
+            rooms.extend(room_ids(&page.rooms));
+            next = page.next;
+            if next.is_none() {
+                break;
+            }
+        }
+        rooms.sort();
+        assert_eq!(
+            rooms,
+            [
+                "!a:localhost",
+                "!b:localhost",
+                "!c:localhost",
+                "!d:localhost",
+                "!e:localhost"
+            ]
+        );
+    }
+}
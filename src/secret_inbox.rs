@@ -0,0 +1,296 @@
+//! Storage for secrets received from other devices
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        secret::request::SecretName,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    OwnedDeviceId, OwnedTransactionId, OwnedUserId,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// A secret that was sent to us by another device
+#[derive(Clone, Serialize, Deserialize, educe::Educe)]
+#[educe(Debug)]
+pub struct ReceivedSecret {
+    /// The name of the secret
+    pub secret_name: SecretName,
+    /// The ID of the request the secret was sent in response to
+    pub request_id: OwnedTransactionId,
+    /// The user that sent the secret
+    pub sender: OwnedUserId,
+    /// The device that sent the secret
+    pub sender_device: OwnedDeviceId,
+    /// The secret itself, zeroized when it is dropped
+    #[educe(Debug(ignore))]
+    #[serde(with = "zeroizing_string")]
+    pub secret: Zeroizing<String>,
+}
+
+/// Serde support for zeroized strings
+mod zeroizing_string {
+    use super::{Deserialize, Deserializer, Serializer, Zeroizing};
+
+    /// Serializes the string
+    pub(super) fn serialize<S: Serializer>(
+        value: &Zeroizing<String>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(value)
+    }
+
+    /// Deserializes the string
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Zeroizing<String>, D::Error> {
+        String::deserialize(deserializer).map(Zeroizing::new)
+    }
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Stores a received secret until it is processed
+    ///
+    /// Secrets are encrypted like the rest of the cryptostore. Several secrets with the same name
+    /// may be stored, for example if more than one device answered a request.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn save_secret_to_inbox(&self, secret: &ReceivedSecret) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let secret_name = e2e.encode_key(
+            "cryptostore_secret_inbox:secret_name",
+            secret.secret_name.as_str().as_bytes(),
+        );
+        DB::secret_inbox_store_query()
+            .bind(secret_name.as_ref())
+            .bind(e2e.encode_value(secret)?)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Retrieves the received secrets with the given name, oldest first
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn get_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<Vec<ReceivedSecret>> {
+        let e2e = self.ensure_e2e()?;
+        let secret_name = e2e.encode_key(
+            "cryptostore_secret_inbox:secret_name",
+            secret_name.as_str().as_bytes(),
+        );
+        let mut rows = DB::secret_inbox_fetch_query()
+            .bind(secret_name.as_ref())
+            .fetch(&*self.db);
+        let mut secrets = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let data: Vec<u8> = row.try_get("secret_data")?;
+            secrets.push(e2e.decode_value(&data)?);
+        }
+        Ok(secrets)
+    }
+
+    /// Deletes the received secrets with the given name
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn delete_secrets_from_inbox(&self, secret_name: &SecretName) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let secret_name = e2e.encode_key(
+            "cryptostore_secret_inbox:secret_name",
+            secret_name.as_str().as_bytes(),
+        );
+        DB::secret_inbox_delete_query()
+            .bind(secret_name.as_ref())
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use ruma::{device_id, events::secret::request::SecretName, user_id, TransactionId};
+
+    use super::ReceivedSecret;
+    use crate::SQLStoreError;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Creates a received secret
+    fn secret(secret_name: SecretName, secret: &str) -> ReceivedSecret {
+        ReceivedSecret {
+            secret_name,
+            request_id: TransactionId::new(),
+            sender: user_id!("@alice:localhost").to_owned(),
+            sender_device: device_id!("ALICEDEVICE").to_owned(),
+            secret: secret.to_owned().into(),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_secret_inbox() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let backup = secret(SecretName::RecoveryKey, "backup key");
+        assert!(matches!(
+            store.save_secret_to_inbox(&backup).await,
+            Err(SQLStoreError::DatabaseLocked)
+        ));
+        store.unlock().await.unwrap();
+
+        let first = secret(SecretName::CrossSigningMasterKey, "first");
+        let second = secret(SecretName::CrossSigningMasterKey, "second");
+        store.save_secret_to_inbox(&first).await.unwrap();
+        store.save_secret_to_inbox(&backup).await.unwrap();
+        store.save_secret_to_inbox(&second).await.unwrap();
+        assert!(!format!("{:?}", first).contains("first"));
+
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets
+                .iter()
+                .map(|s| s.secret.as_str())
+                .collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+        assert_eq!(secrets[0].request_id, first.request_id);
+        assert_eq!(secrets[1].sender_device, second.sender_device);
+        assert!(store
+            .get_secrets_from_inbox(&SecretName::CrossSigningSelfSigningKey)
+            .await
+            .unwrap()
+            .is_empty());
+
+        store
+            .delete_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+        assert!(store
+            .get_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap()
+            .is_empty());
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::RecoveryKey)
+            .await
+            .unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].secret.as_str(), "backup key");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_secret_inbox() {
+        let store = crate::schema::postgres_test_store("matrix_secret_inbox_test").await;
+        let backup = secret(SecretName::RecoveryKey, "backup key");
+        assert!(matches!(
+            store.save_secret_to_inbox(&backup).await,
+            Err(SQLStoreError::DatabaseLocked)
+        ));
+        store.unlock().await.unwrap();
+
+        let first = secret(SecretName::CrossSigningMasterKey, "first");
+        let second = secret(SecretName::CrossSigningMasterKey, "second");
+        store.save_secret_to_inbox(&first).await.unwrap();
+        store.save_secret_to_inbox(&backup).await.unwrap();
+        store.save_secret_to_inbox(&second).await.unwrap();
+        assert!(!format!("{:?}", first).contains("first"));
+
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets
+                .iter()
+                .map(|s| s.secret.as_str())
+                .collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+        assert_eq!(secrets[0].request_id, first.request_id);
+        assert_eq!(secrets[1].sender_device, second.sender_device);
+        assert!(store
+            .get_secrets_from_inbox(&SecretName::CrossSigningSelfSigningKey)
+            .await
+            .unwrap()
+            .is_empty());
+
+        store
+            .delete_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap();
+        assert!(store
+            .get_secrets_from_inbox(&SecretName::CrossSigningMasterKey)
+            .await
+            .unwrap()
+            .is_empty());
+        let secrets = store
+            .get_secrets_from_inbox(&SecretName::RecoveryKey)
+            .await
+            .unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].secret.as_str(), "backup key");
+    }
+}
@@ -0,0 +1,477 @@
+//! Online snapshots of the whole store and restoring from them
+//!
+//! Snapshots contain the raw rows of all tables, so cryptostore contents stay encrypted with the
+//! store cipher. They record the migration version of the store they were taken from and can only
+//! be restored into a store at the same version.
+
+use std::path::Path;
+
+use crate::{maintenance::TABLES, Result, SQLStoreError, StateStore};
+
+/// Returns the path as a string for use in SQL
+///
+/// # Errors
+/// This function will return an error if the path is not valid UTF-8
+#[cfg(feature = "sqlite")]
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        SQLStoreError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "snapshot path is not valid UTF-8",
+        ))
+    })
+}
+
+#[cfg(feature = "sqlite")]
+impl StateStore<sqlx::Sqlite> {
+    /// Writes a consistent snapshot of the store to a new database file
+    ///
+    /// The snapshot is taken with `VACUUM INTO` while the store stays usable. The file must not
+    /// exist yet. In-memory databases write the snapshot into memory, so the store has to be backed
+    /// by a file.
+    ///
+    /// # Errors
+    /// This function will return an error if the file exists or cannot be written
+    pub async fn snapshot_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        // Older SQLite versions overwrite existing files, an empty file is accepted by all
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())?;
+        let result = self.vacuum_into(path.as_ref()).await;
+        if result.is_err() {
+            // Do not leave an incomplete snapshot behind
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    /// Writes the database into an empty file with `VACUUM INTO`
+    ///
+    /// # Errors
+    /// This function will return an error if the path is not valid UTF-8 or the query fails
+    async fn vacuum_into(&self, path: &Path) -> Result<()> {
+        sqlx::query("VACUUM INTO $1")
+            .bind(path_str(path)?)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the contents of the store with a snapshot written by [`Self::snapshot_to`]
+    ///
+    /// All tables are replaced in one transaction. Reopen and unlock the store afterwards, as the
+    /// cryptostore cipher may have changed.
+    ///
+    /// # Errors
+    /// This function will return an error if the snapshot cannot be read, or if it was taken at a
+    /// different migration version than the store is at
+    pub async fn restore_from(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut conn = self.db.acquire().await?;
+        sqlx::query("ATTACH DATABASE $1 AS snapshot")
+            .bind(path_str(path.as_ref())?)
+            .execute(&mut *conn)
+            .await?;
+        let result = Self::restore_attached(&mut conn).await;
+        sqlx::query("DETACH DATABASE snapshot")
+            .execute(&mut *conn)
+            .await?;
+        result
+    }
+
+    /// Copies all tables from the attached snapshot database
+    ///
+    /// # Errors
+    /// This function will return an error if the versions differ or a query fails
+    async fn restore_attached(conn: &mut sqlx::SqliteConnection) -> Result<()> {
+        use sqlx::Connection;
+
+        let snapshot: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM snapshot._sqlx_migrations WHERE success")
+                .fetch_one(&mut *conn)
+                .await?;
+        let store: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM main._sqlx_migrations WHERE success")
+                .fetch_one(&mut *conn)
+                .await?;
+        if snapshot != store {
+            return Err(SQLStoreError::SnapshotVersion {
+                snapshot: snapshot.unwrap_or_default(),
+                store: store.unwrap_or_default(),
+            });
+        }
+
+        let mut txn = conn.begin().await?;
+        for table in TABLES {
+            sqlx::query(&format!("DELETE FROM main.{}", table))
+                .execute(&mut txn)
+                .await?;
+            sqlx::query(&format!(
+                "INSERT INTO main.{0} SELECT * FROM snapshot.{0}",
+                table
+            ))
+            .execute(&mut txn)
+            .await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+}
+
+/// Identifies postgres snapshot files
+#[cfg(feature = "postgres")]
+const SNAPSHOT_FORMAT: &str = "matrix-sdk-sql";
+
+/// Number of rows restored per statement
+#[cfg(feature = "postgres")]
+const RESTORE_BATCH_SIZE: usize = 500;
+
+/// Identity columns whose sequences are advanced after a restore
+#[cfg(feature = "postgres")]
+const IDENTITY_COLUMNS: &[(&str, &str)] = &[
+    ("statestore_state_history", "history_id"),
+    ("statestore_membership_history", "history_id"),
+    ("statestore_checkpoints", "checkpoint_id"),
+    ("cryptostore_secret_inbox", "secret_id"),
+    ("cryptostore_identity_change", "change_id"),
+];
+
+#[cfg(feature = "postgres")]
+impl StateStore<sqlx::Postgres> {
+    /// Writes a consistent snapshot of the store to a new file
+    ///
+    /// The rows of all tables are dumped as JSON lines inside a `REPEATABLE READ` transaction,
+    /// while the store stays usable. The file must not exist yet.
+    ///
+    /// # Errors
+    /// This function will return an error if the file exists or cannot be written
+    pub async fn snapshot_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut txn = self.db.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut txn)
+            .await?;
+        let version: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&mut txn)
+                .await?;
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())?;
+        let result = Self::dump(&mut txn, version, file).await;
+        if result.is_err() {
+            // Do not leave an incomplete snapshot behind
+            let _ = std::fs::remove_file(path);
+            return result;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Writes the snapshot header and the rows of all tables to a file
+    ///
+    /// # Errors
+    /// This function will return an error if a query fails or the file cannot be written
+    async fn dump(
+        txn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        version: Option<i64>,
+        file: std::fs::File,
+    ) -> Result<()> {
+        use std::io::Write;
+
+        use futures::TryStreamExt;
+        use sqlx::Row;
+
+        let mut file = std::io::BufWriter::new(file);
+        writeln!(
+            file,
+            "{}",
+            serde_json::json!({ "format": SNAPSHOT_FORMAT, "version": version })
+        )?;
+        for table in TABLES {
+            let query = format!(
+                "SELECT jsonb_build_object('table', '{0}', 'row', to_jsonb(t))::TEXT AS line FROM {0} t",
+                table
+            );
+            let mut rows = sqlx::query(&query).fetch(&mut *txn);
+            while let Some(row) = rows.try_next().await? {
+                writeln!(file, "{}", row.try_get::<'_, String, _>("line")?)?;
+            }
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Replaces the contents of the store with a snapshot written by [`Self::snapshot_to`]
+    ///
+    /// All tables are replaced in one transaction. Reopen and unlock the store afterwards, as the
+    /// cryptostore cipher may have changed.
+    ///
+    /// # Errors
+    /// This function will return an error if the snapshot cannot be read, or if it was taken at a
+    /// different migration version than the store is at
+    pub async fn restore_from(&self, path: impl AsRef<Path>) -> Result<()> {
+        use std::io::BufRead;
+
+        use serde_json::Value;
+
+        let mut lines = std::io::BufReader::new(std::fs::File::open(path)?).lines();
+        let header: Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => {
+                return Err(SQLStoreError::InvalidSnapshot(
+                    "the file is empty".to_owned(),
+                ))
+            }
+        };
+        if header["format"] != SNAPSHOT_FORMAT {
+            return Err(SQLStoreError::InvalidSnapshot(
+                "the file is not a snapshot".to_owned(),
+            ));
+        }
+        let snapshot = header["version"].as_i64();
+
+        let mut txn = self.db.begin().await?;
+        let store: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&mut txn)
+                .await?;
+        if snapshot != store {
+            return Err(SQLStoreError::SnapshotVersion {
+                snapshot: snapshot.unwrap_or_default(),
+                store: store.unwrap_or_default(),
+            });
+        }
+        sqlx::query(&format!("TRUNCATE {}", TABLES.join(", ")))
+            .execute(&mut txn)
+            .await?;
+
+        let mut table = "";
+        let mut batch = Vec::new();
+        for line in lines {
+            let mut entry: Value = serde_json::from_str(&line?)?;
+            let entry_table = entry["table"]
+                .as_str()
+                .and_then(|name| TABLES.iter().find(|table| **table == name))
+                .ok_or_else(|| {
+                    SQLStoreError::InvalidSnapshot(format!("unknown table {}", entry["table"]))
+                })?;
+            if *entry_table != table || batch.len() >= RESTORE_BATCH_SIZE {
+                Self::restore_rows(&mut txn, table, &mut batch).await?;
+                table = entry_table;
+            }
+            batch.push(entry["row"].take());
+        }
+        Self::restore_rows(&mut txn, table, &mut batch).await?;
+
+        for (table, column) in IDENTITY_COLUMNS {
+            sqlx::query(&format!(
+                "SELECT setval(pg_get_serial_sequence('{0}', '{1}'), COALESCE((SELECT MAX({1}) FROM {0}), 0) + 1, false)",
+                table, column
+            ))
+            .execute(&mut txn)
+            .await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Inserts a batch of snapshot rows into a table and clears the batch
+    ///
+    /// # Errors
+    /// This function will return an error if the rows do not match the table
+    async fn restore_rows(
+        txn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        table: &str,
+        batch: &mut Vec<serde_json::Value>,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(&format!(
+            "INSERT INTO {0} OVERRIDING SYSTEM VALUE SELECT * FROM jsonb_populate_recordset(NULL::{0}, $1)",
+            table
+        ))
+        .bind(sqlx::types::Json(std::mem::take(batch)))
+        .execute(&mut *txn)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use matrix_sdk_base::{StateChanges, StateStore as _};
+    use ruma::{events::StateEventType, room_id, serde::Raw};
+    use serde_json::{json, Value};
+
+    use crate::{SQLStoreError, StateStore};
+
+    /// Returns changes that set the topic of the test room
+    fn topic_changes(topic: &str) -> StateChanges {
+        let mut changes = StateChanges::new(format!("token-{}", topic));
+        changes
+            .state
+            .entry(room_id!("!snapshot:localhost").to_owned())
+            .or_default()
+            .entry(StateEventType::RoomTopic)
+            .or_default()
+            .insert(
+                String::new(),
+                Raw::new(&json!({
+                    "content": { "topic": topic },
+                    "event_id": format!("${}", topic),
+                    "origin_server_ts": 1,
+                    "sender": "@alice:localhost",
+                    "state_key": "",
+                    "type": "m.room.topic",
+                }))
+                .unwrap()
+                .cast(),
+            );
+        changes
+    }
+
+    /// Returns the topic of the test room
+    async fn topic<DB: crate::SupportedDatabase>(store: &StateStore<DB>) -> Value
+    where
+        StateStore<DB>: matrix_sdk_base::StateStore,
+    {
+        let event = store
+            .get_state_event(
+                room_id!("!snapshot:localhost"),
+                StateEventType::RoomTopic,
+                "",
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        event.deserialize_as::<Value>().unwrap()["content"]["topic"].clone()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(
+            sqlx::SqlitePool::connect(&format!(
+                "sqlite://{}?mode=rwc",
+                dir.path().join("store").to_string_lossy()
+            ))
+            .await
+            .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let path = dir.path().join("snapshot");
+        store
+            .save_state_changes(&topic_changes("before"))
+            .await
+            .unwrap();
+        store.set_custom_value(b"key", b"value").await.unwrap();
+        store.snapshot_to(&path).await.unwrap();
+        assert!(store.snapshot_to(&path).await.is_err());
+
+        // A failed snapshot does not leave a file behind
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+            let invalid = dir.path().join(OsStr::from_bytes(b"snapshot-\xff"));
+            assert!(store.snapshot_to(&invalid).await.is_err());
+            assert!(!invalid.exists());
+        }
+
+        store
+            .save_state_changes(&topic_changes("after"))
+            .await
+            .unwrap();
+        store.remove_custom_value(b"key").await.unwrap();
+        assert_eq!(topic(&store).await, "after");
+
+        store.restore_from(&path).await.unwrap();
+        assert_eq!(topic(&store).await, "before");
+        assert_eq!(
+            store.get_sync_token().await.unwrap().as_deref(),
+            Some("token-before")
+        );
+        assert_eq!(
+            store.get_custom_value(b"key").await.unwrap().as_deref(),
+            Some(b"value".as_slice())
+        );
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 1);
+        store
+            .save_state_changes(&topic_changes("again"))
+            .await
+            .unwrap();
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 2);
+
+        sqlx::query(
+            "DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)",
+        )
+        .execute(&*db)
+        .await
+        .unwrap();
+        assert!(matches!(
+            store.restore_from(&path).await,
+            Err(SQLStoreError::SnapshotVersion { .. })
+        ));
+        assert_eq!(topic(&store).await, "again");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_snapshot() {
+        let store = crate::schema::postgres_test_store("matrix_snapshot_test").await;
+        let db = Arc::clone(&store.db);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        store
+            .save_state_changes(&topic_changes("before"))
+            .await
+            .unwrap();
+        store.set_custom_value(b"key", b"value").await.unwrap();
+        store.snapshot_to(&path).await.unwrap();
+        assert!(store.snapshot_to(&path).await.is_err());
+
+        store
+            .save_state_changes(&topic_changes("after"))
+            .await
+            .unwrap();
+        store.remove_custom_value(b"key").await.unwrap();
+        assert_eq!(topic(&store).await, "after");
+
+        store.restore_from(&path).await.unwrap();
+        assert_eq!(topic(&store).await, "before");
+        assert_eq!(
+            store.get_sync_token().await.unwrap().as_deref(),
+            Some("token-before")
+        );
+        assert_eq!(
+            store.get_custom_value(b"key").await.unwrap().as_deref(),
+            Some(b"value".as_slice())
+        );
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 1);
+        store
+            .save_state_changes(&topic_changes("again"))
+            .await
+            .unwrap();
+        assert_eq!(store.sync_checkpoints().await.unwrap().len(), 2);
+
+        sqlx::query(
+            "DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)",
+        )
+        .execute(&*db)
+        .await
+        .unwrap();
+        assert!(matches!(
+            store.restore_from(&path).await,
+            Err(SQLStoreError::SnapshotVersion { .. })
+        ));
+        assert_eq!(topic(&store).await, "again");
+    }
+}
@@ -0,0 +1,484 @@
+//! Space hierarchy
+
+use std::cmp::Ordering;
+
+use futures::TryStreamExt;
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    OwnedRoomId, OwnedServerName, RoomId,
+};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// A child room of a space, from an `m.space.child` event of the space
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpaceChild {
+    /// The ID of the child room
+    pub room_id: OwnedRoomId,
+    /// Servers to join the child room through
+    pub via: Vec<OwnedServerName>,
+    /// The order of the child in the space
+    pub order: Option<String>,
+    /// Whether or not the space suggests joining the child room
+    pub suggested: bool,
+}
+
+impl SpaceChild {
+    /// Returns the order if it is valid, children with an invalid order are sorted like
+    /// children without one
+    fn valid_order(&self) -> Option<&str> {
+        self.order
+            .as_deref()
+            .filter(|order| order.len() <= 50 && order.bytes().all(|b| (0x20..=0x7e).contains(&b)))
+    }
+
+    /// Compares children in the order they should be shown in the space
+    fn cmp_order(&self, other: &Self) -> Ordering {
+        match (self.valid_order(), other.valid_order()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+        .then_with(|| self.room_id.cmp(&other.room_id))
+    }
+}
+
+/// A parent space of a room, from an `m.space.parent` event of the room
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpaceParent {
+    /// The ID of the parent space
+    pub room_id: OwnedRoomId,
+    /// Servers to join the parent space through
+    pub via: Vec<OwnedServerName>,
+    /// Whether or not this is the main parent of the room
+    pub canonical: bool,
+}
+
+/// A room in the tree of a space, returned by [`StateStore::space_tree`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpaceTreeEntry {
+    /// The ID of the space containing the room
+    pub parent_id: OwnedRoomId,
+    /// The room
+    pub child: SpaceChild,
+    /// The distance from the root of the tree, starting with 1 for its children
+    pub depth: u32,
+}
+
+/// Decodes an `m.space.child` event of a space
+fn decode_child(
+    room_id: OwnedRoomId,
+    event: &Raw<AnySyncStateEvent>,
+) -> Result<Option<SpaceChild>> {
+    if let AnySyncStateEvent::SpaceChild(event) = event.deserialize()? {
+        Ok(event.as_original().map(|event| SpaceChild {
+            room_id,
+            via: event.content.via.clone().unwrap_or_default(),
+            order: event.content.order.clone(),
+            suggested: event.content.suggested.unwrap_or_default(),
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'a, 'c> &'c mut Transaction<'a, DB>: Executor<'c, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    String: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Returns the children of a space, in the order of the space
+    ///
+    /// Children are ordered by their `order` field, then by room ID.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails
+    pub async fn space_children(&self, space_id: &RoomId) -> Result<Vec<SpaceChild>> {
+        let mut rows = DB::space_children_load_query()
+            .bind(space_id.as_str())
+            .fetch(&*self.db);
+        let mut children = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let room_id: String = row.try_get("child_id")?;
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            children.extend(decode_child(room_id.try_into()?, &event.0)?);
+        }
+        children.sort_by(SpaceChild::cmp_order);
+        Ok(children)
+    }
+
+    /// Returns the spaces a room declares as its parents
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails
+    pub async fn space_parents(&self, room_id: &RoomId) -> Result<Vec<SpaceParent>> {
+        let mut rows = DB::space_parents_load_query()
+            .bind(room_id.as_str())
+            .fetch(&*self.db);
+        let mut parents = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let parent_id: String = row.try_get("parent_id")?;
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            if let AnySyncStateEvent::SpaceParent(event) = event.0.deserialize()? {
+                if let Some(event) = event.as_original() {
+                    parents.push(SpaceParent {
+                        room_id: parent_id.try_into()?,
+                        via: event.content.via.clone().unwrap_or_default(),
+                        canonical: event.content.canonical,
+                    });
+                }
+            }
+        }
+        parents.sort_by(|a, b| {
+            b.canonical
+                .cmp(&a.canonical)
+                .then_with(|| a.room_id.cmp(&b.room_id))
+        });
+        Ok(parents)
+    }
+
+    /// Returns the rooms in a space and its subspaces, up to `max_depth` levels deep
+    ///
+    /// A room that is reachable through several spaces is returned once for each of them. Each
+    /// path through the tree ends before it would revisit a room, so cycles between spaces are
+    /// safe. Entries are ordered by depth, then by parent, then in the order of the parent.
+    ///
+    /// # Errors
+    /// This function will return an error if the the query fails
+    pub async fn space_tree(
+        &self,
+        space_id: &RoomId,
+        max_depth: u32,
+    ) -> Result<Vec<SpaceTreeEntry>> {
+        let mut rows = DB::space_tree_load_query()
+            .bind(space_id.as_str())
+            .bind(i64::from(max_depth))
+            .fetch(&*self.db);
+        let mut entries = Vec::new();
+        while let Some(row) = rows.try_next().await? {
+            let parent_id: String = row.try_get("parent_id")?;
+            let child_id: String = row.try_get("child_id")?;
+            let depth: i64 = row.try_get("depth")?;
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            if let Some(child) = decode_child(child_id.try_into()?, &event.0)? {
+                entries.push(SpaceTreeEntry {
+                    parent_id: parent_id.try_into()?,
+                    child,
+                    depth: depth.try_into().unwrap_or_default(),
+                });
+            }
+        }
+        // Cycles can reach the same edge through different paths
+        entries.sort_by(|a, b| {
+            a.depth
+                .cmp(&b.depth)
+                .then_with(|| a.parent_id.cmp(&b.parent_id))
+                .then_with(|| a.child.cmp_order(&b.child))
+        });
+        entries.dedup_by(|a, b| {
+            a.parent_id == b.parent_id && a.child.room_id == b.child.room_id && a.depth == b.depth
+        });
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use matrix_sdk_base::StateChanges;
+    use ruma::{
+        events::{room::redaction::OriginalSyncRoomRedactionEvent, StateEventType},
+        room_id,
+        serde::Raw,
+        OwnedRoomId,
+    };
+    use serde_json::{json, Value};
+
+    use super::SpaceTreeEntry;
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    /// Adds a space state event to changes
+    fn add_event(
+        changes: &mut StateChanges,
+        room_id: &str,
+        event_type: StateEventType,
+        state_key: &str,
+        content: Value,
+    ) {
+        changes
+            .state
+            .entry(room_id.try_into().unwrap())
+            .or_default()
+            .entry(event_type.clone())
+            .or_default()
+            .insert(
+                state_key.to_owned(),
+                Raw::new(&json!({
+                    "content": content,
+                    "event_id": format!("${}-{}", event_type, state_key),
+                    "origin_server_ts": 1,
+                    "sender": "@admin:localhost",
+                    "state_key": state_key,
+                    "type": event_type.to_string(),
+                }))
+                .unwrap()
+                .cast(),
+            );
+    }
+
+    /// Returns changes that build a space with a subspace that contains the space again
+    fn changes() -> StateChanges {
+        let mut changes = StateChanges::default();
+        for (space, child, content) in [
+            (
+                "!root:localhost",
+                "!a:localhost",
+                json!({ "via": ["localhost"], "order": "b" }),
+            ),
+            (
+                "!root:localhost",
+                "!b:localhost",
+                json!({ "via": ["localhost"], "order": "a", "suggested": true }),
+            ),
+            (
+                "!root:localhost",
+                "!sub:localhost",
+                json!({ "via": ["localhost"] }),
+            ),
+            ("!root:localhost", "!gone:localhost", json!({})),
+            (
+                "!sub:localhost",
+                "!c:localhost",
+                json!({ "via": ["localhost"] }),
+            ),
+            (
+                "!sub:localhost",
+                "!root:localhost",
+                json!({ "via": ["localhost"] }),
+            ),
+        ] {
+            add_event(
+                &mut changes,
+                space,
+                StateEventType::SpaceChild,
+                child,
+                content,
+            );
+        }
+        for (room, parent, canonical) in [
+            ("!a:localhost", "!other:localhost", false),
+            ("!a:localhost", "!root:localhost", true),
+        ] {
+            add_event(
+                &mut changes,
+                room,
+                StateEventType::SpaceParent,
+                parent,
+                json!({ "via": ["localhost"], "canonical": canonical }),
+            );
+        }
+        changes
+    }
+
+    /// Returns changes that redact an event
+    fn redaction(room_id: &str, event_id: &str) -> StateChanges {
+        let redaction: OriginalSyncRoomRedactionEvent = serde_json::from_value(json!({
+            "content": {},
+            "event_id": "$redaction",
+            "origin_server_ts": 2,
+            "redacts": event_id,
+            "sender": "@admin:localhost",
+            "type": "m.room.redaction",
+        }))
+        .unwrap();
+        let mut changes = StateChanges::default();
+        changes.add_redaction(room_id.try_into().unwrap(), redaction);
+        changes
+    }
+
+    /// Returns the parent, child and depth of tree entries
+    fn edges(entries: &[SpaceTreeEntry]) -> Vec<(&str, &str, u32)> {
+        entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.parent_id.as_str(),
+                    entry.child.room_id.as_str(),
+                    entry.depth,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns room IDs as strings
+    fn room_ids<'a>(room_ids: impl IntoIterator<Item = &'a OwnedRoomId>) -> Vec<&'a str> {
+        room_ids
+            .into_iter()
+            .map(|room_id| room_id.as_str())
+            .collect()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_space_hierarchy() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        let root = room_id!("!root:localhost");
+        store.save_state_changes(&changes()).await.unwrap();
+
+        let children = store.space_children(root).await.unwrap();
+        assert_eq!(
+            room_ids(children.iter().map(|child| &child.room_id)),
+            ["!b:localhost", "!a:localhost", "!sub:localhost"]
+        );
+        assert!(children[0].suggested);
+        assert_eq!(children[1].order.as_deref(), Some("b"));
+
+        let parents = store.space_parents(room_id!("!a:localhost")).await.unwrap();
+        assert_eq!(
+            room_ids(parents.iter().map(|parent| &parent.room_id)),
+            ["!root:localhost", "!other:localhost"]
+        );
+        assert!(parents[0].canonical);
+
+        let tree = store.space_tree(root, 10).await.unwrap();
+        assert_eq!(
+            edges(&tree),
+            [
+                ("!root:localhost", "!b:localhost", 1),
+                ("!root:localhost", "!a:localhost", 1),
+                ("!root:localhost", "!sub:localhost", 1),
+                ("!sub:localhost", "!c:localhost", 2),
+            ]
+        );
+        assert_eq!(store.space_tree(root, 1).await.unwrap().len(), 3);
+
+        // Removing the via or redacting the event removes the child
+        let mut changes = StateChanges::default();
+        add_event(
+            &mut changes,
+            "!root:localhost",
+            StateEventType::SpaceChild,
+            "!sub:localhost",
+            json!({}),
+        );
+        store.save_state_changes(&changes).await.unwrap();
+        store
+            .save_state_changes(&redaction("!root:localhost", "$m.space.child-!a:localhost"))
+            .await
+            .unwrap();
+        let tree = store.space_tree(root, 10).await.unwrap();
+        assert_eq!(edges(&tree), [("!root:localhost", "!b:localhost", 1)]);
+
+        store.remove_room(room_id!("!a:localhost")).await.unwrap();
+        assert!(store
+            .space_parents(room_id!("!a:localhost"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_space_hierarchy() {
+        let store = crate::schema::postgres_test_store("matrix_spaces_test").await;
+        let root = room_id!("!root:localhost");
+        store.save_state_changes(&changes()).await.unwrap();
+
+        let children = store.space_children(root).await.unwrap();
+        assert_eq!(
+            room_ids(children.iter().map(|child| &child.room_id)),
+            ["!b:localhost", "!a:localhost", "!sub:localhost"]
+        );
+        assert!(children[0].suggested);
+        assert_eq!(children[1].order.as_deref(), Some("b"));
+
+        let parents = store.space_parents(room_id!("!a:localhost")).await.unwrap();
+        assert_eq!(
+            room_ids(parents.iter().map(|parent| &parent.room_id)),
+            ["!root:localhost", "!other:localhost"]
+        );
+        assert!(parents[0].canonical);
+
+        let tree = store.space_tree(root, 10).await.unwrap();
+        assert_eq!(
+            edges(&tree),
+            [
+                ("!root:localhost", "!b:localhost", 1),
+                ("!root:localhost", "!a:localhost", 1),
+                ("!root:localhost", "!sub:localhost", 1),
+                ("!sub:localhost", "!c:localhost", 2),
+            ]
+        );
+        assert_eq!(store.space_tree(root, 1).await.unwrap().len(), 3);
+
+        // Removing the via or redacting the event removes the child
+        let mut changes = StateChanges::default();
+        add_event(
+            &mut changes,
+            "!root:localhost",
+            StateEventType::SpaceChild,
+            "!sub:localhost",
+            json!({}),
+        );
+        store.save_state_changes(&changes).await.unwrap();
+        store
+            .save_state_changes(&redaction("!root:localhost", "$m.space.child-!a:localhost"))
+            .await
+            .unwrap();
+        let tree = store.space_tree(root, 10).await.unwrap();
+        assert_eq!(edges(&tree), [("!root:localhost", "!b:localhost", 1)]);
+
+        store.remove_room(room_id!("!a:localhost")).await.unwrap();
+        assert!(store
+            .space_parents(room_id!("!a:localhost"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+//! Full database encryption for sqlite with SQLCipher
+
+use sqlx::sqlite::SqliteConnectOptions;
+use zeroize::Zeroizing;
+
+use crate::{key_provider::key_passphrase, Result, StateStore, StoreKey};
+
+/// Returns the value of the `key` and `rekey` pragmas for a store key
+///
+/// Passphrases are stretched by SQLCipher, raw keys are used as they are.
+fn key_pragma(key: &StoreKey) -> Zeroizing<String> {
+    match key {
+        StoreKey::Passphrase(passphrase) => {
+            Zeroizing::new(format!("'{}'", passphrase.replace('\'', "''")))
+        }
+        StoreKey::Key(key) => Zeroizing::new(format!("\"x'{}'\"", key_passphrase(key).as_str())),
+    }
+}
+
+/// Configures sqlite connect options to open an SQLCipher encrypted database
+///
+/// The `key` pragma is executed before any other statement of every connection. A new database
+/// is encrypted with the key, opening an existing database fails if the key is wrong.
+///
+/// The cryptostore rows are still encrypted with the store cipher, which should be unlocked with a
+/// different secret than the database.
+#[must_use]
+pub fn sqlcipher_options(options: SqliteConnectOptions, key: &StoreKey) -> SqliteConnectOptions {
+    options.pragma("key", key_pragma(key).as_str().to_owned())
+}
+
+impl StateStore<sqlx::Sqlite> {
+    /// Re-encrypts the SQLCipher encrypted database with a new key
+    ///
+    /// Connections that are already open keep working, but new connections need the new key, so
+    /// the pool has to be recreated with [`sqlcipher_options`] for the new key afterwards.
+    ///
+    /// # Errors
+    /// This function will return an error if the database cannot be re-encrypted
+    pub async fn rekey(&self, key: &StoreKey) -> Result<()> {
+        let query = Zeroizing::new(format!("PRAGMA rekey = {}", key_pragma(key).as_str()));
+        sqlx::query(query.as_str()).execute(&*self.db).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use super::sqlcipher_options;
+    use crate::{StateStore, StoreKey};
+
+    async fn open(
+        path: &std::path::Path,
+        key: Option<&StoreKey>,
+    ) -> crate::Result<StateStore<sqlx::Sqlite>> {
+        let mut options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        if let Some(key) = key {
+            options = sqlcipher_options(options, key);
+        }
+        let db = Arc::new(
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_with(options)
+                .await?,
+        );
+        StateStore::new(&db).await
+    }
+
+    #[tokio::test]
+    async fn test_sqlcipher_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let key = StoreKey::Passphrase("it's a secret".to_owned());
+        let new_key = StoreKey::Key([7; 32]);
+
+        let store = open(&path, Some(&key)).await.unwrap();
+        store.insert_kv(b"data", b"value").await.unwrap();
+        drop(store);
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents.starts_with(b"SQLite format 3"));
+
+        assert!(open(&path, None).await.is_err());
+        assert!(open(&path, Some(&new_key)).await.is_err());
+        let store = open(&path, Some(&key)).await.unwrap();
+        assert_eq!(
+            store.get_kv(b"data").await.unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        store.rekey(&new_key).await.unwrap();
+        drop(store);
+
+        assert!(open(&path, Some(&key)).await.is_err());
+        let store = open(&path, Some(&new_key)).await.unwrap();
+        assert_eq!(
+            store.get_kv(b"data").await.unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+    }
+}
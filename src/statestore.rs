@@ -3,8 +3,10 @@
 use std::collections::BTreeSet;
 
 use crate::{
+    custom::custom_key,
     helpers::{BorrowedSqlType, SqlType},
-    Result, StateStore, SupportedDatabase,
+    members::normalize_search,
+    ReceiptThread, Result, StateStore, SupportedDatabase,
 };
 use async_trait::async_trait;
 use futures::TryStreamExt;
@@ -13,6 +15,7 @@ use matrix_sdk_base::{
     StateChanges, StoreError,
 };
 use ruma::{
+    canonical_json::redact_in_place,
     events::{
         presence::PresenceEvent,
         receipt::{Receipt, ReceiptType},
@@ -25,13 +28,66 @@ use ruma::{
         AnySyncStateEvent, GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType,
     },
     serde::Raw,
-    EventId, MxcUri, OwnedEventId, OwnedUserId, RoomId, UserId,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedUserId,
+    RoomId, RoomVersionId, UserId,
 };
 use sqlx::{
     database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
     Transaction,
 };
 
+/// Copies of room info fields that are stored in their own columns
+struct RoomColumns {
+    /// The number of unread notifications
+    notification_count: i64,
+    /// The number of unread highlights
+    highlight_count: i64,
+    /// The room state, `Joined`, `Left` or `Invited`
+    room_state: String,
+    /// Whether or not the room is encrypted
+    is_encrypted: bool,
+    /// Whether or not the room is a direct message
+    is_direct: bool,
+    /// The room name, if any
+    room_name: Option<String>,
+}
+
+impl RoomColumns {
+    /// Extracts the columns from a room info
+    ///
+    /// # Errors
+    /// This function will return an error if the room info cannot be serialized
+    fn new(room_info: &RoomInfo) -> Result<Self> {
+        // Most of the fields are not public, so they are read from the serialized room info
+        let value = serde_json::to_value(room_info)?;
+        let counts = &value["notification_counts"];
+        let base_info = &value["base_info"];
+        Ok(Self {
+            notification_count: counts["notification_count"].as_i64().unwrap_or_default(),
+            highlight_count: counts["highlight_count"].as_i64().unwrap_or_default(),
+            room_state: value["room_type"].as_str().unwrap_or("Joined").to_owned(),
+            is_encrypted: room_info.is_encrypted(),
+            is_direct: base_info["dm_targets"]
+                .as_array()
+                .map_or(false, |targets| !targets.is_empty()),
+            room_name: base_info["name"]["Original"]["content"]["name"]
+                .as_str()
+                .map(ToOwned::to_owned),
+        })
+    }
+
+    /// Returns the key used to sort rooms by name
+    fn name_sort(&self) -> String {
+        self.room_name
+            .as_deref()
+            .map(str::to_lowercase)
+            .unwrap_or_default()
+    }
+}
+
+/// Maximum number of entries kept in the media cache
+pub(crate) const MEDIA_CACHE_LIMIT: i64 = 100;
+
 impl<DB: SupportedDatabase> StateStore<DB>
 where
     for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
@@ -47,6 +103,7 @@ where
     Json<SyncRoomMemberEvent>: SqlType<DB>,
     Json<MinimalRoomMemberEvent>: SqlType<DB>,
     bool: SqlType<DB>,
+    i64: SqlType<DB>,
     Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
     Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
     Json<RoomInfo>: SqlType<DB>,
@@ -61,22 +118,22 @@ where
     /// # Errors
     /// This function will return an error if the upsert cannot be performed
     pub(crate) async fn set_custom_value(&self, key_ref: &[u8], val: &[u8]) -> Result<()> {
-        let mut key = Vec::with_capacity(7 + key_ref.len());
-        key.extend_from_slice(b"custom:");
-        key.extend_from_slice(key_ref);
-
-        self.insert_kv(&key, val).await
+        self.insert_kv(&custom_key(key_ref), val).await
     }
 
-    /// Get arbitrary data from the custom store
+    /// Get arbitrary data from the custom store, unless it has expired
     ///
     /// # Errors
     /// This function will return an error if the database query fails
     pub(crate) async fn get_custom_value(&self, key_ref: &[u8]) -> Result<Option<Vec<u8>>> {
-        let mut key = Vec::with_capacity(7 + key_ref.len());
-        key.extend_from_slice(b"custom:");
-        key.extend_from_slice(key_ref);
-        self.get_kv(&key).await
+        let row = DB::kv_load_unexpired_query()
+            .bind(custom_key(key_ref))
+            .bind(i64::from(MilliSecondsSinceUnixEpoch::now().0))
+            .fetch_optional(&*self.db)
+            .await?;
+        row.map(|row| row.try_get("kv_value"))
+            .transpose()
+            .map_err(Into::into)
     }
 
     /// Save the given filter id under the given name
@@ -118,7 +175,10 @@ where
             .bind(media)
             .execute(&mut txn)
             .await?;
-        DB::media_insert_query_2().execute(&mut txn).await?;
+        DB::media_insert_query_2()
+            .bind(MEDIA_CACHE_LIMIT)
+            .execute(&mut txn)
+            .await?;
 
         txn.commit().await?;
         Ok(())
@@ -166,15 +226,19 @@ where
 
     /// Deletes a room from the room store
     ///
+    /// The removal is recorded as a sync checkpoint, so it can be rolled back.
+    ///
     /// # Errors
     /// This function will return an error if the the query fails
     pub(crate) async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         let mut txn = self.db.begin().await?;
+        Self::open_checkpoint(&mut txn).await?;
 
         for query in DB::room_remove_queries() {
             query.bind(room_id.as_str()).execute(&mut txn).await?;
         }
 
+        Self::close_checkpoint(&mut txn, None).await?;
         txn.commit().await?;
         Ok(())
     }
@@ -308,9 +372,19 @@ where
         user_id: &UserId,
         member_event: SyncRoomMemberEvent,
     ) -> Result<()> {
+        DB::membership_history_insert_query()
+            .bind(room_id.as_str())
+            .bind(user_id.as_str())
+            .bind(member_event.event_id().as_str())
+            .bind(member_event.sender().as_str())
+            .bind(member_event.membership().to_string())
+            .bind(i64::from(member_event.origin_server_ts().0))
+            .execute(&mut *txn)
+            .await?;
         let displayname = member_event
             .as_original()
             .and_then(|v| v.content.displayname.clone());
+        let search_name = normalize_search(displayname.as_deref().unwrap_or_default());
         let joined = match member_event.as_original().map(|v| &v.content.membership) {
             Some(MembershipState::Join) => true,
             Some(MembershipState::Invite) => false,
@@ -323,6 +397,8 @@ where
             .bind(Json(member_event))
             .bind(displayname)
             .bind(joined)
+            .bind(search_name)
+            .bind(normalize_search(user_id.as_str()))
             .execute(txn)
             .await?;
         Ok(())
@@ -339,6 +415,7 @@ where
         member_event: StrippedRoomMemberEvent,
     ) -> Result<()> {
         let displayname = member_event.content.displayname.clone();
+        let search_name = normalize_search(displayname.as_deref().unwrap_or_default());
         let joined = match member_event.content.membership {
             MembershipState::Join => true,
             MembershipState::Invite => false,
@@ -351,6 +428,8 @@ where
             .bind(Json(member_event))
             .bind(displayname)
             .bind(joined)
+            .bind(search_name)
+            .bind(normalize_search(user_id.as_str()))
             .execute(txn)
             .await?;
         Ok(())
@@ -394,10 +473,84 @@ where
             .bind(event_type.to_string())
             .bind(state_key)
             .bind(false)
-            .bind(Json(state))
+            .bind(Json(state.clone()))
             .bind(event_id.as_str())
-            .execute(txn)
+            .execute(&mut *txn)
+            .await?;
+        DB::state_history_insert_query()
+            .bind(room_id.as_str())
+            .bind(event_type.to_string())
+            .bind(state_key)
+            .bind(event_id.as_str())
+            .bind(decoded.sender().as_str())
+            .bind(i64::from(decoded.origin_server_ts().0))
+            .bind(Json(state))
+            .execute(&mut *txn)
             .await?;
+        let has_via = match &decoded {
+            AnySyncStateEvent::SpaceChild(event) => Some(
+                event
+                    .as_original()
+                    .and_then(|event| event.content.via.as_ref())
+                    .map_or(false, |via| !via.is_empty()),
+            ),
+            AnySyncStateEvent::SpaceParent(event) => Some(
+                event
+                    .as_original()
+                    .and_then(|event| event.content.via.as_ref())
+                    .map_or(false, |via| !via.is_empty()),
+            ),
+            _ => None,
+        };
+        match has_via {
+            // Children and parents without a via are no longer part of the space
+            Some(true) => {
+                let (parent_id, child_id) = if *event_type == StateEventType::SpaceChild {
+                    (room_id.as_str(), state_key)
+                } else {
+                    (state_key, room_id.as_str())
+                };
+                DB::space_edge_upsert_query()
+                    .bind(room_id.as_str())
+                    .bind(event_type.to_string())
+                    .bind(state_key)
+                    .bind(event_id.as_str())
+                    .bind(parent_id)
+                    .bind(child_id)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+            Some(false) => {
+                DB::space_edge_remove_query()
+                    .bind(room_id.as_str())
+                    .bind(event_type.to_string())
+                    .bind(state_key)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+            None => {}
+        }
+        if let AnySyncStateEvent::RoomPowerLevels(event) = decoded {
+            let power_levels = event.power_levels();
+            DB::power_levels_remove_query()
+                .bind(room_id.as_str())
+                .execute(&mut *txn)
+                .await?;
+            DB::power_level_insert_query()
+                .bind(room_id.as_str())
+                .bind("")
+                .bind(i64::from(power_levels.users_default))
+                .execute(&mut *txn)
+                .await?;
+            for (user_id, power_level) in power_levels.users {
+                DB::power_level_insert_query()
+                    .bind(room_id.as_str())
+                    .bind(user_id.as_str())
+                    .bind(i64::from(power_level))
+                    .execute(&mut *txn)
+                    .await?;
+            }
+        }
         Ok(())
     }
 
@@ -433,6 +586,21 @@ where
         event_type: &RoomAccountDataEventType,
         event_data: Raw<AnyRoomAccountDataEvent>,
     ) -> Result<()> {
+        if *event_type == RoomAccountDataEventType::Tag {
+            DB::room_tags_remove_query()
+                .bind(room_id.as_str())
+                .execute(&mut *txn)
+                .await?;
+            if let Ok(AnyRoomAccountDataEvent::Tag(event)) = event_data.deserialize() {
+                for tag in event.content.tags.keys() {
+                    DB::room_tag_insert_query()
+                        .bind(room_id.as_str())
+                        .bind(tag.as_ref())
+                        .execute(&mut *txn)
+                        .await?;
+                }
+            }
+        }
         DB::account_data_upsert_query()
             .bind(room_id.as_str())
             .bind(event_type.to_string())
@@ -442,7 +610,7 @@ where
         Ok(())
     }
 
-    /// Stores info for a room
+    /// Stores info for a room, with the time its notification counts were stored
     ///
     /// # Errors
     /// This function will return an error if the the query fails
@@ -450,17 +618,28 @@ where
         txn: &mut Transaction<'c, DB>,
         room_id: &RoomId,
         room_info: RoomInfo,
+        updated_at: i64,
     ) -> Result<()> {
+        let columns = RoomColumns::new(&room_info)?;
+        let name_sort = columns.name_sort();
         DB::room_upsert_query()
             .bind(room_id.as_str())
             .bind(false)
             .bind(Json(room_info))
+            .bind(columns.notification_count)
+            .bind(columns.highlight_count)
+            .bind(updated_at)
+            .bind(columns.room_state)
+            .bind(columns.is_encrypted)
+            .bind(columns.is_direct)
+            .bind(columns.room_name)
+            .bind(name_sort)
             .execute(txn)
             .await?;
         Ok(())
     }
 
-    /// Stores stripped info for a room
+    /// Stores stripped info for a room, with the time its notification counts were stored
     ///
     /// # Errors
     /// This function will return an error if the the query fails
@@ -468,34 +647,57 @@ where
         txn: &mut Transaction<'c, DB>,
         room_id: &RoomId,
         room_info: RoomInfo,
+        updated_at: i64,
     ) -> Result<()> {
+        let columns = RoomColumns::new(&room_info)?;
+        let name_sort = columns.name_sort();
         DB::room_upsert_query()
             .bind(room_id.as_str())
             .bind(true)
             .bind(Json(room_info))
+            .bind(columns.notification_count)
+            .bind(columns.highlight_count)
+            .bind(updated_at)
+            .bind(columns.room_state)
+            .bind(columns.is_encrypted)
+            .bind(columns.is_direct)
+            .bind(columns.room_name)
+            .bind(name_sort)
             .execute(txn)
             .await?;
         Ok(())
     }
 
-    /// Stores receipt for an event
+    /// Stores receipt for an event, with the local time it was stored at
+    ///
+    /// The receipts of a sync reach the store as a deserialized `ReceiptEventContent`, which
+    /// does not keep the `thread_id` of the receipts in ruma 0.7. `save_state_changes_txn`
+    /// therefore stores all of them as [`ReceiptThread::Unthreaded`], replacing the unthreaded
+    /// receipt of the user even if the homeserver sent a threaded one.
     ///
     /// # Errors
     /// This function will return an error if the the query fails
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn set_receipt<'c>(
         txn: &mut Transaction<'c, DB>,
         room_id: &RoomId,
         event_id: &EventId,
         receipt_type: &ReceiptType,
+        thread: &ReceiptThread,
         user_id: &UserId,
         receipt: Receipt,
+        stored_at: i64,
     ) -> Result<()> {
+        let receipt_ts = receipt.ts.map_or(0, |ts| i64::from(ts.0));
         DB::receipt_upsert_query()
             .bind(room_id.as_str())
             .bind(event_id.as_str())
             .bind(receipt_type.as_str())
             .bind(user_id.as_str())
             .bind(Json(receipt))
+            .bind(thread.as_str())
+            .bind(receipt_ts)
+            .bind(stored_at)
             .execute(txn)
             .await?;
         Ok(())
@@ -696,20 +898,22 @@ where
         Ok(result)
     }
 
-    /// Get latest receipt for user in room
+    /// Get latest receipt for user in a thread of a room
     ///
     /// # Errors
     /// This function will return an error if the the query fails
-    pub(crate) async fn get_user_room_receipt_event(
+    pub async fn get_user_room_thread_receipt_event(
         &self,
         room_id: &RoomId,
         receipt_type: ReceiptType,
+        thread: &ReceiptThread,
         user_id: &UserId,
     ) -> Result<Option<(OwnedEventId, Receipt)>> {
         let row = DB::receipt_load_query()
             .bind(room_id.as_ref())
             .bind(receipt_type.as_ref())
             .bind(user_id.as_ref())
+            .bind(thread.as_str())
             .fetch_optional(&*self.db)
             .await?;
         let row = if let Some(row) = row {
@@ -722,20 +926,22 @@ where
         Ok(Some((event_id, receipt)))
     }
 
-    /// Get all receipts for event in room
+    /// Get all receipts for event in a thread of a room
     ///
     /// # Errors
     /// This function will return an error if the the query fails
-    pub(crate) async fn get_event_room_receipt_events(
+    pub async fn get_event_room_thread_receipt_events(
         &self,
         room_id: &RoomId,
         receipt_type: ReceiptType,
+        thread: &ReceiptThread,
         event_id: &EventId,
     ) -> Result<Vec<(OwnedUserId, Receipt)>> {
         let mut rows = DB::event_receipt_load_query()
             .bind(room_id.as_ref())
             .bind(receipt_type.as_ref())
             .bind(event_id.as_ref())
+            .bind(thread.as_str())
             .fetch(&*self.db);
         let mut result = Vec::new();
         while let Some(row) = rows.try_next().await? {
@@ -837,7 +1043,30 @@ where
         event_id: &EventId,
         _redaction_event: &OriginalSyncRoomRedactionEvent,
     ) -> Result<()> {
+        let room_version = Self::room_version(txn, room_id).await?;
+        let row = DB::state_history_event_load_query()
+            .bind(room_id.as_str())
+            .bind(event_id.as_str())
+            .fetch_optional(&mut *txn)
+            .await?;
+        if let Some(row) = row {
+            let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+            let mut event: CanonicalJsonObject = event.0.deserialize_as()?;
+            redact_in_place(&mut event, &room_version)?;
+            let event: Raw<AnySyncStateEvent> = Raw::new(&event)?.cast();
+            DB::state_history_redact_query()
+                .bind(room_id.as_str())
+                .bind(event_id.as_str())
+                .bind(Json(event))
+                .execute(&mut *txn)
+                .await?;
+        }
         DB::state_redact_query()
+            .bind(room_id.as_str())
+            .bind(event_id.as_str())
+            .execute(&mut *txn)
+            .await?;
+        DB::space_edge_redact_query()
             .bind(room_id.as_str())
             .bind(event_id.as_str())
             .execute(txn)
@@ -845,6 +1074,35 @@ where
         Ok(())
     }
 
+    /// Returns the version of a room from its create event
+    ///
+    /// Rooms without a stored create event are assumed to use room version 1.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn room_version<'c>(
+        txn: &mut Transaction<'c, DB>,
+        room_id: &RoomId,
+    ) -> Result<RoomVersionId> {
+        let row = DB::state_load_query()
+            .bind(room_id.as_str())
+            .bind(StateEventType::RoomCreate.to_string())
+            .bind("")
+            .fetch_optional(txn)
+            .await?;
+        let version = match row {
+            Some(row) => {
+                let event: Json<Raw<AnySyncStateEvent>> = row.try_get("state_event")?;
+                let event: serde_json::Value = event.0.deserialize_as()?;
+                event["content"]["room_version"]
+                    .as_str()
+                    .and_then(|version| RoomVersionId::try_from(version).ok())
+            }
+            None => None,
+        };
+        Ok(version.unwrap_or(RoomVersionId::V1))
+    }
+
     /// Save state changes to the database in a transaction
     ///
     /// # Errors
@@ -853,6 +1111,9 @@ where
         txn: &mut Transaction<'c, DB>,
         state_changes: &StateChanges,
     ) -> Result<()> {
+        Self::open_checkpoint(txn).await?;
+        // Receipts and notification counts of the same sync are stored with the same time
+        let now = i64::from(MilliSecondsSinceUnixEpoch::now().0);
         if let Some(sync_token) = &state_changes.sync_token {
             Self::save_sync_token(txn, sync_token).await?;
         }
@@ -866,10 +1127,10 @@ where
         }
 
         for (room_id, room_info) in &state_changes.room_infos {
-            Self::set_room_info(txn, room_id, room_info.clone()).await?;
+            Self::set_room_info(txn, room_id, room_info.clone(), now).await?;
         }
         for (room_id, room_info) in &state_changes.stripped_room_infos {
-            Self::set_stripped_room_info(txn, room_id, room_info.clone()).await?;
+            Self::set_stripped_room_info(txn, room_id, room_info.clone(), now).await?;
         }
 
         for (room_id, members) in &state_changes.members {
@@ -927,6 +1188,7 @@ where
             }
         }
 
+        // The thread IDs of synced receipts are lost in deserialization, see `set_receipt`
         for (room_id, receipt) in &state_changes.receipts {
             for (event_id, receipt) in &receipt.0 {
                 for (receipt_type, receipt) in receipt {
@@ -936,8 +1198,10 @@ where
                             room_id,
                             event_id,
                             receipt_type,
+                            &ReceiptThread::Unthreaded,
                             user_id,
                             receipt.clone(),
+                            now,
                         )
                         .await?;
                     }
@@ -945,6 +1209,7 @@ where
             }
         }
 
+        Self::close_checkpoint(txn, state_changes.sync_token.as_deref()).await?;
         Ok(())
     }
 
@@ -979,6 +1244,7 @@ where
     Json<SyncRoomMemberEvent>: SqlType<DB>,
     Json<MinimalRoomMemberEvent>: SqlType<DB>,
     bool: SqlType<DB>,
+    i64: SqlType<DB>,
     Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
     Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
     Json<RoomInfo>: SqlType<DB>,
@@ -1215,9 +1481,14 @@ where
         receipt_type: ReceiptType,
         user_id: &UserId,
     ) -> StoreResult<Option<(OwnedEventId, Receipt)>> {
-        self.get_user_room_receipt_event(room_id, receipt_type, user_id)
-            .await
-            .map_err(|e| StoreError::Backend(e.into()))
+        self.get_user_room_thread_receipt_event(
+            room_id,
+            receipt_type,
+            &ReceiptThread::Unthreaded,
+            user_id,
+        )
+        .await
+        .map_err(|e| StoreError::Backend(e.into()))
     }
 
     /// Get events out of the event room receipt store.
@@ -1237,9 +1508,14 @@ where
         receipt_type: ReceiptType,
         event_id: &EventId,
     ) -> StoreResult<Vec<(OwnedUserId, Receipt)>> {
-        self.get_event_room_receipt_events(room_id, receipt_type, event_id)
-            .await
-            .map_err(|e| StoreError::Backend(e.into()))
+        self.get_event_room_thread_receipt_events(
+            room_id,
+            receipt_type,
+            &ReceiptThread::Unthreaded,
+            event_id,
+        )
+        .await
+        .map_err(|e| StoreError::Backend(e.into()))
     }
 
     /// Get arbitrary data from the custom store
@@ -0,0 +1,369 @@
+//! Storage for room keys that senders refused to share
+
+use std::{borrow::Cow, fmt};
+
+use matrix_sdk_base::{deserialized_responses::MemberEvent, MinimalRoomMemberEvent, RoomInfo};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{StrippedRoomMemberEvent, SyncRoomMemberEvent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent,
+    },
+    serde::Raw,
+    OwnedRoomId, RoomId,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    database::HasArguments, types::Json, ColumnIndex, Database, Executor, IntoArguments, Row,
+    Transaction,
+};
+
+use crate::{
+    cryptostore::CryptostoreData,
+    helpers::{BorrowedSqlType, SqlType},
+    Result, StateStore, SupportedDatabase,
+};
+
+/// The reason a room key was withheld
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum WithheldCode {
+    /// `m.blacklisted`, the device was blocked by the sender
+    Blacklisted,
+    /// `m.unverified`, the device is not verified by the sender
+    Unverified,
+    /// `m.unauthorised`, the device is not allowed to see the messages
+    Unauthorised,
+    /// `m.unavailable`, the sender does not have the key
+    Unavailable,
+    /// `m.no_olm`, no olm session could be established with the device
+    NoOlm,
+    /// A code that is not part of the specification
+    Other(String),
+}
+
+impl WithheldCode {
+    /// Returns the code as it is sent in `m.room_key.withheld` events
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Blacklisted => "m.blacklisted",
+            Self::Unverified => "m.unverified",
+            Self::Unauthorised => "m.unauthorised",
+            Self::Unavailable => "m.unavailable",
+            Self::NoOlm => "m.no_olm",
+            Self::Other(code) => code,
+        }
+    }
+}
+
+impl From<String> for WithheldCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "m.blacklisted" => Self::Blacklisted,
+            "m.unverified" => Self::Unverified,
+            "m.unauthorised" => Self::Unauthorised,
+            "m.unavailable" => Self::Unavailable,
+            "m.no_olm" => Self::NoOlm,
+            _ => Self::Other(code),
+        }
+    }
+}
+
+impl From<WithheldCode> for String {
+    fn from(code: WithheldCode) -> Self {
+        match code {
+            WithheldCode::Other(code) => code,
+            code => code.as_str().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A notice that the sender of a megolm session withheld its key from us
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomKeyWithheld {
+    /// The room of the session
+    pub room_id: OwnedRoomId,
+    /// The base64 encoded curve25519 key of the sender
+    pub sender_key: String,
+    /// The ID of the session
+    pub session_id: String,
+    /// Why the key was withheld
+    pub code: WithheldCode,
+    /// A human readable reason given by the sender
+    pub reason: Option<String>,
+}
+
+/// Hashes the primary key of a withheld notice
+fn withheld_key<'a>(
+    e2e: &CryptostoreData,
+    room_id: &'a RoomId,
+    sender_key: &'a str,
+    session_id: &'a str,
+) -> [Cow<'a, [u8]>; 3] {
+    [
+        e2e.encode_key("cryptostore_room_key_withheld:room_id", room_id.as_bytes()),
+        e2e.encode_key(
+            "cryptostore_room_key_withheld:sender_key",
+            sender_key.as_bytes(),
+        ),
+        e2e.encode_key(
+            "cryptostore_room_key_withheld:session_id",
+            session_id.as_bytes(),
+        ),
+    ]
+}
+
+impl<DB: SupportedDatabase> StateStore<DB>
+where
+    for<'a> <DB as HasArguments<'a>>::Arguments: IntoArguments<'a, DB>,
+    for<'c> &'c mut <DB as sqlx::Database>::Connection: Executor<'c, Database = DB>,
+    for<'c, 'a> &'a mut Transaction<'c, DB>: Executor<'a, Database = DB>,
+    for<'a> &'a [u8]: BorrowedSqlType<'a, DB>,
+    for<'a> &'a str: BorrowedSqlType<'a, DB>,
+    Vec<u8>: SqlType<DB>,
+    String: SqlType<DB>,
+    bool: SqlType<DB>,
+    i64: SqlType<DB>,
+    Option<String>: SqlType<DB>,
+    Json<Raw<AnyGlobalAccountDataEvent>>: SqlType<DB>,
+    Json<Raw<PresenceEvent>>: SqlType<DB>,
+    Json<SyncRoomMemberEvent>: SqlType<DB>,
+    Json<MinimalRoomMemberEvent>: SqlType<DB>,
+    Json<Raw<AnySyncStateEvent>>: SqlType<DB>,
+    Json<Raw<AnyRoomAccountDataEvent>>: SqlType<DB>,
+    Json<RoomInfo>: SqlType<DB>,
+    Json<Receipt>: SqlType<DB>,
+    Json<Raw<AnyStrippedStateEvent>>: SqlType<DB>,
+    Json<StrippedRoomMemberEvent>: SqlType<DB>,
+    Json<MemberEvent>: SqlType<DB>,
+    for<'a> &'a str: ColumnIndex<<DB as Database>::Row>,
+{
+    /// Records that the key of a megolm session was withheld from us
+    ///
+    /// A notice for the same session replaces the previous one.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn save_room_key_withheld(&self, withheld: &RoomKeyWithheld) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let [room_id, sender_key, session_id] = withheld_key(
+            &e2e,
+            &withheld.room_id,
+            &withheld.sender_key,
+            &withheld.session_id,
+        );
+        DB::room_key_withheld_store_query()
+            .bind(room_id.as_ref())
+            .bind(sender_key.as_ref())
+            .bind(session_id.as_ref())
+            .bind(e2e.encode_value(withheld)?)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Retrieves the withheld notice of a megolm session
+    ///
+    /// This can be used to explain why a message cannot be decrypted, instead of requesting a key
+    /// that will not be sent.
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn get_room_key_withheld(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheld>> {
+        let e2e = self.ensure_e2e()?;
+        let [room_id, sender_key, session_id] = withheld_key(&e2e, room_id, sender_key, session_id);
+        let row = DB::room_key_withheld_fetch_query()
+            .bind(room_id.as_ref())
+            .bind(sender_key.as_ref())
+            .bind(session_id.as_ref())
+            .fetch_optional(&*self.db)
+            .await?;
+        if let Some(row) = row {
+            let data: Vec<u8> = row.try_get("withheld_data")?;
+            Ok(Some(e2e.decode_value(&data)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Deletes the withheld notice of a megolm session, for example once its key was received
+    ///
+    /// # Errors
+    /// This function will return an error if the database has not been unlocked,
+    /// or if the query fails.
+    pub async fn delete_room_key_withheld(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<()> {
+        let e2e = self.ensure_e2e()?;
+        let [room_id, sender_key, session_id] = withheld_key(&e2e, room_id, sender_key, session_id);
+        DB::room_key_withheld_delete_query()
+            .bind(room_id.as_ref())
+            .bind(sender_key.as_ref())
+            .bind(session_id.as_ref())
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::sync::Arc;
+
+    use ruma::room_id;
+
+    use super::{RoomKeyWithheld, WithheldCode};
+    #[cfg(feature = "sqlite")]
+    use crate::StateStore;
+
+    #[test]
+    fn test_withheld_code_serialization() {
+        assert_eq!(
+            serde_json::to_string(&WithheldCode::NoOlm).unwrap(),
+            r#""m.no_olm""#
+        );
+        assert_eq!(
+            serde_json::from_str::<WithheldCode>(r#""m.unverified""#).unwrap(),
+            WithheldCode::Unverified
+        );
+        assert_eq!(
+            serde_json::from_str::<WithheldCode>(r#""org.example.custom""#).unwrap(),
+            WithheldCode::Other("org.example.custom".to_owned())
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_room_key_withheld() {
+        let db = Arc::new(
+            sqlx::SqlitePool::connect("sqlite://:memory:")
+                .await
+                .unwrap(),
+        );
+        let store = StateStore::new(&db).await.unwrap();
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let mut withheld = RoomKeyWithheld {
+            room_id: room.to_owned(),
+            sender_key: "sender".to_owned(),
+            session_id: "session".to_owned(),
+            code: WithheldCode::Unverified,
+            reason: Some("Your device is not verified".to_owned()),
+        };
+        store.save_room_key_withheld(&withheld).await.unwrap();
+        assert_eq!(
+            store
+                .get_room_key_withheld(room, "sender", "session")
+                .await
+                .unwrap(),
+            Some(withheld.clone())
+        );
+        assert!(store
+            .get_room_key_withheld(room, "sender", "other")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_room_key_withheld(room_id!("!other:localhost"), "sender", "session")
+            .await
+            .unwrap()
+            .is_none());
+
+        withheld.code = WithheldCode::Blacklisted;
+        withheld.reason = None;
+        store.save_room_key_withheld(&withheld).await.unwrap();
+        let stored = store
+            .get_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.code, WithheldCode::Blacklisted);
+        assert_eq!(stored.reason, None);
+
+        store
+            .delete_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap();
+        assert!(store
+            .get_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[cfg_attr(not(feature = "ci"), ignore)]
+    async fn test_postgres_room_key_withheld() {
+        let store = crate::schema::postgres_test_store("matrix_room_key_withheld_test").await;
+        store.unlock().await.unwrap();
+        let room = room_id!("!room:localhost");
+        let mut withheld = RoomKeyWithheld {
+            room_id: room.to_owned(),
+            sender_key: "sender".to_owned(),
+            session_id: "session".to_owned(),
+            code: WithheldCode::Unverified,
+            reason: Some("Your device is not verified".to_owned()),
+        };
+        store.save_room_key_withheld(&withheld).await.unwrap();
+        assert_eq!(
+            store
+                .get_room_key_withheld(room, "sender", "session")
+                .await
+                .unwrap(),
+            Some(withheld.clone())
+        );
+        assert!(store
+            .get_room_key_withheld(room, "sender", "other")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_room_key_withheld(room_id!("!other:localhost"), "sender", "session")
+            .await
+            .unwrap()
+            .is_none());
+
+        withheld.code = WithheldCode::Blacklisted;
+        withheld.reason = None;
+        store.save_room_key_withheld(&withheld).await.unwrap();
+        let stored = store
+            .get_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.code, WithheldCode::Blacklisted);
+        assert_eq!(stored.reason, None);
+
+        store
+            .delete_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap();
+        assert!(store
+            .get_room_key_withheld(room, "sender", "session")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}